@@ -0,0 +1,229 @@
+/*
+This command distributes promotional $affron tokens to a list of customers in one all-or-nothing
+batch. It reads a CSV file of recipients, works out the totals and prints them without touching
+the ledger. Only when run with --execute does it apply the batch and commit it to the ledger
+database.
+
+Usage: airdrop <ledger-db> <sender-address> <recipients.csv> [--execute]
+
+Each line of the CSV file is an address and an amount of whole tokens, which may have a decimal
+part, e.g. "0x1234...abcd,2.5". Blank lines, lines starting with '#' and an
+"address,amount" header are skipped.
+*/
+
+use std::error::Error;
+use std::fs;
+
+use web3::types::{Address, U256};
+
+use saffron::saffron_storage::SledStore;
+use saffron::saffron_vendor::{BatchSummary, CallContext, Saffron};
+
+// The token's name, symbol and decimals, as deployed in SaffronVendor.sol
+const TOKEN_NAME: &str = "Saffron Vendor";
+const TOKEN_SYMBOL: &str = "$affron";
+const TOKEN_DECIMALS: u8 = 18;
+
+fn main() {
+    // Errors are printed in full rather than in their debug form, so that e.g. a locked database
+    // says what to do about it
+    if let Err(err) = run() {
+        eprintln!("airdrop: {}", err);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
+    // Read the ledger database, sender and CSV file from the command line arguments
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 4 || args.len() > 5 {
+        return Err("usage: airdrop <ledger-db> <sender-address> <recipients.csv> [--execute]".into());
+    }
+    let execute = match args.get(4).map(String::as_str) {
+        None => false,
+        Some("--execute") => true,
+        Some(other) => return Err(format!("unknown option {}", other).into()),
+    };
+    let sender: Address = args[2].parse().map_err(|_| format!("invalid sender address {}", args[2]))?;
+
+    // Parse the recipients before opening the ledger, so a bad file changes nothing
+    let csv = fs::read_to_string(&args[3])?;
+    let transfers = parse_recipients(&csv, TOKEN_DECIMALS)?;
+    if transfers.is_empty() {
+        return Err("the CSV file lists no recipients".into());
+    }
+
+    // Load the ledger and check the whole batch against it. The database cannot be opened while
+    // the server has it, so the airdrop never works on a copy the server is about to overwrite.
+    // The sender is never made an owner: the stored roles, pause switch and blocklist all apply.
+    let store = SledStore::open(&args[1])?;
+    let mut saffron = Saffron::restore(TOKEN_NAME.to_string(), TOKEN_SYMBOL.to_string(), TOKEN_DECIMALS, None, &store)?;
+    // The transfers are logged in the block after the latest one on record, so the event log
+    // stays in block order
+    let mut ctx = CallContext::from_sender(sender);
+    ctx.block_number = saffron.event_log().last().map_or(0, |logged| logged.block_number + 1);
+    let summary = saffron.check_batch_transfer(&ctx, &transfers)?;
+    print_summary(&summary);
+
+    if !execute {
+        println!("Dry run only; run again with --execute to send the tokens.");
+        return Ok(());
+    }
+
+    // Apply the batch and store it in one atomic write
+    saffron.batch_transfer(&ctx, &transfers)?;
+    saffron.commit(&store)?;
+    println!("Airdrop sent.");
+    Ok(())
+}
+
+// Parse the CSV file into (recipient, amount in base units) pairs, reporting the line number of
+// the first malformed line
+fn parse_recipients(csv: &str, decimals: u8) -> Result<Vec<(Address, U256)>, String> {
+    let mut transfers = Vec::new();
+    for (index, line) in csv.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || is_header(line) {
+            continue;
+        }
+
+        let line_number = index + 1;
+        let (address, amount) = line
+            .split_once(',')
+            .ok_or_else(|| format!("line {}: expected \"address,amount\"", line_number))?;
+        let address: Address = address
+            .trim()
+            .parse()
+            .map_err(|_| format!("line {}: invalid address {}", line_number, address.trim()))?;
+        let amount = parse_token_amount(amount.trim(), decimals).map_err(|err| format!("line {}: {}", line_number, err))?;
+        transfers.push((address, amount));
+    }
+    Ok(transfers)
+}
+
+// Return true if `line` is the "address,amount" header, in any case and spacing
+fn is_header(line: &str) -> bool {
+    line.split_once(',').is_some_and(|(address, amount)| {
+        address.trim().eq_ignore_ascii_case("address") && amount.trim().eq_ignore_ascii_case("amount")
+    })
+}
+
+// Convert an amount of whole tokens such as "2.5" into base units
+fn parse_token_amount(amount: &str, decimals: u8) -> Result<U256, String> {
+    let (whole, fraction) = amount.split_once('.').unwrap_or((amount, ""));
+    if whole.is_empty() && fraction.is_empty() {
+        return Err("empty amount".to_string());
+    }
+    if !whole.chars().all(|c| c.is_ascii_digit()) || !fraction.chars().all(|c| c.is_ascii_digit()) {
+        return Err(format!("invalid amount {}", amount));
+    }
+    if fraction.len() > decimals as usize {
+        return Err(format!("amount {} has more than {} decimal places", amount, decimals));
+    }
+
+    // Pad the fraction out to the token's decimals and read the digits as one integer
+    let digits = format!("{}{:0<width$}", whole, fraction, width = decimals as usize);
+    U256::from_dec_str(&digits).map_err(|_| format!("amount {} is too large", amount))
+}
+
+// Print the totals of a checked batch
+fn print_summary(summary: &BatchSummary) {
+    println!("Transfers:      {}", summary.transfers);
+    println!("Recipients:     {}", summary.recipients);
+    println!("Total sent:     {}", summary.total);
+    println!("Transfer fees:  {}", summary.fees);
+    println!("Balance after:  {}", summary.sender_balance_after);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use saffron::saffron_vendor::Role;
+
+    // One whole token in base units
+    fn tokens(whole: u64) -> U256 {
+        U256::from(whole) * U256::exp10(18)
+    }
+
+    #[test]
+    fn amounts_may_leave_out_either_side_of_the_point() {
+        assert_eq!(parse_token_amount("5.", 18), Ok(tokens(5)));
+        assert_eq!(parse_token_amount(".5", 18), Ok(U256::exp10(17) * 5));
+        assert_eq!(parse_token_amount("2.25", 18), Ok(U256::exp10(16) * 225));
+        assert_eq!(parse_token_amount("0.000000000000000001", 18), Ok(U256::one()));
+        assert_eq!(parse_token_amount("7", 0), Ok(U256::from(7)));
+    }
+
+    #[test]
+    fn malformed_amounts_are_refused() {
+        assert_eq!(parse_token_amount(".", 18), Err("empty amount".to_string()));
+        assert_eq!(parse_token_amount("", 18), Err("empty amount".to_string()));
+        assert_eq!(
+            parse_token_amount("0.0000000000000000001", 18),
+            Err("amount 0.0000000000000000001 has more than 18 decimal places".to_string())
+        );
+        assert_eq!(parse_token_amount("1.5", 0), Err("amount 1.5 has more than 0 decimal places".to_string()));
+        for amount in ["-1", "1e5", "1,5", "1.2.3", " 1", "+2"] {
+            assert_eq!(parse_token_amount(amount, 18), Err(format!("invalid amount {}", amount)));
+        }
+        let huge = "1".repeat(80);
+        assert_eq!(parse_token_amount(&huge, 18), Err(format!("amount {} is too large", huge)));
+    }
+
+    #[test]
+    fn headers_comments_and_blank_lines_are_skipped() {
+        let csv = "Address, Amount\n# promotional batch\n\n0x0000000000000000000000000000000000000002,1.5\n  0x0000000000000000000000000000000000000003 , 2 \n";
+        let transfers = parse_recipients(csv, 18).unwrap();
+        assert_eq!(
+            transfers,
+            [
+                (Address::from_low_u64_be(2), U256::exp10(17) * 15),
+                (Address::from_low_u64_be(3), tokens(2)),
+            ]
+        );
+        assert!(parse_recipients("address,amount\n", 18).unwrap().is_empty());
+    }
+
+    #[test]
+    fn bad_lines_are_reported_with_their_line_number() {
+        let line = "address,amount\n0x0000000000000000000000000000000000000002,1\n";
+        assert_eq!(
+            parse_recipients(&format!("{}0x1234,1\n", line), 18),
+            Err("line 3: invalid address 0x1234".to_string())
+        );
+        assert_eq!(
+            parse_recipients(&format!("{}not-an-address,1\n", line), 18),
+            Err("line 3: invalid address not-an-address".to_string())
+        );
+        assert_eq!(
+            parse_recipients(&format!("{}0x0000000000000000000000000000000000000003\n", line), 18),
+            Err("line 3: expected \"address,amount\"".to_string())
+        );
+        assert_eq!(
+            parse_recipients(&format!("{}0x0000000000000000000000000000000000000003,.\n", line), 18),
+            Err("line 3: empty amount".to_string())
+        );
+    }
+
+    #[test]
+    fn duplicate_recipients_and_the_sender_are_counted_once_or_not_at_all() {
+        let (sender, recipient) = (Address::from_low_u64_be(1), Address::from_low_u64_be(2));
+        let csv = format!("{:?},1\n{:?},2\n{:?},3\n", recipient, recipient, sender);
+        let transfers = parse_recipients(&csv, 18).unwrap();
+        // Every line is kept, so the same customer can be sent two amounts
+        assert_eq!(transfers, [(recipient, tokens(1)), (recipient, tokens(2)), (sender, tokens(3))]);
+
+        let ctx = CallContext::new(sender, U256::zero(), 1, 1_000);
+        let mut saffron = Saffron::new(TOKEN_NAME.to_string(), TOKEN_SYMBOL.to_string(), TOKEN_DECIMALS, sender).unwrap();
+        saffron.grant_role(&ctx, Role::Minter, &sender).unwrap();
+        saffron.add_inventory(&ctx, U256::from(10)).unwrap();
+        saffron.mint(&ctx, tokens(10)).unwrap();
+
+        // The transfer back to the sender is left out of both the total and the recipients
+        let summary = saffron.check_batch_transfer(&ctx, &transfers).unwrap();
+        assert_eq!(summary.transfers, 3);
+        assert_eq!(summary.recipients, 1);
+        assert_eq!(summary.total, tokens(3));
+        assert_eq!(summary.sender_balance_after, tokens(7));
+    }
+}
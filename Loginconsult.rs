@@ -149,7 +149,9 @@ Template::render("booked", &context)
 
 fn main() {
 let api_key = env::var("INFURA_API_KEY").expect("INFURA_API_KEY must be set");
-let client = web3::Web3::new(web3::transports::Http::new("https://mainnet.infura.io/v3/").expect("Error creating Web3 client"));
+// Append the API key to the Infura endpoint. The transport error is not printed, since it can
+// echo the URL and with it the key.
+let client = web3::Web3::new(web3::transports::Http::new(&format!("https://mainnet.infura.io/v3/{}", api_key)).unwrap_or_else(|_| panic!("Error creating Web3 client")));
 let ethereum = EthereumData {
 client,
 session: String::new(),
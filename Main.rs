@@ -1,154 +1,394 @@
 #[macro_use]
 extern crate rocket;
-#[macro_use]
-extern crate serde_derive;
-extern crate serde;
-extern crate reqwest;
-extern crate web3;
-extern crate tokio;
 
-// Import the necessary traits and types for serializing and deserializing structs and making HTTP requests
+// Import the necessary traits and types for serializing structs and making HTTP requests
 use serde::{Serialize, Deserialize};
-use serde_derive::{Serialize, Deserialize};
-use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::prelude::*;
-use reqwest::{Client, Response};
+use reqwest::{Client, StatusCode};
+
+// Import the necessary types for validating and normalising mail addresses
+use std::collections::BTreeMap;
+use saffron::saffron_mailing_address::{AddressErrors, AddressField, MailingAddress, MailingAddressFields};
+use saffron::saffron_order_numbers::{OrderNumberAllocator, OrderNumberError};
+
+// Import the necessary types for checking redemptions on chain
+use web3::types::{Address, H256};
+use saffron::saffron_config::Config;
+use saffron::saffron_redemption_proof::{ProofError, RedemptionClaim, RedemptionClaims, RedemptionVerifier, VerifiedRedemption};
+
+// Import the necessary types for queueing and retrying fulfilment requests
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use saffron::saffron_outbox::{self, Delivery, Outbox, OutboxEntry, RetryPolicy};
 
-// Import the necessary types for validating and sanitizing input
-use std::net::ToSocketAddrs;
-use std::str::FromStr;
+use rocket::form::Form;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::Redirect;
+use rocket::serde::json::Json;
+use rocket::State;
+use rocket_dyn_templates::Template;
 
+// The database the order number sequence is kept in, inside the configured data directory
+const ORDER_NUMBER_DB: &str = "order_numbers.db";
 
-use rocket::{Form, Redirect, Template};
+// The database pending and dead-letter fulfilment requests are kept in, together with the record
+// of which redemption transactions have already been turned into orders
+const OUTBOX_DB: &str = "outbox.db";
+
+// The token's decimals: one whole token (10^18 base units) redeems one gram
+const TOKEN_DECIMALS: u8 = 18;
+
+// How often the background worker looks for fulfilment requests that are due
+const OUTBOX_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// How long a single request to the FWS API may take before it is treated as failed and retried
+const FULFILLMENT_TIMEOUT: Duration = Duration::from_secs(30);
 
 
 
 // Define a struct for representing a saffron order
 #[derive(Serialize, Deserialize, Debug)]
 struct SaffronOrder {
-    // Number of saffron tokens being redeemed
+    // Number of saffron tokens being redeemed, in whole tokens (grams)
     x: u64,
-    // Mail address provided by the user
-    mail_address: String,
+    // The transaction that burned or escrowed the tokens; each one ships once
+    tx_hash: H256,
+    // The holder whose tokens were redeemed
+    holder: Address,
+    // Checked and normalised mail address provided by the user
+    mail_address: MailingAddress,
     // Ascending 8-digit number generated by gen_order_number
     order_number: u64,
 }
 
-// Asynchronously prompt the user for a valid US mail address and return it as a string
-//This version of the ask_mail_address function uses the ToSocketAddrs trait to validate the mail address entered by the user. If the mail address is not a valid address, an error message is returned as the result.
-//The function also sanitizes the mail address by using the FromStr trait to convert the address to a string and taking the first address returned by ToSocketAddrs. This ensures that any unnecessary or potentially malicious elements of the address are removed before the address is used.
-async fn ask_mail_address() -> Result<String, String> {
-    println!("Please enter a valid US mail address:");
-    let mut mail_address = String::new();
-    // Read the user's input from stdin
-    io::stdin().read_line(&mut mail_address).await.unwrap();
-    // Trim leading and trailing whitespace
-    mail_address = mail_address.trim().to_string();
-    // Validate the mail address using the ToSocketAddrs trait
-    let addr = mail_address.to_socket_addrs().map_err(|_| "Invalid mail address")?;
-    // Sanitize the mail address by taking the first address returned by ToSocketAddrs
-    let sanitized_mail_address = addr.next().unwrap().to_string();
-    // Return the sanitized mail address as a result
-    Ok(sanitized_mail_address)
-}
-
-
-// Asynchronously generate an ascending 8-digit number and return it as a u64
-async fn gen_order_number() -> u64 {
-    // Declare a static variable to store the current order number
-    static mut ORDER_NUMBER: u64 = 0;
-    // Increment the order number and return it
-    unsafe {
-        ORDER_NUMBER += 1;
-        ORDER_NUMBER
-    }
+// Asynchronously generate an ascending 8-digit number, ending in a check digit, and return it as
+// a u64. The allocator stores the sequence on disk, so numbers are never reused, even after a
+// restart or when requests arrive at the same time. Writing the sequence blocks on the disk, so
+// it is done on the blocking thread pool rather than holding up the server's other requests.
+async fn gen_order_number(allocator: Arc<OrderNumberAllocator>) -> Result<u64, OrderNumberError> {
+    tokio::task::spawn_blocking(move || allocator.next())
+        .await
+        .unwrap_or_else(|e| Err(OrderNumberError::Backend(e.to_string())))
 }
-    // Define a form for collecting the user's mail address using the `Form` macro provided by Rocket
-#[derive(FromForm)]
+
+// Define a form for collecting the user's mail address using the `FromForm` derive provided by Rocket
+#[derive(FromForm, Serialize, Default)]
 struct MailAddressForm {
-    mail_address: String,
+    // The hash of the transaction that burned or escrowed the tokens being redeemed
+    tx_hash: String,
+    // The grams of saffron the transaction redeemed
+    grams: String,
+    // The holder's personal_sign signature over the redemption message for tx_hash, the grams
+    // and the normalised mail address
+    signature: String,
+    name: String,
+    line1: String,
+    line2: String,
+    city: String,
+    state: String,
+    zip: String,
+    country: String,
+}
+
+impl MailAddressForm {
+    // Return the form's fields as an address to be checked
+    fn to_fields(&self) -> MailingAddressFields {
+        MailingAddressFields {
+            name: self.name.clone(),
+            line1: self.line1.clone(),
+            line2: self.line2.clone(),
+            city: self.city.clone(),
+            state: self.state.clone(),
+            zip: self.zip.clone(),
+            country: self.country.clone(),
+        }
+    }
+}
+
+// Define the context the form template is rendered with: the values entered so far and the
+// error message for each field that was rejected, keyed by field name
+#[derive(Serialize, Default)]
+struct MailAddressContext {
+    form: MailAddressForm,
+    errors: BTreeMap<String, String>,
 }
+
+impl MailAddressContext {
+    // Build the context for re-displaying a form whose redemption could not be verified
+    fn unverified(form: MailAddressForm, error: &ProofError, config: &Config) -> Self {
+        let mut errors = BTreeMap::new();
+        // Node errors can echo the RPC URL, so secrets are removed before the page shows them
+        errors.insert("redemption".to_string(), config.redact(&error.to_string()));
+        Self { form, errors }
+    }
+
+    // Build the context for re-displaying a rejected form with its errors
+    fn rejected(form: MailAddressForm, errors: &AddressErrors) -> Self {
+        let fields = [
+            AddressField::Name,
+            AddressField::Line1,
+            AddressField::Line2,
+            AddressField::City,
+            AddressField::State,
+            AddressField::Zip,
+            AddressField::Country,
+        ];
+        let errors = fields
+            .iter()
+            .filter_map(|field| errors.message_for(*field).map(|message| (field.form_name().to_string(), message)))
+            .collect();
+        Self { form, errors }
+    }
+}
+
 // Define a route for displaying the form for collecting the user's mail address
 #[get("/mail_address_form")]
 fn mail_address_form() -> Template {
     // Render the form template
-    Template::render("mail_address_form", &MailAddressForm { mail_address: String::new() })
+    Template::render("mail_address_form", MailAddressContext::default())
 }
 
 
 
-// Asynchronously make an HTTP POST request to the FWS API to redeem saffron tokens
-async fn redeem_saffron(x: u64, client: &Client, mail_address: &str) -> Result<Response, reqwest::Error> {
-    // Create an instance of the SaffronOrder struct with the specified number of tokens, the mail address entered by the user, and a unique order number
-    let order = SaffronOrder {
-        x,
-               mail_address: mail_address.to_string(), // mail_address: ask_mail_address().await,
-        order_number: gen_order_number().await,
-    };
+// Create an instance of the SaffronOrder struct with the number of tokens redeemed on chain, the transaction that redeemed them, the mail address entered by the user, and a unique order number
+fn saffron_order(redemption: &VerifiedRedemption, mail_address: &MailingAddress, order_number: u64) -> SaffronOrder {
+    SaffronOrder {
+        // The grams were checked to be whole before the order number was allocated
+        x: redemption.grams(TOKEN_DECIMALS).unwrap_or_default(),
+        tx_hash: redemption.tx_hash,
+        holder: redemption.holder,
+        mail_address: mail_address.clone(), // mail_address: ask_mail_address().await,
+        order_number,
+    }
+}
 
-    // Set the placeholder AWS credentials and the URL for the FWS API
-    let creds = "AWS_CREDS_PLACEHOLDER";
-    let url = "https://fws.com/api/redeem_saffron";
-    // Make the HTTP POST request with the specified header and JSON body, and return the response
-    let res = client.post(url)
-        .header("Authorization", creds)
-        .json(&order)
+// Asynchronously make an HTTP POST request to the FWS API to redeem saffron tokens for an order from the outbox, and report how it went
+async fn redeem_saffron(client: &Client, config: &Config, entry: &OutboxEntry) -> Delivery {
+    // Make the HTTP POST request to the configured FWS API with the configured credentials, the order's idempotency key and the JSON body
+    let res = client.post(&config.fulfillment_endpoint)
+        .header("Authorization", config.fulfillment_credentials.expose())
+        .header("Idempotency-Key", entry.idempotency_key.as_str())
+        .header("Content-Type", "application/json")
+        .timeout(FULFILLMENT_TIMEOUT)
+        .body(entry.body.clone())
         .send()
         .await;
-       // Return the result of the request or an error message
+    // Sort the result into delivered, worth retrying, or refused for good
     match res {
-        Ok(response) => Ok(response),
-        Err(e) => Err(e),
+        Ok(response) if response.status().is_success() => Delivery::Delivered,
+        // The FWS API answers 409 when it already has an order with this idempotency key, i.e. an earlier attempt got through
+        Ok(response) if response.status() == StatusCode::CONFLICT => Delivery::Delivered,
+        Ok(response) if response.status().is_server_error()
+            || response.status() == StatusCode::REQUEST_TIMEOUT
+            || response.status() == StatusCode::TOO_MANY_REQUESTS =>
+        {
+            Delivery::Retry(format!("FWS API answered {}", response.status()))
+        }
+        Ok(response) => Delivery::Rejected(format!("FWS API refused the order with {}", response.status())),
+        // Timeouts and connection errors may or may not have reached the API; the idempotency key makes the retry safe
+        Err(e) => Delivery::Retry(config.redact(&e.to_string())),
     }
 }
 
-// Define routes for the application
+// Return the current time in seconds since the Unix epoch
+fn unix_now() -> u64 {
+    // A clock set before the epoch is treated as the epoch itself
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0)
+}
+
+// Asynchronously deliver the outbox's due fulfilment requests, forever. Failures are retried with exponential backoff and end up on the dead-letter list once the retry policy gives up.
+async fn run_outbox_worker(outbox: Arc<Outbox>, config: Config) {
+    let client = Client::new();
+    loop {
+        match outbox.due(unix_now()) {
+            Ok(entries) => {
+                for entry in entries {
+                    let delivery = redeem_saffron(&client, &config, &entry).await;
+                    match &delivery {
+                        Delivery::Delivered => info!("Order {} delivered to the FWS API", entry.order_number),
+                        Delivery::Retry(reason) => error!("Order {} failed, will retry: {}", entry.order_number, reason),
+                        Delivery::Rejected(reason) => error!("Order {} refused, moved to the dead-letter list: {}", entry.order_number, reason),
+                    }
+                    if let Err(e) = outbox.record(entry.order_number, &delivery, unix_now()) {
+                        error!("Could not record the outcome of order {}: {}", entry.order_number, e);
+                    }
+                }
+            }
+            Err(e) => error!("Could not read the outbox: {}", e),
+        }
+        tokio::time::sleep(OUTBOX_POLL_INTERVAL).await;
+    }
+}
+
+// Define routes for the application. The landing page is the redemption form.
 #[get("/")]
 fn index() -> Template {
-    let context = MailAddressForm { mail_address: "".to_string() };
-    Template::render("index", &context)
+    Template::render("mail_address_form", MailAddressContext::default())
 }
 
 #[post("/", data = "<form>")]
-async fn redeem(form: Form<MailAddressForm>) -> Redirect {
-    // Validate the mail address entered by the user
-    let mail_address = form.mail_address.trim();
-    if !mail_address.is_empty() {
-        // Create an instance of the reqwest::Client type
-        let client = Client::new();
-        // Call the redeem_saffron function to make the HTTP POST request to the FWS API
-        let res = redeem_saffron(10, &client, &mail_address).await;
-        match res {
-            Ok(_) => Redirect::to("/success"),
-            Err(_) => Redirect::to("/error"),
+async fn redeem(
+    form: Form<MailAddressForm>,
+    order_numbers: &State<Arc<OrderNumberAllocator>>,
+    verifier: &State<RedemptionVerifier>,
+    claims: &State<Arc<RedemptionClaims>>,
+    config: &State<Config>,
+    outbox: &State<Arc<Outbox>>,
+) -> Result<Redirect, Template> {
+    // Validate the mail address entered by the user. Saffron ships by courier, so PO boxes are
+    // refused too. A rejected address is shown again with the problem next to each field.
+    let form = form.into_inner();
+    let mail_address = match MailingAddress::parse(&form.to_fields())
+        .and_then(|address| address.require_street_address().map(|_| address))
+    {
+        Ok(mail_address) => mail_address,
+        Err(errors) => {
+            return Err(Template::render("mail_address_form", MailAddressContext::rejected(form, &errors)));
+        }
+    };
+    // Check on chain that the visitor's wallet burned or escrowed the tokens, and that it signed
+    // for the grams redeemed to be shipped to this address, so a signature seen by someone else
+    // cannot be replayed to send the saffron elsewhere
+    let verified = match RedemptionClaim::parse(&form.tx_hash, &form.grams, &mail_address, &form.signature) {
+        Ok(claim) => match verifier.verify(&claim).await {
+            Ok(redemption) => claim.grams_of(&redemption, TOKEN_DECIMALS).map(|_| redemption),
+            Err(error) => Err(error),
+        },
+        Err(error) => Err(error),
+    };
+    let redemption = match verified {
+        Ok(redemption) => redemption,
+        Err(error) => {
+            return Err(Template::render("mail_address_form", MailAddressContext::unverified(form, &error, config)));
+        }
+    };
+    // Allocate the order number before contacting the FWS API, so a failed request never shares
+    // its number with a later one
+    let order_number = match gen_order_number(Arc::clone(order_numbers)).await {
+        Ok(order_number) => order_number,
+        Err(_) => return Ok(Redirect::to("/error")),
+    };
+    // Bind the transaction to this order and queue the order in the outbox in one transaction,
+    // rather than calling the FWS API from the request. A transaction that has already been
+    // claimed, even by a request racing this one, is refused so the same burn never ships twice.
+    // Once the order is stored the background worker delivers it, retrying until it gets
+    // through, so the redemption survives FWS outages and restarts.
+    let order = saffron_order(&redemption, &mail_address, order_number);
+    let body = match serde_json::to_string(&order) {
+        Ok(body) => body,
+        Err(_) => return Ok(Redirect::to("/error")),
+    };
+    // Storing them waits for the disk, so it runs on the blocking thread pool.
+    let (claims, outbox, tx_hash) = (Arc::clone(claims), Arc::clone(outbox), redemption.tx_hash);
+    let queued = tokio::task::spawn_blocking(move || claims.claim_and_enqueue(&tx_hash, order_number, &outbox, body, unix_now()))
+        .await
+        .unwrap_or_else(|e| Err(ProofError::Storage(e.to_string())));
+    match queued {
+        Ok(_) => Ok(Redirect::to("/success")),
+        Err(error @ ProofError::AlreadyClaimed { .. }) => {
+            Err(Template::render("mail_address_form", MailAddressContext::unverified(form, &error, config)))
+        }
+        Err(e) => {
+            // Neither the claim nor the order was stored, so the visitor can simply try again
+            error!("Order {} for {:?} could not be queued: {}", order_number, tx_hash, e);
+            Ok(Redirect::to("/error"))
         }
-    } else {
-        // Return an error if the mail address is empty
-        Redirect::to("/error")
     }
 }
-    
+
 #[get("/success")]
 fn success() -> Template {
-    Template::render("success", &())
+    Template::render("success", BTreeMap::<String, String>::new())
 }
 
 #[get("/error")]
 fn error() -> Template {
-    Template::render("error", &())
+    Template::render("error", BTreeMap::<String, String>::new())
+}
+
+// Define a request guard for the admin routes: the request must carry the configured admin token
+// as "Authorization: Bearer <token>". Without a configured token the admin routes are turned off.
+struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let token = match request.rocket().state::<Config>().and_then(|config| config.admin_token.as_ref()) {
+            Some(token) => token,
+            None => return Outcome::Error((Status::Forbidden, ())),
+        };
+        let presented = request.headers().get_one("Authorization").and_then(|value| value.strip_prefix("Bearer "));
+        match presented {
+            Some(presented) if token.matches(presented) => Outcome::Success(Admin),
+            _ => Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+// Define the outbox contents shown to operators
+#[derive(Serialize)]
+struct OutboxStatus {
+    pending: Vec<OutboxEntry>,
+    dead_letters: Vec<OutboxEntry>,
+}
+
+// Define a route for operators to inspect the pending and dead-letter fulfilment requests. The entries hold customers' addresses, so only requests carrying the admin token are answered.
+#[get("/admin/outbox")]
+fn outbox_status(_admin: Admin, outbox: &State<Arc<Outbox>>) -> Result<Json<OutboxStatus>, Status> {
+    let pending = outbox.pending().map_err(|_| Status::InternalServerError)?;
+    let dead_letters = outbox.dead_letters().map_err(|_| Status::InternalServerError)?;
+    Ok(Json(OutboxStatus { pending, dead_letters }))
+}
+
+// Define a route for operators to move a dead-letter fulfilment request back into the outbox, once whatever made it fail has been fixed. Only requests carrying the admin token are answered.
+#[post("/admin/outbox/<order_number>/replay")]
+fn replay_dead_letter(_admin: Admin, order_number: u64, outbox: &State<Arc<Outbox>>) -> Result<Json<OutboxEntry>, Status> {
+    match outbox.replay(order_number, unix_now()) {
+        Ok(entry) => Ok(Json(entry)),
+        Err(saffron_outbox::OutboxError::UnknownOrder(_)) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+#[rocket::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Load and check the configuration before anything else, so a bad setting stops the server
+    // at startup with every problem listed. Secrets are redacted when it is printed.
+    let config = Config::load()?;
+    println!("Starting the redemption server with:\n{}", config);
+
+    // Open the order number sequence, with check digits so support staff can spot mistyped
+    // order numbers
+    let order_numbers = Arc::new(OrderNumberAllocator::open(config.database_path(ORDER_NUMBER_DB), true)?);
+    // Connect to the Ethereum node redemptions are verified against. The node's URL carries the
+    // API key, so errors are redacted.
+    let verifier = RedemptionVerifier::new(&config.rpc_url(), config.token_address, config.vendor_address, config.confirmations)
+        .map_err(|err| config.redact(&err.to_string()))?;
+
+    // Open the outbox and the record of redemptions already shipped, then start the worker that
+    // delivers the outbox to the FWS API in the background. Both live in the same database, so a
+    // claim and its order are stored together.
+    let db = sled::open(config.database_path(OUTBOX_DB))?;
+    let claims = Arc::new(RedemptionClaims::open_in(&db)?);
+    let outbox = Arc::new(Outbox::open_in(db, RetryPolicy::default())?);
+    tokio::spawn(run_outbox_worker(Arc::clone(&outbox), config.clone()));
+
+    // Listen on the configured address
+    let figment = rocket::Config::figment()
+        .merge(("address", config.bind_address.ip()))
+        .merge(("port", config.bind_address.port()));
     // Initialize the Rocket application and start the server
-    rocket::ignite()
-        .mount("/", routes![index, redeem, success, error])
-        .launch();
+    rocket::custom(figment)
+        .attach(Template::fairing())
+        .manage(config)
+        .manage(order_numbers)
+        .manage(verifier)
+        .manage(claims)
+        .manage(outbox)
+        .mount("/", routes![index, mail_address_form, redeem, success, error, outbox_status, replay_dead_letter])
+        .launch()
+        .await?;
     Ok(())
-}   
-    
-// Define the main function for the program
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Create an instance of the reqwest::Client type
-    let client = Client::new();
+}
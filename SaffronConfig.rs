@@ -0,0 +1,590 @@
+use serde::Deserialize;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use web3::types::Address;
+
+// The file the configuration is read from when SAFFRON_CONFIG is not set
+pub const DEFAULT_CONFIG_PATH: &str = "Saffron.toml";
+
+// What secrets are replaced with wherever the configuration is printed
+const REDACTED: &str = "[REDACTED]";
+
+// The shortest admin token accepted, so that it cannot be guessed
+const MIN_ADMIN_TOKEN_LEN: usize = 32;
+
+// ConfigError describes why the configuration could not be loaded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    // The configuration file could not be read
+    Io { path: PathBuf, message: String },
+    // The configuration file is not valid TOML or has fields of the wrong type
+    Parse { path: PathBuf, message: String },
+    // One or more settings are missing or invalid, listed as (setting, problem) pairs
+    Invalid(Vec<(String, String)>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, message } => write!(f, "could not read {}: {}", path.display(), message),
+            ConfigError::Parse { path, message } => write!(f, "could not parse {}: {}", path.display(), message),
+            ConfigError::Invalid(problems) => {
+                write!(f, "invalid configuration:")?;
+                for (setting, problem) in problems {
+                    write!(f, "\n  {}: {}", setting, problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// Secret holds a value, such as an API key, that must never be printed. Its Debug and Display
+// output is always "[REDACTED]"; the value itself is only available through `expose`.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Secret(value.into())
+    }
+
+    // Return the secret value, for handing to the service it is meant for
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    // Return true if `candidate` is the secret. Every byte is compared whatever the outcome, so
+    // the time taken does not give away how much of a guess was right.
+    pub fn matches(&self, candidate: &str) -> bool {
+        let (secret, candidate) = (self.0.as_bytes(), candidate.as_bytes());
+        let differences = secret
+            .iter()
+            .zip(candidate)
+            .fold(0u8, |differences, (a, b)| differences | (a ^ b));
+        differences == 0 && secret.len() == candidate.len()
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+// FileConfig is the configuration file as written. Every setting is optional here, so that
+// missing ones can be reported together once the environment overrides have been applied.
+//
+//   rpc_endpoint = "https://mainnet.infura.io/v3"
+//   token_address = "0x..."
+//   vendor_address = "0x..."
+//   confirmations = 12
+//   fulfillment_endpoint = "https://fws.com/api/redeem_saffron"
+//   fulfillment_credentials_file = "/etc/saffron/fws-credentials"
+//   bind_address = "0.0.0.0:8000"
+//   data_dir = "/var/lib/saffron"
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    rpc_endpoint: Option<String>,
+    rpc_api_key: Option<Secret>,
+    token_address: Option<String>,
+    vendor_address: Option<String>,
+    confirmations: Option<u64>,
+    fulfillment_endpoint: Option<String>,
+    fulfillment_credentials_file: Option<PathBuf>,
+    bind_address: Option<String>,
+    data_dir: Option<PathBuf>,
+    admin_token: Option<Secret>,
+}
+
+// Config is the checked configuration of the redemption server
+#[derive(Clone)]
+pub struct Config {
+    // The Ethereum JSON-RPC endpoint, without the API key
+    pub rpc_endpoint: String,
+    // The API key appended to the endpoint's path, as providers such as Infura expect
+    pub rpc_api_key: Option<Secret>,
+    // The deployed $affron token contract
+    pub token_address: Address,
+    // The vendor contract redeemed tokens are escrowed in
+    pub vendor_address: Address,
+    // The number of blocks a redemption must be buried under before it ships
+    pub confirmations: u64,
+    // The fulfilment service URL redemption orders are posted to
+    pub fulfillment_endpoint: String,
+    // The file the fulfilment service credentials were read from
+    pub fulfillment_credentials_file: PathBuf,
+    // The credentials sent in the Authorization header of each fulfilment request
+    pub fulfillment_credentials: Secret,
+    // The address and port the web server listens on
+    pub bind_address: SocketAddr,
+    // The directory the server's databases are kept in
+    pub data_dir: PathBuf,
+    // The bearer token operators must send to use the admin routes. Without one the admin
+    // routes are turned off.
+    pub admin_token: Option<Secret>,
+}
+
+impl Config {
+    // Load the configuration from the TOML file named by SAFFRON_CONFIG, or Saffron.toml if that
+    // is not set, then apply the environment overrides and check the result. A missing default
+    // file is not an error, so the server can be configured from the environment alone.
+    pub fn load() -> Result<Self, ConfigError> {
+        match env::var("SAFFRON_CONFIG") {
+            Ok(path) => Self::load_from(Path::new(&path), true),
+            Err(_) => Self::load_from(Path::new(DEFAULT_CONFIG_PATH), false),
+        }
+    }
+
+    // Load the configuration from the TOML file at `path`, then apply the environment overrides
+    // and check the result
+    pub fn load_from(path: &Path, required: bool) -> Result<Self, ConfigError> {
+        let mut file = if path.exists() || required {
+            let contents = fs::read_to_string(path).map_err(|err| ConfigError::Io {
+                path: path.to_path_buf(),
+                message: err.to_string(),
+            })?;
+            toml::from_str(&contents).map_err(|err| ConfigError::Parse {
+                path: path.to_path_buf(),
+                message: err.to_string(),
+            })?
+        } else {
+            FileConfig::default()
+        };
+        let mut problems = Vec::new();
+        apply_env_overrides(&mut file, &mut problems);
+        Self::validate(file, problems)
+    }
+
+    // Check every setting, reporting all the problems at once rather than one per restart
+    fn validate(file: FileConfig, mut problems: Vec<(String, String)>) -> Result<Self, ConfigError> {
+        let mut problem = |setting: &str, message: String| problems.push((setting.to_string(), message));
+
+        let rpc_endpoint = file.rpc_endpoint.unwrap_or_default().trim_end_matches('/').to_string();
+        if rpc_endpoint.is_empty() {
+            problem("rpc_endpoint", "is required".to_string());
+        } else if !["http://", "https://", "ws://", "wss://"].iter().any(|scheme| rpc_endpoint.starts_with(scheme)) {
+            problem("rpc_endpoint", "must be an http(s) or ws(s) URL".to_string());
+        }
+
+        let token_address = parse_address("token_address", file.token_address, &mut problem);
+        let vendor_address = parse_address("vendor_address", file.vendor_address, &mut problem);
+
+        let confirmations = file.confirmations.unwrap_or(12);
+        if confirmations == 0 {
+            problem("confirmations", "must be at least 1".to_string());
+        }
+
+        // Credentials and addresses go to the fulfilment service, so it must be reached over
+        // TLS unless it runs on this machine
+        let fulfillment_endpoint = file.fulfillment_endpoint.unwrap_or_default();
+        if fulfillment_endpoint.is_empty() {
+            problem("fulfillment_endpoint", "is required".to_string());
+        } else if !fulfillment_endpoint.starts_with("https://") && !is_local_http(&fulfillment_endpoint) {
+            problem("fulfillment_endpoint", "must be an https URL".to_string());
+        }
+
+        let fulfillment_credentials_file = file.fulfillment_credentials_file.unwrap_or_default();
+        let mut fulfillment_credentials = Secret::new(String::new());
+        if fulfillment_credentials_file.as_os_str().is_empty() {
+            problem("fulfillment_credentials_file", "is required".to_string());
+        } else {
+            match fs::read_to_string(&fulfillment_credentials_file) {
+                Ok(contents) if !contents.trim().is_empty() => fulfillment_credentials = Secret::new(contents.trim()),
+                Ok(_) => problem("fulfillment_credentials_file", format!("{} is empty", fulfillment_credentials_file.display())),
+                Err(err) => problem(
+                    "fulfillment_credentials_file",
+                    format!("could not read {}: {}", fulfillment_credentials_file.display(), err),
+                ),
+            }
+        }
+
+        let bind_address = file.bind_address.unwrap_or_else(|| "127.0.0.1:8000".to_string());
+        let bind_address = match bind_address.parse::<SocketAddr>() {
+            Ok(address) => Some(address),
+            Err(_) => {
+                problem("bind_address", format!("{} is not an address and port", bind_address));
+                None
+            }
+        };
+
+        let data_dir = file.data_dir.unwrap_or_else(|| PathBuf::from("."));
+        if !data_dir.is_dir() {
+            problem("data_dir", format!("{} is not a directory", data_dir.display()));
+        }
+
+        let admin_token = file.admin_token.filter(|token| !token.expose().is_empty());
+        if admin_token.as_ref().is_some_and(|token| token.expose().len() < MIN_ADMIN_TOKEN_LEN) {
+            problem("admin_token", format!("must be at least {} characters", MIN_ADMIN_TOKEN_LEN));
+        }
+
+        if !problems.is_empty() {
+            return Err(ConfigError::Invalid(problems));
+        }
+        Ok(Self {
+            rpc_endpoint,
+            rpc_api_key: file.rpc_api_key.filter(|key| !key.expose().is_empty()),
+            token_address: token_address.unwrap_or_default(),
+            vendor_address: vendor_address.unwrap_or_default(),
+            confirmations,
+            fulfillment_endpoint,
+            fulfillment_credentials_file,
+            fulfillment_credentials,
+            bind_address: bind_address.expect("checked above"),
+            data_dir,
+            admin_token,
+        })
+    }
+
+    // Return the full RPC URL, including the API key. Never log this; log the Config instead.
+    pub fn rpc_url(&self) -> String {
+        match &self.rpc_api_key {
+            Some(key) => format!("{}/{}", self.rpc_endpoint, key.expose()),
+            None => self.rpc_endpoint.clone(),
+        }
+    }
+
+    // Return the path of the database `name` in the data directory
+    pub fn database_path(&self, name: &str) -> PathBuf {
+        self.data_dir.join(name)
+    }
+
+    // Replace every secret in `text` with "[REDACTED]". Use this on error messages from
+    // libraries that may echo a URL or header back, before logging or showing them.
+    pub fn redact(&self, text: &str) -> String {
+        let mut redacted = text.to_string();
+        let secrets = [self.rpc_api_key.as_ref(), Some(&self.fulfillment_credentials), self.admin_token.as_ref()];
+        for secret in secrets.iter().flatten() {
+            if !secret.expose().is_empty() {
+                redacted = redacted.replace(secret.expose(), REDACTED);
+            }
+        }
+        redacted
+    }
+}
+
+impl fmt::Display for Config {
+    // Print the configuration for the startup log, with every secret redacted
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "rpc_endpoint = {}", self.rpc_endpoint)?;
+        writeln!(f, "rpc_api_key = {}", if self.rpc_api_key.is_some() { REDACTED } else { "(none)" })?;
+        writeln!(f, "token_address = {:?}", self.token_address)?;
+        writeln!(f, "vendor_address = {:?}", self.vendor_address)?;
+        writeln!(f, "confirmations = {}", self.confirmations)?;
+        writeln!(f, "fulfillment_endpoint = {}", self.fulfillment_endpoint)?;
+        writeln!(f, "fulfillment_credentials_file = {}", self.fulfillment_credentials_file.display())?;
+        writeln!(f, "bind_address = {}", self.bind_address)?;
+        writeln!(f, "data_dir = {}", self.data_dir.display())?;
+        write!(f, "admin_token = {}", if self.admin_token.is_some() { REDACTED } else { "(none; admin routes disabled)" })
+    }
+}
+
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+// Replace file settings with the SAFFRON_* environment variables that are set. INFURA_API_KEY is
+// accepted for the RPC API key too, as older deployments set it.
+fn apply_env_overrides(file: &mut FileConfig, problems: &mut Vec<(String, String)>) {
+    let var = |name: &str| env::var(name).ok().filter(|value| !value.is_empty());
+
+    if let Some(value) = var("SAFFRON_RPC_ENDPOINT") {
+        file.rpc_endpoint = Some(value);
+    }
+    if let Some(value) = var("SAFFRON_RPC_API_KEY").or_else(|| var("INFURA_API_KEY")) {
+        file.rpc_api_key = Some(Secret::new(value));
+    }
+    if let Some(value) = var("SAFFRON_TOKEN_ADDRESS") {
+        file.token_address = Some(value);
+    }
+    if let Some(value) = var("SAFFRON_VENDOR_ADDRESS") {
+        file.vendor_address = Some(value);
+    }
+    if let Some(value) = var("SAFFRON_CONFIRMATIONS") {
+        match value.parse() {
+            Ok(confirmations) => file.confirmations = Some(confirmations),
+            Err(_) => problems.push(("SAFFRON_CONFIRMATIONS".to_string(), format!("{} is not a number", value))),
+        }
+    }
+    if let Some(value) = var("SAFFRON_FULFILLMENT_ENDPOINT") {
+        file.fulfillment_endpoint = Some(value);
+    }
+    if let Some(value) = var("SAFFRON_FULFILLMENT_CREDENTIALS_FILE") {
+        file.fulfillment_credentials_file = Some(PathBuf::from(value));
+    }
+    if let Some(value) = var("SAFFRON_BIND_ADDRESS") {
+        file.bind_address = Some(value);
+    }
+    if let Some(value) = var("SAFFRON_DATA_DIR") {
+        file.data_dir = Some(PathBuf::from(value));
+    }
+    if let Some(value) = var("SAFFRON_ADMIN_TOKEN") {
+        file.admin_token = Some(Secret::new(value));
+    }
+}
+
+// Parse a required, non-zero contract address
+fn parse_address(setting: &str, value: Option<String>, problem: &mut impl FnMut(&str, String)) -> Option<Address> {
+    let value = match value {
+        Some(value) if !value.trim().is_empty() => value,
+        _ => {
+            problem(setting, "is required".to_string());
+            return None;
+        }
+    };
+    match value.trim().parse::<Address>() {
+        Ok(address) if !address.is_zero() => Some(address),
+        Ok(_) => {
+            problem(setting, "must not be the zero address".to_string());
+            None
+        }
+        Err(_) => {
+            problem(setting, format!("{} is not an address", value));
+            None
+        }
+    }
+}
+
+// Return true for plain-http URLs that point at this machine. The URL is parsed and its host
+// compared whole, so hosts such as localhost.example.com or localhost@example.com do not pass.
+fn is_local_http(url: &str) -> bool {
+    match reqwest::Url::parse(url) {
+        Ok(url) => url.scheme() == "http" && matches!(url.host_str(), Some("localhost" | "127.0.0.1" | "[::1]")),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::sync::Mutex;
+
+    // The environment is shared by every test in the process, so the tests that load a
+    // configuration take turns
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    // Every environment variable that overrides a setting
+    const ENV_VARS: &[&str] = &[
+        "SAFFRON_RPC_ENDPOINT",
+        "SAFFRON_RPC_API_KEY",
+        "INFURA_API_KEY",
+        "SAFFRON_TOKEN_ADDRESS",
+        "SAFFRON_VENDOR_ADDRESS",
+        "SAFFRON_CONFIRMATIONS",
+        "SAFFRON_FULFILLMENT_ENDPOINT",
+        "SAFFRON_FULFILLMENT_CREDENTIALS_FILE",
+        "SAFFRON_BIND_ADDRESS",
+        "SAFFRON_DATA_DIR",
+        "SAFFRON_ADMIN_TOKEN",
+    ];
+
+    // Run `test` with only the given overrides set in the environment
+    fn with_env(vars: &[(&str, &str)], test: impl FnOnce()) {
+        let _lock = ENV_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        ENV_VARS.iter().for_each(|name| env::remove_var(name));
+        vars.iter().for_each(|(name, value)| env::set_var(name, value));
+        test();
+        ENV_VARS.iter().for_each(|name| env::remove_var(name));
+    }
+
+    // Write a valid configuration file to `dir`, with the given settings replaced or added as
+    // TOML values, and return its path. The credentials file and data directory are in `dir`.
+    fn write_config(dir: &Path, settings: &[(&str, &str)]) -> PathBuf {
+        fs::write(dir.join("fws-credentials"), "Basic c2FmZnJvbg==\n").unwrap();
+        let mut file: BTreeMap<&str, String> = BTreeMap::new();
+        file.insert("rpc_endpoint", "\"https://mainnet.infura.io/v3/\"".to_string());
+        file.insert("rpc_api_key", "\"infura-key\"".to_string());
+        file.insert("token_address", "\"0x0000000000000000000000000000000000000001\"".to_string());
+        file.insert("vendor_address", "\"0x0000000000000000000000000000000000000002\"".to_string());
+        file.insert("confirmations", "6".to_string());
+        file.insert("fulfillment_endpoint", "\"https://fws.com/api/redeem_saffron\"".to_string());
+        file.insert("fulfillment_credentials_file", format!("\"{}\"", dir.join("fws-credentials").display()));
+        file.insert("bind_address", "\"0.0.0.0:9000\"".to_string());
+        file.insert("data_dir", format!("\"{}\"", dir.display()));
+        for (setting, value) in settings {
+            file.insert(setting, value.to_string());
+        }
+        let contents: String = file.iter().map(|(setting, value)| format!("{} = {}\n", setting, value)).collect();
+        let path = dir.join("Saffron.toml");
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    // Return the settings named in an Invalid error, in the order they were reported
+    fn invalid_settings(result: Result<Config, ConfigError>) -> Vec<String> {
+        match result {
+            Err(ConfigError::Invalid(problems)) => problems.into_iter().map(|(setting, _)| setting).collect(),
+            other => panic!("expected invalid settings, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn settings_are_read_from_the_toml_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_config(dir.path(), &[]);
+        with_env(&[], || {
+            let config = Config::load_from(&path, true).unwrap();
+            assert_eq!(config.rpc_endpoint, "https://mainnet.infura.io/v3");
+            assert_eq!(config.rpc_url(), "https://mainnet.infura.io/v3/infura-key");
+            assert_eq!(config.token_address, Address::from_low_u64_be(1));
+            assert_eq!(config.vendor_address, Address::from_low_u64_be(2));
+            assert_eq!(config.confirmations, 6);
+            assert_eq!(config.fulfillment_endpoint, "https://fws.com/api/redeem_saffron");
+            assert_eq!(config.fulfillment_credentials.expose(), "Basic c2FmZnJvbg==");
+            assert_eq!(config.bind_address, "0.0.0.0:9000".parse().unwrap());
+            assert_eq!(config.database_path("outbox.db"), dir.path().join("outbox.db"));
+            assert_eq!(config.admin_token, None);
+
+            // Secrets never reach the startup log
+            let printed = config.to_string();
+            assert!(!printed.contains("infura-key") && !printed.contains("c2FmZnJvbg"), "{}", printed);
+        });
+    }
+
+    #[test]
+    fn environment_variables_override_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_config(dir.path(), &[]);
+        let admin_token = "a".repeat(MIN_ADMIN_TOKEN_LEN);
+        let overrides = [
+            ("SAFFRON_CONFIRMATIONS", "30"),
+            ("SAFFRON_BIND_ADDRESS", "127.0.0.1:8080"),
+            ("INFURA_API_KEY", "older-key"),
+            ("SAFFRON_ADMIN_TOKEN", admin_token.as_str()),
+        ];
+        with_env(&overrides, || {
+            let config = Config::load_from(&path, true).unwrap();
+            assert_eq!(config.confirmations, 30);
+            assert_eq!(config.bind_address, "127.0.0.1:8080".parse().unwrap());
+            assert_eq!(config.rpc_url(), "https://mainnet.infura.io/v3/older-key");
+            assert!(config.admin_token.is_some_and(|token| token.matches(&admin_token)));
+        });
+
+        // The new name for the API key wins over the old one, and a bad number is reported
+        // against the variable rather than the file
+        let overrides = [("SAFFRON_RPC_API_KEY", "new-key"), ("INFURA_API_KEY", "older-key"), ("SAFFRON_CONFIRMATIONS", "many")];
+        with_env(&overrides, || {
+            assert_eq!(invalid_settings(Config::load_from(&path, true)), vec!["SAFFRON_CONFIRMATIONS"]);
+        });
+        with_env(&overrides[..2], || {
+            assert_eq!(Config::load_from(&path, true).unwrap().rpc_url(), "https://mainnet.infura.io/v3/new-key");
+        });
+    }
+
+    #[test]
+    fn a_mistyped_setting_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_config(dir.path(), &[("confirmation", "6")]);
+        with_env(&[], || match Config::load_from(&path, true) {
+            Err(ConfigError::Parse { path: reported, message }) => {
+                assert_eq!(reported, path);
+                assert!(message.contains("unknown field `confirmation`"), "{}", message);
+            }
+            other => panic!("expected a parse error, got {:?}", other.map(|_| ())),
+        });
+    }
+
+    #[test]
+    fn a_missing_file_is_an_error_only_when_it_was_asked_for() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing.toml");
+        with_env(&[], || {
+            assert!(matches!(Config::load_from(&path, true), Err(ConfigError::Io { .. })));
+            assert_eq!(
+                invalid_settings(Config::load_from(&path, false)),
+                vec!["rpc_endpoint", "token_address", "vendor_address", "fulfillment_endpoint", "fulfillment_credentials_file"]
+            );
+        });
+    }
+
+    #[test]
+    fn every_invalid_setting_is_reported_at_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_config(
+            dir.path(),
+            &[
+                ("rpc_endpoint", "\"ftp://mainnet.infura.io\""),
+                ("token_address", "\"0x0000000000000000000000000000000000000000\""),
+                ("vendor_address", "\"vendor\""),
+                ("confirmations", "0"),
+                ("fulfillment_endpoint", "\"http://fws.com/api/redeem_saffron\""),
+                ("fulfillment_credentials_file", "\"/nonexistent/fws-credentials\""),
+                ("bind_address", "\"localhost\""),
+                ("data_dir", "\"/nonexistent\""),
+                ("admin_token", "\"short\""),
+            ],
+        );
+        with_env(&[], || {
+            let problems = match Config::load_from(&path, true) {
+                Err(ConfigError::Invalid(problems)) => problems,
+                other => panic!("expected invalid settings, got {:?}", other.map(|_| ())),
+            };
+            let problems: BTreeMap<&str, &str> = problems.iter().map(|(setting, problem)| (setting.as_str(), problem.as_str())).collect();
+            assert_eq!(problems["rpc_endpoint"], "must be an http(s) or ws(s) URL");
+            assert_eq!(problems["token_address"], "must not be the zero address");
+            assert_eq!(problems["vendor_address"], "vendor is not an address");
+            assert_eq!(problems["confirmations"], "must be at least 1");
+            assert_eq!(problems["fulfillment_endpoint"], "must be an https URL");
+            assert!(problems["fulfillment_credentials_file"].starts_with("could not read /nonexistent/fws-credentials"));
+            assert_eq!(problems["bind_address"], "localhost is not an address and port");
+            assert_eq!(problems["data_dir"], "/nonexistent is not a directory");
+            assert_eq!(problems["admin_token"], format!("must be at least {} characters", MIN_ADMIN_TOKEN_LEN));
+            assert_eq!(problems.len(), 9);
+        });
+
+        // Plain http is fine for a fulfilment service on this machine, but an empty credentials
+        // file is not
+        fs::write(dir.path().join("empty-credentials"), "  \n").unwrap();
+        let empty = format!("\"{}\"", dir.path().join("empty-credentials").display());
+        let path = write_config(
+            dir.path(),
+            &[("fulfillment_endpoint", "\"http://localhost:8080/redeem\""), ("fulfillment_credentials_file", empty.as_str())],
+        );
+        with_env(&[], || {
+            assert_eq!(invalid_settings(Config::load_from(&path, true)), vec!["fulfillment_credentials_file"]);
+        });
+    }
+
+    #[test]
+    fn secrets_match_only_themselves() {
+        let token = Secret::new("correct horse battery staple");
+        assert!(token.matches("correct horse battery staple"));
+        assert!(!token.matches("correct horse battery stapler"));
+        assert!(!token.matches("correct horse battery"));
+        assert!(!token.matches(""));
+        assert_eq!(format!("{} {:?}", token, token), "[REDACTED] [REDACTED]");
+    }
+
+    #[test]
+    fn only_this_machine_counts_as_local() {
+        for url in ["http://localhost:8080/redeem", "http://127.0.0.1/", "http://[::1]:9000", "http://LOCALHOST"] {
+            assert!(is_local_http(url), "{}", url);
+        }
+        for url in [
+            "http://localhost.example.com/",
+            "http://localhost@example.com/",
+            "http://127.0.0.1.example.com/",
+            "http://example.com/?http://localhost",
+            "https://localhost/",
+            "http//localhost",
+        ] {
+            assert!(!is_local_http(url), "{}", url);
+        }
+    }
+}
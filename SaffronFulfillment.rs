@@ -0,0 +1,370 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+use chrono::{TimeZone, Utc};
+use reqwest::blocking::Client;
+use serde_json::{json, Value};
+
+use crate::saffron_mailing_address::MailingAddress;
+
+// The item name the warehouse stocks saffron under. Orders are placed in grams of this item.
+pub const SAFFRON_ITEM: &str = "SAFFRON_GRAM";
+
+// FulfillmentError describes why a fulfilment provider could not carry out a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FulfillmentError {
+    // The provider does not know an order with this id
+    UnknownOrder(String),
+    // The order has already shipped and can no longer be cancelled
+    AlreadyShipped(String),
+    // The provider refused the request or could not be reached
+    Provider(String),
+    // The local order file could not be read or written
+    Storage(String),
+}
+
+impl fmt::Display for FulfillmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FulfillmentError::UnknownOrder(order_id) => write!(f, "unknown fulfillment order {}", order_id),
+            FulfillmentError::AlreadyShipped(order_id) => write!(f, "fulfillment order {} has already shipped", order_id),
+            FulfillmentError::Provider(message) => write!(f, "fulfillment provider error: {}", message),
+            FulfillmentError::Storage(message) => write!(f, "fulfillment storage error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for FulfillmentError {}
+
+// FulfillmentRequest is everything a provider needs to ship the saffron for one redemption
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FulfillmentRequest {
+    // The order id, unique per redemption so a retried request cannot ship twice
+    pub order_id: String,
+    // The item to ship
+    pub item: String,
+    // The number of grams to ship
+    pub grams: u64,
+    // When the redemption was requested, as an RFC 3339 timestamp
+    pub order_date_time: String,
+    // A comment shown with the order
+    pub comment: String,
+    // Where to ship the saffron
+    pub destination: ShippingAddress,
+}
+
+// ShippingAddress is the address an order is shipped to, in the form fulfilment services take it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShippingAddress {
+    // The recipient's name
+    pub name: String,
+    pub line1: String,
+    pub line2: Option<String>,
+    pub city: String,
+    // The state or region code, e.g. "CA"
+    pub state_or_region: String,
+    // The postal code, with the ZIP+4 add-on if it is known
+    pub postal_code: String,
+    // The two-letter country code
+    pub country_code: String,
+}
+
+impl From<&MailingAddress> for ShippingAddress {
+    fn from(address: &MailingAddress) -> Self {
+        let postal_code = match &address.zip4 {
+            Some(zip4) => format!("{}-{}", address.zip5, zip4),
+            None => address.zip5.clone(),
+        };
+        Self {
+            name: address.name.clone(),
+            line1: address.line1.clone(),
+            line2: address.line2.clone(),
+            city: address.city.clone(),
+            state_or_region: address.state.clone(),
+            postal_code,
+            country_code: address.country.clone(),
+        }
+    }
+}
+
+impl FulfillmentRequest {
+    // Build the request for redemption `redemption_id` of `grams` grams requested at the Unix
+    // timestamp `requested_at`, shipping to `destination`
+    pub fn for_redemption(redemption_id: u64, grams: u64, requested_at: u64, destination: ShippingAddress) -> Self {
+        // A timestamp chrono cannot represent falls back to the epoch
+        let order_date_time = Utc
+            .timestamp_opt(requested_at as i64, 0)
+            .single()
+            .unwrap_or_else(|| Utc.timestamp_opt(0, 0).unwrap())
+            .to_rfc3339();
+        Self {
+            order_id: order_id_for_redemption(redemption_id),
+            item: SAFFRON_ITEM.to_string(),
+            grams,
+            order_date_time,
+            comment: format!("Order for Saffron token redemption {}", redemption_id),
+            destination,
+        }
+    }
+}
+
+// FulfillmentStatus is where an order is in the provider's hands
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FulfillmentStatus {
+    // The provider has accepted the order but not shipped it, so there is no tracking number yet
+    Processing,
+    // The order has shipped
+    Shipped { tracking_number: String, carrier_code: String },
+    // The order was cancelled before it shipped
+    Cancelled,
+    // The provider cannot ship the order, e.g. because it is out of stock
+    Unfulfillable(String),
+}
+
+// FulfillmentProvider is a service that ships saffron for redemptions. The ledger only talks to
+// this trait, so the warehouse behind it can be swapped without touching the token code.
+pub trait FulfillmentProvider {
+    // Place an order. Placing an order whose id already exists returns the existing status
+    // instead of creating a second shipment.
+    fn create_order(&self, request: &FulfillmentRequest) -> Result<FulfillmentStatus, FulfillmentError>;
+
+    // Return the current status of an order
+    fn order_status(&self, order_id: &str) -> Result<FulfillmentStatus, FulfillmentError>;
+
+    // Cancel an order that has not shipped yet
+    fn cancel_order(&self, order_id: &str) -> Result<(), FulfillmentError>;
+}
+
+// Return the provider order id used for the redemption with the given ledger id
+pub fn order_id_for_redemption(redemption_id: u64) -> String {
+    format!("SAFFRON-REDEMPTION-{}", redemption_id)
+}
+
+// The path of the fulfilment orders resource of the AWS fulfilment outbound API
+const FULFILLMENT_ORDERS_PATH: &str = "/fba/outbound/2020-07-01/fulfillmentOrders";
+
+// AwsFulfillmentProvider ships orders through the AWS fulfilment outbound API
+pub struct AwsFulfillmentProvider {
+    // The client used to talk to the fulfilment service
+    client: Client,
+    // The base URL of the fulfilment service, e.g. https://sellingpartnerapi-na.amazon.com
+    endpoint: String,
+    // The access token sent with every request
+    access_token: String,
+}
+
+impl AwsFulfillmentProvider {
+    // Create a provider that talks to the fulfilment service at `endpoint`, authenticating with
+    // `access_token`
+    pub fn new(endpoint: &str, access_token: &str) -> Self {
+        Self {
+            client: Client::new(),
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            access_token: access_token.to_string(),
+        }
+    }
+
+    // Return the URL of the order with the given id, or of the orders resource itself
+    fn orders_url(&self, order_id: Option<&str>) -> String {
+        match order_id {
+            Some(order_id) => format!("{}{}/{}", self.endpoint, FULFILLMENT_ORDERS_PATH, order_id),
+            None => format!("{}{}", self.endpoint, FULFILLMENT_ORDERS_PATH),
+        }
+    }
+
+    // Send a request and return the JSON body of a successful response, or an error naming
+    // `action` if the request fails. A 404 is reported as an unknown order.
+    fn send(&self, request: reqwest::blocking::RequestBuilder, order_id: &str, action: &str) -> Result<Value, FulfillmentError> {
+        let response = request
+            .header("x-amz-access-token", &self.access_token)
+            .send()
+            .map_err(|err| FulfillmentError::Provider(format!("Error {} fulfillment order: {}", action, err)))?;
+        let status = response.status();
+        if status == reqwest::StatusCode::NOT_FOUND {
+            return Err(FulfillmentError::UnknownOrder(order_id.to_string()));
+        }
+        if !status.is_success() {
+            return Err(FulfillmentError::Provider(format!("Error {} fulfillment order: {}", action, status)));
+        }
+        // Some calls answer with an empty body
+        let body = response
+            .text()
+            .map_err(|err| FulfillmentError::Provider(format!("Error {} fulfillment order: {}", action, err)))?;
+        if body.trim().is_empty() {
+            return Ok(Value::Null);
+        }
+        serde_json::from_str(&body).map_err(|err| FulfillmentError::Provider(format!("Error {} fulfillment order: {}", action, err)))
+    }
+}
+
+impl FulfillmentProvider for AwsFulfillmentProvider {
+    fn create_order(&self, request: &FulfillmentRequest) -> Result<FulfillmentStatus, FulfillmentError> {
+        // The service does not deduplicate orders for us, so look the order up first. A retry
+        // after a lost response then returns the existing order instead of shipping twice.
+        match self.order_status(&request.order_id) {
+            Ok(status) => return Ok(status),
+            Err(FulfillmentError::UnknownOrder(_)) => {}
+            Err(err) => return Err(err),
+        }
+
+        // Create a fulfillment order request for the requested item and quantity
+        let destination = &request.destination;
+        let body = json!({
+            "sellerFulfillmentOrderId": request.order_id,
+            "displayableOrderId": request.order_id,
+            "displayableOrderDate": request.order_date_time,
+            "displayableOrderComment": request.comment,
+            "shippingSpeedCategory": "Standard",
+            "destinationAddress": {
+                "name": destination.name,
+                "addressLine1": destination.line1,
+                "addressLine2": destination.line2,
+                "city": destination.city,
+                "stateOrRegion": destination.state_or_region,
+                "postalCode": destination.postal_code,
+                "countryCode": destination.country_code,
+            },
+            "items": [{
+                "sellerSku": request.item,
+                "sellerFulfillmentOrderItemId": request.order_id,
+                "quantity": request.grams,
+            }],
+        });
+
+        // Send the request. A new order has no tracking number until it ships.
+        self.send(self.client.post(self.orders_url(None)).json(&body), &request.order_id, "creating")?;
+        Ok(FulfillmentStatus::Processing)
+    }
+
+    fn order_status(&self, order_id: &str) -> Result<FulfillmentStatus, FulfillmentError> {
+        let response = self.send(self.client.get(self.orders_url(Some(order_id))), order_id, "fetching")?;
+        let payload = &response["payload"];
+
+        // Map the service's status names onto ours
+        let status = payload["fulfillmentOrder"]["fulfillmentOrderStatus"].as_str().unwrap_or_default();
+        match status {
+            "Cancelled" => Ok(FulfillmentStatus::Cancelled),
+            "Unfulfillable" | "Invalid" => Ok(FulfillmentStatus::Unfulfillable(status.to_string())),
+            _ => {
+                let package = &payload["fulfillmentShipments"][0]["fulfillmentShipmentPackage"][0];
+                Ok(shipment_status(
+                    package["trackingNumber"].as_str().map(str::to_string),
+                    package["carrierCode"].as_str().map(str::to_string),
+                ))
+            }
+        }
+    }
+
+    fn cancel_order(&self, order_id: &str) -> Result<(), FulfillmentError> {
+        let url = format!("{}/cancel", self.orders_url(Some(order_id)));
+        self.send(self.client.put(url), order_id, "cancelling")?;
+        Ok(())
+    }
+}
+
+// Return Shipped if the provider has given a tracking number, and Processing otherwise
+fn shipment_status(tracking_number: Option<String>, carrier_code: Option<String>) -> FulfillmentStatus {
+    match tracking_number {
+        Some(tracking_number) => FulfillmentStatus::Shipped {
+            tracking_number,
+            carrier_code: carrier_code.unwrap_or_default(),
+        },
+        None => FulfillmentStatus::Processing,
+    }
+}
+
+// FileFulfillmentProvider keeps orders in a local JSON file instead of sending them anywhere. It
+// stands in for a real warehouse during development and tests; orders stay Processing until
+// they are marked as shipped or unfulfillable by hand.
+pub struct FileFulfillmentProvider {
+    // The file the orders are stored in
+    path: PathBuf,
+}
+
+// StoredOrder is one order as written to the local order file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredOrder {
+    request: FulfillmentRequest,
+    status: FulfillmentStatus,
+}
+
+impl FileFulfillmentProvider {
+    // Create a provider that stores its orders in the file at `path`. The file is created on
+    // the first order.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    // Record that an order has shipped under the given tracking number
+    pub fn mark_shipped(&self, order_id: &str, tracking_number: &str, carrier_code: &str) -> Result<(), FulfillmentError> {
+        self.set_status(order_id, FulfillmentStatus::Shipped {
+            tracking_number: tracking_number.to_string(),
+            carrier_code: carrier_code.to_string(),
+        })
+    }
+
+    // Record that an order cannot be shipped
+    pub fn mark_unfulfillable(&self, order_id: &str, reason: &str) -> Result<(), FulfillmentError> {
+        self.set_status(order_id, FulfillmentStatus::Unfulfillable(reason.to_string()))
+    }
+
+    // Replace the status of an existing order
+    fn set_status(&self, order_id: &str, status: FulfillmentStatus) -> Result<(), FulfillmentError> {
+        let mut orders = self.load()?;
+        let order = orders
+            .get_mut(order_id)
+            .ok_or_else(|| FulfillmentError::UnknownOrder(order_id.to_string()))?;
+        order.status = status;
+        self.save(&orders)
+    }
+
+    // Read every order from the file. A missing file holds no orders.
+    fn load(&self) -> Result<BTreeMap<String, StoredOrder>, FulfillmentError> {
+        if !self.path.exists() {
+            return Ok(BTreeMap::new());
+        }
+        let contents = fs::read_to_string(&self.path).map_err(|err| FulfillmentError::Storage(err.to_string()))?;
+        serde_json::from_str(&contents).map_err(|err| FulfillmentError::Storage(err.to_string()))
+    }
+
+    // Write every order back to the file
+    fn save(&self, orders: &BTreeMap<String, StoredOrder>) -> Result<(), FulfillmentError> {
+        let contents = serde_json::to_string_pretty(orders).map_err(|err| FulfillmentError::Storage(err.to_string()))?;
+        fs::write(&self.path, contents).map_err(|err| FulfillmentError::Storage(err.to_string()))
+    }
+}
+
+impl FulfillmentProvider for FileFulfillmentProvider {
+    fn create_order(&self, request: &FulfillmentRequest) -> Result<FulfillmentStatus, FulfillmentError> {
+        let mut orders = self.load()?;
+        // An order that already exists is not placed a second time
+        if let Some(existing) = orders.get(&request.order_id) {
+            return Ok(existing.status.clone());
+        }
+
+        orders.insert(request.order_id.clone(), StoredOrder {
+            request: request.clone(),
+            status: FulfillmentStatus::Processing,
+        });
+        self.save(&orders)?;
+        Ok(FulfillmentStatus::Processing)
+    }
+
+    fn order_status(&self, order_id: &str) -> Result<FulfillmentStatus, FulfillmentError> {
+        self.load()?
+            .get(order_id)
+            .map(|order| order.status.clone())
+            .ok_or_else(|| FulfillmentError::UnknownOrder(order_id.to_string()))
+    }
+
+    fn cancel_order(&self, order_id: &str) -> Result<(), FulfillmentError> {
+        // Orders that have already shipped cannot be called back
+        if let FulfillmentStatus::Shipped { .. } = self.order_status(order_id)? {
+            return Err(FulfillmentError::AlreadyShipped(order_id.to_string()));
+        }
+        self.set_status(order_id, FulfillmentStatus::Cancelled)
+    }
+}
@@ -0,0 +1,568 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// The longest a single address line may be, as USPS address lines are limited to 40 characters
+const MAX_LINE_LENGTH: usize = 40;
+
+// AddressField names the parts of a mailing address, so errors can be shown next to the right
+// form field
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressField {
+    Name,
+    Line1,
+    Line2,
+    City,
+    State,
+    Zip,
+    Country,
+}
+
+impl AddressField {
+    // Return the name of the form field this part of the address is entered in
+    pub fn form_name(&self) -> &'static str {
+        match self {
+            AddressField::Name => "name",
+            AddressField::Line1 => "line1",
+            AddressField::Line2 => "line2",
+            AddressField::City => "city",
+            AddressField::State => "state",
+            AddressField::Zip => "zip",
+            AddressField::Country => "country",
+        }
+    }
+}
+
+// AddressProblem describes what is wrong with one part of an address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddressProblem {
+    // The field is required but was left empty
+    Missing,
+    // The field is longer than USPS allows
+    TooLong { max: usize },
+    // The field contains characters that do not belong in it
+    InvalidCharacters,
+    // The state is not a US state, territory or military state code
+    UnknownState,
+    // The ZIP code is not five digits, optionally followed by four more
+    InvalidZip,
+    // The ZIP code belongs to another state
+    ZipStateMismatch { state: String, zip: String },
+    // Only US addresses are accepted
+    UnsupportedCountry,
+    // The address is a PO box, which the caller does not accept
+    PoBox,
+}
+
+// AddressFieldError is one problem with one part of an address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressFieldError {
+    pub field: AddressField,
+    pub problem: AddressProblem,
+}
+
+impl fmt::Display for AddressFieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.problem {
+            AddressProblem::Missing => write!(f, "this field is required"),
+            AddressProblem::TooLong { max } => write!(f, "must be at most {} characters", max),
+            AddressProblem::InvalidCharacters => write!(f, "contains characters that are not allowed"),
+            AddressProblem::UnknownState => write!(f, "not a US state or territory"),
+            AddressProblem::InvalidZip => write!(f, "must be a 5-digit ZIP code or ZIP+4, e.g. 12345-6789"),
+            AddressProblem::ZipStateMismatch { state, zip } => write!(f, "ZIP code {} is not in {}", zip, state),
+            AddressProblem::UnsupportedCountry => write!(f, "only addresses in the United States are accepted"),
+            AddressProblem::PoBox => write!(f, "saffron is shipped by courier, which cannot deliver to PO boxes"),
+        }
+    }
+}
+
+// AddressErrors is every problem found with an address, in field order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressErrors(pub Vec<AddressFieldError>);
+
+impl AddressErrors {
+    // Return the message for `field`, if anything is wrong with it
+    pub fn message_for(&self, field: AddressField) -> Option<String> {
+        self.0.iter().find(|error| error.field == field).map(|error| error.to_string())
+    }
+}
+
+impl fmt::Display for AddressErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let messages: Vec<String> = self
+            .0
+            .iter()
+            .map(|error| format!("{}: {}", error.field.form_name(), error))
+            .collect();
+        write!(f, "{}", messages.join("; "))
+    }
+}
+
+impl std::error::Error for AddressErrors {}
+
+// MailingAddressFields is an address as typed in by the user, before it is checked
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MailingAddressFields {
+    pub name: String,
+    pub line1: String,
+    pub line2: String,
+    pub city: String,
+    pub state: String,
+    pub zip: String,
+    pub country: String,
+}
+
+// MailingAddress is a checked US mailing address, normalised the way USPS prints addresses:
+// upper case, single spaces, standard abbreviations and a two-letter state code
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MailingAddress {
+    // The recipient's name
+    pub name: String,
+    // The delivery address line, e.g. "123 N MAIN ST"
+    pub line1: String,
+    // The secondary address line, e.g. "APT 4B", if there is one
+    pub line2: Option<String>,
+    pub city: String,
+    // The two-letter state code
+    pub state: String,
+    // The five-digit ZIP code
+    pub zip5: String,
+    // The four-digit ZIP+4 add-on, if it was given
+    pub zip4: Option<String>,
+    // The two-letter country code, always "US"
+    pub country: String,
+    // True if the delivery line is a PO box
+    pub po_box: bool,
+}
+
+impl MailingAddress {
+    // Check and normalise an address typed in by the user. Every problem found is reported,
+    // not just the first, so the form can mark each bad field at once.
+    pub fn parse(fields: &MailingAddressFields) -> Result<Self, AddressErrors> {
+        let mut errors = Vec::new();
+        let mut fail = |field, problem| errors.push(AddressFieldError { field, problem });
+
+        let name = normalise_text(&fields.name);
+        check_line(AddressField::Name, &name, true, &mut fail);
+
+        let line1 = normalise_street(&fields.line1);
+        check_line(AddressField::Line1, &line1, true, &mut fail);
+        let line2 = normalise_street(&fields.line2);
+        check_line(AddressField::Line2, &line2, false, &mut fail);
+
+        let city = normalise_text(&fields.city);
+        check_line(AddressField::City, &city, true, &mut fail);
+        if !city.chars().all(|c| c.is_ascii_alphabetic() || matches!(c, ' ' | '-' | '\'' | '.')) {
+            fail(AddressField::City, AddressProblem::InvalidCharacters);
+        }
+
+        let state = normalise_text(&fields.state);
+        let state_code = if state.is_empty() {
+            fail(AddressField::State, AddressProblem::Missing);
+            None
+        } else {
+            let code = state_code(&state);
+            if code.is_none() {
+                fail(AddressField::State, AddressProblem::UnknownState);
+            }
+            code
+        };
+
+        let zip = parse_zip(&fields.zip);
+        match &zip {
+            Err(problem) => fail(AddressField::Zip, problem.clone()),
+            // The state and ZIP code can only be compared once both are known to be valid
+            Ok((zip5, _)) => {
+                if let Some(code) = state_code {
+                    if !zip_in_state(zip5, code) {
+                        fail(AddressField::Zip, AddressProblem::ZipStateMismatch {
+                            state: code.to_string(),
+                            zip: zip5.clone(),
+                        });
+                    }
+                }
+            }
+        }
+
+        // An empty country means the United States, as the form only takes US addresses
+        let country = normalise_text(&fields.country);
+        if !matches!(country.as_str(), "" | "US" | "USA" | "U S" | "U S A" | "UNITED STATES" | "UNITED STATES OF AMERICA") {
+            fail(AddressField::Country, AddressProblem::UnsupportedCountry);
+        }
+
+        if !errors.is_empty() {
+            return Err(AddressErrors(errors));
+        }
+        let (zip5, zip4) = zip.unwrap_or_default();
+        let po_box = is_po_box(&line1);
+        Ok(Self {
+            name,
+            line1,
+            line2: if line2.is_empty() { None } else { Some(line2) },
+            city,
+            state: state_code.unwrap_or_default().to_string(),
+            zip5,
+            zip4,
+            country: "US".to_string(),
+            po_box,
+        })
+    }
+
+    // Refuse PO boxes, for shipments a courier has to deliver
+    pub fn require_street_address(&self) -> Result<(), AddressErrors> {
+        if self.po_box {
+            return Err(AddressErrors(vec![AddressFieldError {
+                field: AddressField::Line1,
+                problem: AddressProblem::PoBox,
+            }]));
+        }
+        Ok(())
+    }
+
+    // Return the ZIP code as printed: ZIP+4 if the add-on is known, otherwise five digits
+    pub fn zip(&self) -> String {
+        match &self.zip4 {
+            Some(zip4) => format!("{}-{}", self.zip5, zip4),
+            None => self.zip5.clone(),
+        }
+    }
+}
+
+impl fmt::Display for MailingAddress {
+    // Format the address as it would be printed on a label, one line per row
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.name)?;
+        writeln!(f, "{}", self.line1)?;
+        if let Some(line2) = &self.line2 {
+            writeln!(f, "{}", line2)?;
+        }
+        write!(f, "{} {} {}", self.city, self.state, self.zip())
+    }
+}
+
+// Report a required line that is empty, or any line that is too long or has control characters
+fn check_line(field: AddressField, value: &str, required: bool, fail: &mut impl FnMut(AddressField, AddressProblem)) {
+    if value.is_empty() {
+        if required {
+            fail(field, AddressProblem::Missing);
+        }
+        return;
+    }
+    if value.chars().count() > MAX_LINE_LENGTH {
+        fail(field, AddressProblem::TooLong { max: MAX_LINE_LENGTH });
+    }
+    if value.chars().any(|c| c.is_control() || matches!(c, '<' | '>' | '"' | ';' | '\\')) {
+        fail(field, AddressProblem::InvalidCharacters);
+    }
+}
+
+// Upper-case the text, turn commas into spaces and collapse runs of whitespace
+fn normalise_text(value: &str) -> String {
+    value
+        .replace(',', " ")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_uppercase()
+}
+
+// Normalise a street line and replace spelled-out words with their USPS abbreviations. Periods
+// are dropped, as USPS does, so "P.O. Box" and "St." become "PO BOX" and "ST".
+fn normalise_street(value: &str) -> String {
+    let text = normalise_text(&value.replace('.', ""));
+    let words: Vec<&str> = text.split(' ').filter(|word| !word.is_empty()).map(abbreviate).collect();
+    let line = words.join(" ");
+    // Spell every PO box the same way. A bare "BOX" only counts when a number follows, so
+    // streets such as "BOX ELDER RD" are left alone.
+    for prefix in ["POST OFFICE BOX ", "P O BOX ", "POB ", "BOX "] {
+        if let Some(number) = line.strip_prefix(prefix) {
+            if prefix != "BOX " || number.starts_with(|c: char| c.is_ascii_digit()) {
+                return format!("PO BOX {}", number);
+            }
+        }
+    }
+    line
+}
+
+// Return the USPS abbreviation of a street suffix, directional or secondary unit word
+fn abbreviate(word: &str) -> &str {
+    match word {
+        "STREET" => "ST",
+        "AVENUE" => "AVE",
+        "ROAD" => "RD",
+        "DRIVE" => "DR",
+        "BOULEVARD" => "BLVD",
+        "LANE" => "LN",
+        "COURT" => "CT",
+        "PLACE" => "PL",
+        "TERRACE" => "TER",
+        "PARKWAY" => "PKWY",
+        "HIGHWAY" => "HWY",
+        "CIRCLE" => "CIR",
+        "SQUARE" => "SQ",
+        "TRAIL" => "TRL",
+        "NORTH" => "N",
+        "SOUTH" => "S",
+        "EAST" => "E",
+        "WEST" => "W",
+        "NORTHEAST" => "NE",
+        "NORTHWEST" => "NW",
+        "SOUTHEAST" => "SE",
+        "SOUTHWEST" => "SW",
+        "APARTMENT" => "APT",
+        "SUITE" => "STE",
+        "BUILDING" => "BLDG",
+        "FLOOR" => "FL",
+        "ROOM" => "RM",
+        "DEPARTMENT" => "DEPT",
+        _ => word,
+    }
+}
+
+// Return true if a normalised delivery line is a PO box
+fn is_po_box(line: &str) -> bool {
+    line.starts_with("PO BOX ") || line == "PO BOX"
+}
+
+// Parse a ZIP code as five digits, optionally followed by four more with or without a hyphen
+fn parse_zip(value: &str) -> Result<(String, Option<String>), AddressProblem> {
+    let digits: String = value.trim().chars().filter(|c| *c != '-' && *c != ' ').collect();
+    if digits.is_empty() {
+        return Err(AddressProblem::Missing);
+    }
+    if !digits.chars().all(|c| c.is_ascii_digit()) {
+        return Err(AddressProblem::InvalidZip);
+    }
+    match digits.len() {
+        5 => Ok((digits, None)),
+        9 => Ok((digits[..5].to_string(), Some(digits[5..].to_string()))),
+        _ => Err(AddressProblem::InvalidZip),
+    }
+}
+
+// A state's two-letter code, full name and ZIP code prefix ranges
+type StateEntry = (&'static str, &'static str, &'static [(u16, u16)]);
+
+// The two-letter code, full name and ZIP code prefix ranges (first three digits, inclusive) of
+// every US state, the District of Columbia, the territories and the military "states"
+const STATES: &[StateEntry] = &[
+    ("AL", "ALABAMA", &[(350, 369)]),
+    ("AK", "ALASKA", &[(995, 999)]),
+    ("AZ", "ARIZONA", &[(850, 865)]),
+    ("AR", "ARKANSAS", &[(716, 729)]),
+    ("CA", "CALIFORNIA", &[(900, 961)]),
+    ("CO", "COLORADO", &[(800, 816)]),
+    ("CT", "CONNECTICUT", &[(60, 69)]),
+    ("DE", "DELAWARE", &[(197, 199)]),
+    ("DC", "DISTRICT OF COLUMBIA", &[(200, 205), (569, 569)]),
+    ("FL", "FLORIDA", &[(320, 349)]),
+    ("GA", "GEORGIA", &[(300, 319), (398, 399)]),
+    ("HI", "HAWAII", &[(967, 968)]),
+    ("ID", "IDAHO", &[(832, 838)]),
+    ("IL", "ILLINOIS", &[(600, 629)]),
+    ("IN", "INDIANA", &[(460, 479)]),
+    ("IA", "IOWA", &[(500, 528)]),
+    ("KS", "KANSAS", &[(660, 679)]),
+    ("KY", "KENTUCKY", &[(400, 427)]),
+    ("LA", "LOUISIANA", &[(700, 714)]),
+    ("ME", "MAINE", &[(39, 49)]),
+    ("MD", "MARYLAND", &[(206, 219)]),
+    ("MA", "MASSACHUSETTS", &[(10, 27), (55, 55)]),
+    ("MI", "MICHIGAN", &[(480, 499)]),
+    ("MN", "MINNESOTA", &[(550, 567)]),
+    ("MS", "MISSISSIPPI", &[(386, 397)]),
+    ("MO", "MISSOURI", &[(630, 658)]),
+    ("MT", "MONTANA", &[(590, 599)]),
+    ("NE", "NEBRASKA", &[(680, 693)]),
+    ("NV", "NEVADA", &[(889, 898)]),
+    ("NH", "NEW HAMPSHIRE", &[(30, 38)]),
+    ("NJ", "NEW JERSEY", &[(70, 89)]),
+    ("NM", "NEW MEXICO", &[(870, 884)]),
+    ("NY", "NEW YORK", &[(5, 5), (100, 149)]),
+    ("NC", "NORTH CAROLINA", &[(270, 289)]),
+    ("ND", "NORTH DAKOTA", &[(580, 588)]),
+    ("OH", "OHIO", &[(430, 459)]),
+    ("OK", "OKLAHOMA", &[(730, 749)]),
+    ("OR", "OREGON", &[(970, 979)]),
+    ("PA", "PENNSYLVANIA", &[(150, 196)]),
+    ("RI", "RHODE ISLAND", &[(28, 29)]),
+    ("SC", "SOUTH CAROLINA", &[(290, 299)]),
+    ("SD", "SOUTH DAKOTA", &[(570, 577)]),
+    ("TN", "TENNESSEE", &[(370, 385)]),
+    ("TX", "TEXAS", &[(750, 799), (885, 885)]),
+    ("UT", "UTAH", &[(840, 847)]),
+    ("VT", "VERMONT", &[(50, 54), (56, 59)]),
+    ("VA", "VIRGINIA", &[(201, 201), (220, 246)]),
+    ("WA", "WASHINGTON", &[(980, 994)]),
+    ("WV", "WEST VIRGINIA", &[(247, 268)]),
+    ("WI", "WISCONSIN", &[(530, 549)]),
+    ("WY", "WYOMING", &[(820, 831)]),
+    ("PR", "PUERTO RICO", &[(6, 7), (9, 9)]),
+    ("VI", "VIRGIN ISLANDS", &[(8, 8)]),
+    ("GU", "GUAM", &[(969, 969)]),
+    ("MP", "NORTHERN MARIANA ISLANDS", &[(969, 969)]),
+    ("AS", "AMERICAN SAMOA", &[(967, 967)]),
+    ("AA", "ARMED FORCES AMERICAS", &[(340, 340)]),
+    ("AE", "ARMED FORCES EUROPE", &[(90, 98)]),
+    ("AP", "ARMED FORCES PACIFIC", &[(962, 966)]),
+];
+
+// Return the two-letter code of a state given by code or full name
+fn state_code(state: &str) -> Option<&'static str> {
+    STATES
+        .iter()
+        .find(|(code, name, _)| *code == state || *name == state)
+        .map(|(code, _, _)| *code)
+}
+
+// Return true if the five-digit ZIP code falls in one of the state's prefix ranges
+fn zip_in_state(zip5: &str, state: &str) -> bool {
+    let prefix: u16 = match zip5[..3].parse() {
+        Ok(prefix) => prefix,
+        Err(_) => return false,
+    };
+    STATES
+        .iter()
+        .filter(|(code, _, _)| *code == state)
+        .flat_map(|(_, _, ranges)| ranges.iter())
+        .any(|(low, high)| (*low..=*high).contains(&prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Return a valid address in Springfield, Illinois, as a user might type it
+    fn fields() -> MailingAddressFields {
+        MailingAddressFields {
+            name: "Ada Lovelace".to_string(),
+            line1: "123 north main street".to_string(),
+            line2: String::new(),
+            city: "Springfield".to_string(),
+            state: "IL".to_string(),
+            zip: "62701".to_string(),
+            country: String::new(),
+        }
+    }
+
+    // Return the field and problem of every error found in `fields`
+    fn problems(fields: &MailingAddressFields) -> Vec<(AddressField, AddressProblem)> {
+        MailingAddress::parse(fields)
+            .unwrap_err()
+            .0
+            .into_iter()
+            .map(|error| (error.field, error.problem))
+            .collect()
+    }
+
+    #[test]
+    fn addresses_are_normalised_the_way_usps_prints_them() {
+        let address = MailingAddress::parse(&MailingAddressFields {
+            name: "  ada   lovelace ".to_string(),
+            line1: "123 North Main Street.".to_string(),
+            line2: "apartment 4b".to_string(),
+            city: "springfield".to_string(),
+            state: "illinois".to_string(),
+            zip: " 62701-1234 ".to_string(),
+            country: "usa".to_string(),
+        })
+        .unwrap();
+
+        assert_eq!(address.name, "ADA LOVELACE");
+        assert_eq!(address.line1, "123 N MAIN ST");
+        assert_eq!(address.line2.as_deref(), Some("APT 4B"));
+        assert_eq!(address.city, "SPRINGFIELD");
+        assert_eq!(address.state, "IL");
+        assert_eq!((address.zip5.as_str(), address.zip4.as_deref()), ("62701", Some("1234")));
+        assert_eq!(address.zip(), "62701-1234");
+        assert_eq!(address.country, "US");
+        assert!(!address.po_box);
+        assert_eq!(address.to_string(), "ADA LOVELACE\n123 N MAIN ST\nAPT 4B\nSPRINGFIELD IL 62701-1234");
+
+        // Nine digits without the hyphen are ZIP+4 too, and five digits leave the add-on unknown
+        let address = MailingAddress::parse(&MailingAddressFields { zip: "627011234".to_string(), ..fields() }).unwrap();
+        assert_eq!(address.zip(), "62701-1234");
+        let address = MailingAddress::parse(&fields()).unwrap();
+        assert_eq!((address.zip(), address.zip4), ("62701".to_string(), None));
+    }
+
+    #[test]
+    fn a_zip_code_from_another_state_is_refused() {
+        let mismatched = MailingAddressFields { zip: "90210".to_string(), ..fields() };
+        assert_eq!(
+            problems(&mismatched),
+            vec![(AddressField::Zip, AddressProblem::ZipStateMismatch { state: "IL".to_string(), zip: "90210".to_string() })]
+        );
+
+        // Prefixes at either end of a state's ranges, and in its second range, are accepted
+        for (state, zip) in [("IL", "60001"), ("IL", "62999"), ("GA", "39901"), ("NY", "00501")] {
+            let fields = MailingAddressFields { state: state.to_string(), zip: zip.to_string(), ..fields() };
+            assert!(MailingAddress::parse(&fields).is_ok(), "{} {}", state, zip);
+        }
+    }
+
+    #[test]
+    fn every_spelling_of_a_po_box_is_recognised() {
+        for line1 in ["P.O. Box 42", "po box 42", "POB 42", "Post Office Box 42", "box 42"] {
+            let address = MailingAddress::parse(&MailingAddressFields { line1: line1.to_string(), ..fields() }).unwrap();
+            assert_eq!(address.line1, "PO BOX 42", "{}", line1);
+            assert!(address.po_box, "{}", line1);
+            assert_eq!(
+                address.require_street_address().unwrap_err().0,
+                vec![AddressFieldError { field: AddressField::Line1, problem: AddressProblem::PoBox }]
+            );
+        }
+    }
+
+    #[test]
+    fn streets_named_box_are_not_po_boxes() {
+        for line1 in ["Box Elder Rd", "12 Box Elder Road", "12 Pobox Lane"] {
+            let address = MailingAddress::parse(&MailingAddressFields { line1: line1.to_string(), ..fields() }).unwrap();
+            assert!(!address.po_box, "{}", line1);
+            assert!(address.require_street_address().is_ok());
+        }
+        let address = MailingAddress::parse(&MailingAddressFields { line1: "Box Elder Rd".to_string(), ..fields() }).unwrap();
+        assert_eq!(address.line1, "BOX ELDER RD");
+    }
+
+    #[test]
+    fn each_bad_field_is_reported_against_that_field() {
+        let bad = MailingAddressFields {
+            name: String::new(),
+            line1: "1 ".repeat(25),
+            line2: "<b>4B</b>".to_string(),
+            city: "Springfield 2".to_string(),
+            state: "XX".to_string(),
+            zip: "6270".to_string(),
+            country: "Canada".to_string(),
+        };
+        assert_eq!(
+            problems(&bad),
+            vec![
+                (AddressField::Name, AddressProblem::Missing),
+                (AddressField::Line1, AddressProblem::TooLong { max: MAX_LINE_LENGTH }),
+                (AddressField::Line2, AddressProblem::InvalidCharacters),
+                (AddressField::City, AddressProblem::InvalidCharacters),
+                (AddressField::State, AddressProblem::UnknownState),
+                (AddressField::Zip, AddressProblem::InvalidZip),
+                (AddressField::Country, AddressProblem::UnsupportedCountry),
+            ]
+        );
+
+        // Empty required fields are reported as missing, while an empty second line is fine
+        let empty = MailingAddressFields { line1: String::new(), city: String::new(), state: String::new(), zip: String::new(), ..fields() };
+        assert_eq!(
+            problems(&empty),
+            vec![
+                (AddressField::Line1, AddressProblem::Missing),
+                (AddressField::City, AddressProblem::Missing),
+                (AddressField::State, AddressProblem::Missing),
+                (AddressField::Zip, AddressProblem::Missing),
+            ]
+        );
+
+        let errors = MailingAddress::parse(&MailingAddressFields { zip: "62a01".to_string(), ..fields() }).unwrap_err();
+        assert_eq!(errors.message_for(AddressField::Zip).as_deref(), Some("must be a 5-digit ZIP code or ZIP+4, e.g. 12345-6789"));
+        assert_eq!(errors.message_for(AddressField::City), None);
+    }
+}
@@ -0,0 +1,214 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use web3::types::{Address, H256, U256};
+
+use crate::saffron_vendor::{CallContext, Saffron, SaffronError, SaffronEvent};
+
+// MockOracleError describes why the mock oracle could not be set up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MockOracleError {
+    // The script file could not be read
+    Io(String),
+    // The script file is not a valid list of responses
+    InvalidScript(String),
+}
+
+impl fmt::Display for MockOracleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MockOracleError::Io(message) => write!(f, "could not read the oracle script: {}", message),
+            MockOracleError::InvalidScript(message) => write!(f, "invalid oracle script: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for MockOracleError {}
+
+// MockResponse is how the mock oracle answers one price request. In a script file each response
+// is a JSON object tagged with its kind, e.g.
+//
+//   [
+//     { "kind": "respond", "payload": "2500000000000000" },
+//     { "kind": "respond", "payload": "2600000000000000", "delay_seconds": 120 },
+//     { "kind": "respond", "payload": "not a number" },
+//     { "kind": "fail", "reason": "job run errored" },
+//     { "kind": "silent" }
+//   ]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum MockResponse {
+    // Answer with `payload`, the job's raw result: the price of one gram in wei as a decimal
+    // string. A payload that does not decode is reported as malformed and never reaches the
+    // ledger, as a real node would fail the job.
+    Respond {
+        payload: String,
+        // How long after the request the answer is sent
+        #[serde(default)]
+        delay_seconds: u64,
+    },
+    // Fail the job, leaving the request pending on the ledger
+    Fail { reason: String },
+    // Never answer, as if the node were down
+    Silent,
+}
+
+impl MockResponse {
+    // Return a response that answers at once with `wei_per_gram`
+    pub fn price(wei_per_gram: U256) -> Self {
+        MockResponse::Respond { payload: wei_per_gram.to_string(), delay_seconds: 0 }
+    }
+}
+
+// MockOutcome is what happened to one price request the mock oracle saw
+#[derive(Debug, Clone, PartialEq)]
+pub enum MockOutcome {
+    // The request was answered and the ledger accepted the price
+    Fulfilled { request_id: H256, wei_per_gram: U256, round_id: u64 },
+    // The request was answered but the ledger refused the answer
+    Rejected { request_id: H256, error: SaffronError },
+    // The scripted payload did not decode, so no answer was sent
+    Malformed { request_id: H256, payload: String },
+    // The scripted response failed the job
+    Failed { request_id: H256, reason: String },
+    // The script chose not to answer
+    Ignored { request_id: H256 },
+}
+
+// ScheduledAnswer is a request the mock oracle has picked up and will act on once it is due
+#[derive(Debug, Clone)]
+struct ScheduledAnswer {
+    request_id: H256,
+    // The timestamp from which the response is acted on
+    due_at: u64,
+    response: MockResponse,
+}
+
+// MockOracle stands in for a Chainlink node during development and tests. It watches the
+// ledger's log for PriceRequested events sent to its address and answers each with the next
+// scripted response. Once the script runs out the default response is used, if there is one;
+// otherwise further requests are ignored.
+pub struct MockOracle {
+    // The oracle address requests are sent to and answers come from
+    address: Address,
+    // The responses still to be used, in order
+    script: VecDeque<MockResponse>,
+    // The response used once the script runs out
+    default_response: Option<MockResponse>,
+    // The position in the ledger's log up to which events have been read
+    next_log_index: usize,
+    // Requests picked up but not yet acted on
+    scheduled: Vec<ScheduledAnswer>,
+    // The round id of the next answer
+    next_round_id: u64,
+    // Everything the mock has done, oldest first
+    outcomes: Vec<MockOutcome>,
+}
+
+impl MockOracle {
+    // Create a mock oracle at `address` that answers requests with `script`, in order
+    pub fn new(address: Address, script: Vec<MockResponse>) -> Self {
+        Self {
+            address,
+            script: script.into(),
+            default_response: None,
+            next_log_index: 0,
+            scheduled: Vec::new(),
+            next_round_id: 1,
+            outcomes: Vec::new(),
+        }
+    }
+
+    // Create a mock oracle at `address` whose script is read from the JSON file at `path`
+    pub fn from_file(address: Address, path: impl AsRef<Path>) -> Result<Self, MockOracleError> {
+        let contents = fs::read_to_string(path).map_err(|err| MockOracleError::Io(err.to_string()))?;
+        let script = serde_json::from_str(&contents).map_err(|err| MockOracleError::InvalidScript(err.to_string()))?;
+        Ok(Self::new(address, script))
+    }
+
+    // Return the address the mock oracle answers from
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    // Add a response to the end of the script
+    pub fn push_response(&mut self, response: MockResponse) {
+        self.script.push_back(response);
+    }
+
+    // Set the response used once the script runs out
+    pub fn set_default_response(&mut self, response: Option<MockResponse>) {
+        self.default_response = response;
+    }
+
+    // Return the number of requests picked up but not acted on yet, e.g. because their answer is
+    // delayed
+    pub fn scheduled_count(&self) -> usize {
+        self.scheduled.len()
+    }
+
+    // Return everything the mock has done, oldest first
+    pub fn outcomes(&self) -> &[MockOutcome] {
+        &self.outcomes
+    }
+
+    // Read the ledger's new events, schedule a response for each price request sent to this
+    // oracle, then act on every response due at the caller's timestamp. Answers are sent in
+    // `ctx.block_number` at `ctx.timestamp`; the sender in `ctx` is ignored. Returns what
+    // happened during this poll.
+    pub fn poll(&mut self, saffron: &mut Saffron, ctx: &CallContext) -> Vec<MockOutcome> {
+        // Pick up the price requests logged since the last poll
+        let log = saffron.event_log();
+        for logged in &log[self.next_log_index.min(log.len())..] {
+            if let SaffronEvent::PriceRequested { request_id, oracle, .. } = logged.event {
+                if oracle != self.address {
+                    continue;
+                }
+                let response = match self.script.pop_front().or_else(|| self.default_response.clone()) {
+                    Some(response) => response,
+                    None => MockResponse::Silent,
+                };
+                let delay = match &response {
+                    MockResponse::Respond { delay_seconds, .. } => *delay_seconds,
+                    _ => 0,
+                };
+                self.scheduled.push(ScheduledAnswer {
+                    request_id,
+                    due_at: logged.timestamp.saturating_add(delay),
+                    response,
+                });
+            }
+        }
+        self.next_log_index = log.len();
+
+        // Act on the responses that are due, in the order the requests were made
+        let (due, waiting): (Vec<_>, Vec<_>) = self.scheduled.drain(..).partition(|answer| answer.due_at <= ctx.timestamp);
+        self.scheduled = waiting;
+        let answer_ctx = CallContext::new(self.address, U256::zero(), ctx.block_number, ctx.timestamp);
+        let mut outcomes = Vec::with_capacity(due.len());
+        for answer in due {
+            let request_id = answer.request_id;
+            let outcome = match answer.response {
+                MockResponse::Respond { payload, .. } => match U256::from_dec_str(payload.trim()) {
+                    Ok(wei_per_gram) => {
+                        let round_id = self.next_round_id;
+                        self.next_round_id += 1;
+                        match saffron.fulfill_price(&answer_ctx, request_id, wei_per_gram, round_id) {
+                            Ok(()) => MockOutcome::Fulfilled { request_id, wei_per_gram, round_id },
+                            Err(error) => MockOutcome::Rejected { request_id, error },
+                        }
+                    }
+                    Err(_) => MockOutcome::Malformed { request_id, payload },
+                },
+                MockResponse::Fail { reason } => MockOutcome::Failed { request_id, reason },
+                MockResponse::Silent => MockOutcome::Ignored { request_id },
+            };
+            outcomes.push(outcome);
+        }
+        self.outcomes.extend(outcomes.iter().cloned());
+        outcomes
+    }
+}
@@ -0,0 +1,236 @@
+use std::fmt;
+use std::path::Path;
+
+// The key the last allocated sequence number is stored under
+const SEQUENCE_KEY: &[u8] = b"order-number/sequence";
+
+// The key recording whether the database hands out numbers with a check digit
+const CHECK_DIGIT_KEY: &[u8] = b"order-number/check-digit";
+
+// OrderNumberError describes why an order number could not be allocated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OrderNumberError {
+    // The database could not be opened, read or written
+    Backend(String),
+    // The stored sequence could not be decoded
+    Corrupt(String),
+    // The database was created with the check digit on and opened with it off, or the other way
+    // round. Switching would change the format of numbers already handed out.
+    CheckDigitMismatch { stored: bool, requested: bool },
+    // Every 8-digit order number has been used
+    Exhausted,
+}
+
+impl fmt::Display for OrderNumberError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OrderNumberError::Backend(message) => write!(f, "order number storage error: {}", message),
+            OrderNumberError::Corrupt(message) => write!(f, "corrupt order number sequence: {}", message),
+            OrderNumberError::CheckDigitMismatch { stored, requested } => write!(
+                f,
+                "order numbers were created with check digits {} but check digits were requested {}",
+                if *stored { "on" } else { "off" },
+                if *requested { "on" } else { "off" }
+            ),
+            OrderNumberError::Exhausted => write!(f, "every 8-digit order number has been used"),
+        }
+    }
+}
+
+impl std::error::Error for OrderNumberError {}
+
+// OrderNumberAllocator hands out ascending 8-digit order numbers that are never repeated, even
+// across restarts or between concurrent requests. The last number handed out is stored in a
+// sled database and advanced with an atomic update, which is flushed to disk before the number
+// is returned.
+//
+// With the check digit on, the first seven digits count up from 1000000 and the last digit is
+// a Luhn check digit over them, so `is_valid` catches any single mistyped digit and any swap of
+// two neighbouring digits except 09 and 90, which Luhn cannot tell apart. With it off, the
+// numbers simply count up from 10000000.
+pub struct OrderNumberAllocator {
+    // The database the sequence is kept in
+    db: sled::Db,
+    // True if order numbers end in a check digit
+    check_digit: bool,
+}
+
+impl OrderNumberAllocator {
+    // Open the sequence in the database at `path`, creating it if it does not exist. A database
+    // keeps the check digit setting it was created with.
+    pub fn open(path: impl AsRef<Path>, check_digit: bool) -> Result<Self, OrderNumberError> {
+        let db = sled::open(path).map_err(|err| OrderNumberError::Backend(err.to_string()))?;
+
+        // Record the setting on first use, and refuse to open with the other one afterwards
+        let flag = [check_digit as u8];
+        let stored = db
+            .compare_and_swap(CHECK_DIGIT_KEY, None as Option<&[u8]>, Some(&flag[..]))
+            .map_err(|err| OrderNumberError::Backend(err.to_string()))?;
+        if let Err(existing) = stored {
+            let stored = existing.current.is_some_and(|value| value.as_ref() == [1]);
+            if stored != check_digit {
+                return Err(OrderNumberError::CheckDigitMismatch { stored, requested: check_digit });
+            }
+        }
+        Ok(Self { db, check_digit })
+    }
+
+    // Return true if order numbers end in a check digit
+    pub fn has_check_digit(&self) -> bool {
+        self.check_digit
+    }
+
+    // Allocate the next order number
+    pub fn next(&self) -> Result<u64, OrderNumberError> {
+        let (first, last) = self.sequence_range();
+
+        // Advance the stored sequence atomically. The update may run more than once if another
+        // thread gets in first, so the outcome flags are reset on every run. A value that cannot
+        // be decoded or that has reached the end is left untouched.
+        let mut exhausted = false;
+        let mut corrupt = false;
+        let updated = self
+            .db
+            .update_and_fetch(SEQUENCE_KEY, |current| {
+                exhausted = false;
+                corrupt = false;
+                let next = match current {
+                    None => first,
+                    Some(bytes) => match decode_sequence(bytes) {
+                        Some(previous) if previous < last => previous + 1,
+                        Some(_) => {
+                            exhausted = true;
+                            return current.map(<[u8]>::to_vec);
+                        }
+                        None => {
+                            corrupt = true;
+                            return current.map(<[u8]>::to_vec);
+                        }
+                    },
+                };
+                Some(next.to_be_bytes().to_vec())
+            })
+            .map_err(|err| OrderNumberError::Backend(err.to_string()))?;
+        if exhausted {
+            return Err(OrderNumberError::Exhausted);
+        }
+        let sequence = updated
+            .as_deref()
+            .and_then(decode_sequence)
+            .filter(|_| !corrupt)
+            .ok_or_else(|| OrderNumberError::Corrupt("the stored sequence is not an 8-byte number".to_string()))?;
+
+        // Make sure the number is on disk before anyone sees it, so a crash cannot hand it out
+        // again after a restart
+        self.db.flush().map_err(|err| OrderNumberError::Backend(err.to_string()))?;
+        Ok(self.format(sequence))
+    }
+
+    // Return true if `order_number` has the 8-digit format this allocator hands out and, with the
+    // check digit on, its check digit is right
+    pub fn is_valid(&self, order_number: u64) -> bool {
+        if !(10_000_000..=99_999_999).contains(&order_number) {
+            return false;
+        }
+        !self.check_digit || luhn_check_digit(order_number / 10) == order_number % 10
+    }
+
+    // Return the first and last sequence numbers, which give 8-digit order numbers
+    fn sequence_range(&self) -> (u64, u64) {
+        if self.check_digit {
+            (1_000_000, 9_999_999)
+        } else {
+            (10_000_000, 99_999_999)
+        }
+    }
+
+    // Turn a sequence number into an order number
+    fn format(&self, sequence: u64) -> u64 {
+        if self.check_digit {
+            sequence * 10 + luhn_check_digit(sequence)
+        } else {
+            sequence
+        }
+    }
+}
+
+// Decode a stored sequence number
+fn decode_sequence(bytes: &[u8]) -> Option<u64> {
+    let bytes: [u8; 8] = bytes.try_into().ok()?;
+    Some(u64::from_be_bytes(bytes))
+}
+
+// Return the Luhn check digit of `number`: doubling every second digit from the right, the
+// digit that makes the sum of the digits a multiple of ten
+fn luhn_check_digit(number: u64) -> u64 {
+    let mut sum = 0;
+    let mut rest = number;
+    let mut double = true;
+    while rest > 0 {
+        let mut digit = rest % 10;
+        if double {
+            digit *= 2;
+            if digit > 9 {
+                digit -= 9;
+            }
+        }
+        sum += digit;
+        double = !double;
+        rest /= 10;
+    }
+    (10 - sum % 10) % 10
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::saffron_storage::test_support::reopen_when_released;
+
+    #[test]
+    fn numbers_count_up_and_carry_on_after_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let allocator = OrderNumberAllocator::open(dir.path(), true).unwrap();
+        // 1000000 followed by its Luhn check digit
+        assert_eq!(allocator.next(), Ok(10_000_008));
+        assert_eq!(allocator.next(), Ok(10_000_016));
+        drop(allocator);
+
+        let allocator = reopen_when_released(|| OrderNumberAllocator::open(dir.path(), true));
+        assert_eq!(allocator.next(), Ok(10_000_024));
+
+        let plain_dir = tempfile::tempdir().unwrap();
+        let plain = OrderNumberAllocator::open(plain_dir.path(), false).unwrap();
+        assert_eq!(plain.next(), Ok(10_000_000));
+        assert_eq!(plain.next(), Ok(10_000_001));
+    }
+
+    #[test]
+    fn check_digits_catch_typos_but_not_a_09_swap() {
+        let dir = tempfile::tempdir().unwrap();
+        let allocator = OrderNumberAllocator::open(dir.path(), true).unwrap();
+        assert!(allocator.is_valid(10_000_008));
+        // A mistyped digit and a swap of neighbouring digits
+        assert!(!allocator.is_valid(10_000_007));
+        assert!(!allocator.is_valid(10_000_080));
+        // Too short or too long
+        assert!(!allocator.is_valid(1_000_008));
+        assert!(!allocator.is_valid(100_000_008));
+
+        // 1000090 and 1000900 share a check digit, so swapping 09 for 90 goes unnoticed
+        let check = luhn_check_digit(1_000_090);
+        assert_eq!(luhn_check_digit(1_000_900), check);
+        assert!(allocator.is_valid(1_000_900 * 10 + check));
+    }
+
+    #[test]
+    fn the_check_digit_setting_cannot_change() {
+        let dir = tempfile::tempdir().unwrap();
+        drop(OrderNumberAllocator::open(dir.path(), true).unwrap());
+        // Retry until the error is about the setting rather than the lock sled still holds
+        let result = reopen_when_released(|| match OrderNumberAllocator::open(dir.path(), false) {
+            Err(OrderNumberError::CheckDigitMismatch { stored, requested }) => Ok((stored, requested)),
+            other => Err(other.err()),
+        });
+        assert_eq!(result, (true, false));
+    }
+}
@@ -0,0 +1,407 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+
+// The tree fulfilment requests wait in until they are delivered
+const PENDING_TREE: &str = "outbox/pending";
+
+// The tree fulfilment requests are moved to once they can no longer be retried
+const DEAD_LETTER_TREE: &str = "outbox/dead-letter";
+
+// OutboxError describes why the outbox could not be read or changed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutboxError {
+    // The database could not be opened, read or written
+    Backend(String),
+    // A stored entry could not be decoded
+    Corrupt(String),
+    // An order with this number is already in the outbox or the dead-letter list
+    Duplicate(u64),
+    // No entry has this order number
+    UnknownOrder(u64),
+    // The record an entry was to be queued together with is already taken
+    AlreadyTaken,
+}
+
+impl fmt::Display for OutboxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutboxError::Backend(message) => write!(f, "outbox storage error: {}", message),
+            OutboxError::Corrupt(message) => write!(f, "corrupt outbox entry: {}", message),
+            OutboxError::Duplicate(order_number) => write!(f, "order {} is already in the outbox", order_number),
+            OutboxError::UnknownOrder(order_number) => write!(f, "order {} is not in the outbox", order_number),
+            OutboxError::AlreadyTaken => write!(f, "the record guarding this order is already taken"),
+        }
+    }
+}
+
+impl std::error::Error for OutboxError {}
+
+// OutboxEntry is one fulfilment request waiting to be delivered, or given up on
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    pub order_number: u64,
+    // Sent as the Idempotency-Key header of every attempt, so the fulfilment service can tell a
+    // retry from a new order and never ships twice
+    pub idempotency_key: String,
+    // The JSON body of the request
+    pub body: String,
+    // The number of failed attempts so far
+    pub attempts: u32,
+    // The Unix timestamp the entry was queued at
+    pub created_at: u64,
+    // The Unix timestamp from which the next attempt may be made
+    pub next_attempt_at: u64,
+    // Why the last attempt failed, if one has
+    pub last_error: Option<String>,
+}
+
+// Delivery is the outcome of one attempt to send an entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Delivery {
+    // The fulfilment service accepted the order, or already had it
+    Delivered,
+    // The attempt failed in a way that may succeed later, e.g. a timeout or a 5xx response
+    Retry(String),
+    // The fulfilment service refused the order outright, so retrying cannot help
+    Rejected(String),
+}
+
+// RetryPolicy decides how often and for how long a failing entry is retried. The delay doubles
+// after every failure, from `base_delay` up to `max_delay` seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub base_delay: u64,
+    pub max_delay: u64,
+    // The number of failed attempts after which the entry goes to the dead-letter list
+    pub max_attempts: u32,
+}
+
+impl Default for RetryPolicy {
+    // Retry after 30 seconds, then a minute, two minutes and so on up to an hour between
+    // attempts, giving up after 12 failures (about six hours)
+    fn default() -> Self {
+        Self {
+            base_delay: 30,
+            max_delay: 60 * 60,
+            max_attempts: 12,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Return the delay before the next attempt after `attempts` failures
+    pub fn delay_after(&self, attempts: u32) -> u64 {
+        let exponent = attempts.saturating_sub(1).min(63);
+        self.base_delay.saturating_mul(1u64 << exponent).min(self.max_delay)
+    }
+}
+
+// Outbox keeps fulfilment requests in a sled database, keyed by order number, until the
+// fulfilment service has them. A request is written here before it is first sent, so one that
+// fails, times out or is cut off by a restart is retried instead of lost, and one that keeps
+// failing ends up on a dead-letter list for operators to look at and replay.
+pub struct Outbox {
+    pending: sled::Tree,
+    dead_letters: sled::Tree,
+    db: sled::Db,
+    policy: RetryPolicy,
+}
+
+impl Outbox {
+    // Open the outbox in the database at `path`, creating it if it does not exist
+    pub fn open(path: impl AsRef<Path>, policy: RetryPolicy) -> Result<Self, OutboxError> {
+        let db = sled::open(path).map_err(|err| OutboxError::Backend(err.to_string()))?;
+        Self::open_in(db, policy)
+    }
+
+    // Open the outbox in `db`, which other records that must change together with the outbox,
+    // such as redemption claims, can be kept in too
+    pub fn open_in(db: sled::Db, policy: RetryPolicy) -> Result<Self, OutboxError> {
+        let pending = db.open_tree(PENDING_TREE).map_err(|err| OutboxError::Backend(err.to_string()))?;
+        let dead_letters = db.open_tree(DEAD_LETTER_TREE).map_err(|err| OutboxError::Backend(err.to_string()))?;
+        Ok(Self { pending, dead_letters, db, policy })
+    }
+
+    // Return the idempotency key used for `order_number`. It depends only on the order number,
+    // so every attempt and every replay sends the same key.
+    pub fn idempotency_key(order_number: u64) -> String {
+        format!("saffron-order-{}", order_number)
+    }
+
+    // Queue `body` for delivery as order `order_number`, due at once
+    pub fn enqueue(&self, order_number: u64, body: String, now: u64) -> Result<OutboxEntry, OutboxError> {
+        let key = order_number.to_be_bytes();
+        if self.dead_letters.contains_key(key).map_err(|err| OutboxError::Backend(err.to_string()))? {
+            return Err(OutboxError::Duplicate(order_number));
+        }
+        let entry = OutboxEntry {
+            order_number,
+            idempotency_key: Self::idempotency_key(order_number),
+            body,
+            attempts: 0,
+            created_at: now,
+            next_attempt_at: now,
+            last_error: None,
+        };
+        let swapped = self
+            .pending
+            .compare_and_swap(key, None as Option<&[u8]>, Some(encode(&entry)?))
+            .map_err(|err| OutboxError::Backend(err.to_string()))?;
+        if swapped.is_err() {
+            return Err(OutboxError::Duplicate(order_number));
+        }
+        self.flush()?;
+        Ok(entry)
+    }
+
+    // Queue `body` for delivery as order `order_number`, due at once, and in the same transaction
+    // set `key` in `guard` to `value`. Nothing is written if `key` is already set, which fails
+    // with AlreadyTaken, so a record such as a redemption claim is taken exactly when its order
+    // is queued. `guard` must be a tree of the database the outbox was opened in.
+    pub fn enqueue_guarded(
+        &self,
+        guard: &sled::Tree,
+        key: &[u8],
+        value: &[u8],
+        order_number: u64,
+        body: String,
+        now: u64,
+    ) -> Result<OutboxEntry, OutboxError> {
+        use sled::transaction::{ConflictableTransactionError, TransactionError};
+        use sled::Transactional;
+
+        let order_key = order_number.to_be_bytes();
+        let entry = OutboxEntry {
+            order_number,
+            idempotency_key: Self::idempotency_key(order_number),
+            body,
+            attempts: 0,
+            created_at: now,
+            next_attempt_at: now,
+            last_error: None,
+        };
+        let encoded = encode(&entry)?;
+        (guard, &self.pending, &self.dead_letters)
+            .transaction(|(guard, pending, dead_letters)| {
+                if guard.get(key)?.is_some() {
+                    return Err(ConflictableTransactionError::Abort(OutboxError::AlreadyTaken));
+                }
+                if pending.get(&order_key[..])?.is_some() || dead_letters.get(&order_key[..])?.is_some() {
+                    return Err(ConflictableTransactionError::Abort(OutboxError::Duplicate(order_number)));
+                }
+                guard.insert(key, value)?;
+                pending.insert(&order_key[..], encoded.clone())?;
+                Ok(())
+            })
+            .map_err(|err| match err {
+                TransactionError::Abort(err) => err,
+                TransactionError::Storage(err) => OutboxError::Backend(err.to_string()),
+            })?;
+        self.flush()?;
+        Ok(entry)
+    }
+
+    // Return the pending entries due at `now`, oldest order first
+    pub fn due(&self, now: u64) -> Result<Vec<OutboxEntry>, OutboxError> {
+        Ok(decode_all(&self.pending)?
+            .into_iter()
+            .filter(|entry| entry.next_attempt_at <= now)
+            .collect())
+    }
+
+    // Return every pending entry, oldest order first
+    pub fn pending(&self) -> Result<Vec<OutboxEntry>, OutboxError> {
+        decode_all(&self.pending)
+    }
+
+    // Return every entry that has been given up on, oldest order first
+    pub fn dead_letters(&self) -> Result<Vec<OutboxEntry>, OutboxError> {
+        decode_all(&self.dead_letters)
+    }
+
+    // Record the outcome of an attempt to deliver `order_number` made at `now`. Delivered
+    // entries leave the outbox; rejected ones, and ones out of retries, go to the dead-letter
+    // list; the rest are rescheduled with the next backoff delay.
+    pub fn record(&self, order_number: u64, delivery: &Delivery, now: u64) -> Result<(), OutboxError> {
+        let key = order_number.to_be_bytes();
+        let mut entry = match self.pending.get(key).map_err(|err| OutboxError::Backend(err.to_string()))? {
+            Some(bytes) => decode(&bytes)?,
+            None => return Err(OutboxError::UnknownOrder(order_number)),
+        };
+        match delivery {
+            Delivery::Delivered => {
+                self.pending.remove(key).map_err(|err| OutboxError::Backend(err.to_string()))?;
+            }
+            Delivery::Retry(error) | Delivery::Rejected(error) => {
+                entry.attempts += 1;
+                entry.last_error = Some(error.clone());
+                let give_up = matches!(delivery, Delivery::Rejected(_)) || entry.attempts >= self.policy.max_attempts;
+                if give_up {
+                    self.move_entry(&self.pending, &self.dead_letters, &entry)?;
+                } else {
+                    entry.next_attempt_at = now.saturating_add(self.policy.delay_after(entry.attempts));
+                    self.pending
+                        .insert(key, encode(&entry)?)
+                        .map_err(|err| OutboxError::Backend(err.to_string()))?;
+                }
+            }
+        }
+        self.flush()
+    }
+
+    // Move a dead-letter entry back into the outbox with its attempts reset, due at `now`. The
+    // entry keeps its idempotency key, so if an earlier attempt did reach the fulfilment
+    // service the replay cannot ship a second time.
+    pub fn replay(&self, order_number: u64, now: u64) -> Result<OutboxEntry, OutboxError> {
+        let key = order_number.to_be_bytes();
+        let mut entry = match self.dead_letters.get(key).map_err(|err| OutboxError::Backend(err.to_string()))? {
+            Some(bytes) => decode(&bytes)?,
+            None => return Err(OutboxError::UnknownOrder(order_number)),
+        };
+        entry.attempts = 0;
+        entry.next_attempt_at = now;
+        self.move_entry(&self.dead_letters, &self.pending, &entry)?;
+        self.flush()?;
+        Ok(entry)
+    }
+
+    // Move an entry from one tree to the other in a single transaction, so it is never in both
+    // or neither
+    fn move_entry(&self, from: &sled::Tree, to: &sled::Tree, entry: &OutboxEntry) -> Result<(), OutboxError> {
+        use sled::Transactional;
+
+        let key = entry.order_number.to_be_bytes();
+        let value = encode(entry)?;
+        (from, to)
+            .transaction(|(from, to)| {
+                from.remove(&key[..])?;
+                to.insert(&key[..], value.clone())?;
+                Ok(())
+            })
+            .map_err(|err: sled::transaction::TransactionError<()>| OutboxError::Backend(format!("{:?}", err)))
+    }
+
+    // Make sure every change is on disk
+    fn flush(&self) -> Result<(), OutboxError> {
+        self.db.flush().map_err(|err| OutboxError::Backend(err.to_string()))?;
+        Ok(())
+    }
+}
+
+// Encode an entry for storage
+fn encode(entry: &OutboxEntry) -> Result<Vec<u8>, OutboxError> {
+    serde_json::to_vec(entry).map_err(|err| OutboxError::Corrupt(err.to_string()))
+}
+
+// Decode a stored entry
+fn decode(bytes: &[u8]) -> Result<OutboxEntry, OutboxError> {
+    serde_json::from_slice(bytes).map_err(|err| OutboxError::Corrupt(err.to_string()))
+}
+
+// Decode every entry in a tree. Keys are big-endian order numbers, so they come out in order.
+fn decode_all(tree: &sled::Tree) -> Result<Vec<OutboxEntry>, OutboxError> {
+    tree.iter()
+        .map(|item| {
+            let (_, value) = item.map_err(|err| OutboxError::Backend(err.to_string()))?;
+            decode(&value)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A policy that retries after 10, 20, 40 and then 50 seconds, giving up after 5 failures
+    const POLICY: RetryPolicy = RetryPolicy { base_delay: 10, max_delay: 50, max_attempts: 5 };
+
+    // Open an outbox with POLICY in a fresh temporary directory, which is removed when the
+    // returned guard is dropped
+    fn outbox() -> (Outbox, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = Outbox::open(dir.path().join("outbox"), POLICY).unwrap();
+        (outbox, dir)
+    }
+
+    #[test]
+    fn the_backoff_doubles_up_to_the_maximum_delay() {
+        let delays: Vec<u64> = (1..=6).map(|attempts| POLICY.delay_after(attempts)).collect();
+        assert_eq!(delays, vec![10, 20, 40, 50, 50, 50]);
+        assert_eq!(POLICY.delay_after(u32::MAX), 50);
+        assert_eq!(RetryPolicy::default().delay_after(100), 60 * 60);
+
+        // Each failed attempt pushes the next one back by the delay for the attempts so far
+        let (outbox, _dir) = outbox();
+        outbox.enqueue(1, "{}".to_string(), 1_000).unwrap();
+        let mut now = 1_000;
+        for expected in [10, 20, 40, 50] {
+            outbox.record(1, &Delivery::Retry("503".to_string()), now).unwrap();
+            let entry = &outbox.pending().unwrap()[0];
+            assert_eq!(entry.next_attempt_at, now + expected);
+            assert!(outbox.due(now + expected - 1).unwrap().is_empty());
+            assert_eq!(outbox.due(now + expected).unwrap().len(), 1);
+            now += expected;
+        }
+    }
+
+    #[test]
+    fn entries_are_dead_lettered_after_the_last_attempt() {
+        let (outbox, _dir) = outbox();
+        outbox.enqueue(1, "{}".to_string(), 0).unwrap();
+        for attempt in 1..POLICY.max_attempts {
+            outbox.record(1, &Delivery::Retry(format!("timeout {}", attempt)), 0).unwrap();
+            assert_eq!(outbox.pending().unwrap()[0].attempts, attempt);
+        }
+        outbox.record(1, &Delivery::Retry("timeout 5".to_string()), 0).unwrap();
+
+        assert!(outbox.pending().unwrap().is_empty());
+        let dead = outbox.dead_letters().unwrap();
+        assert_eq!(dead.len(), 1);
+        assert_eq!((dead[0].attempts, dead[0].last_error.as_deref()), (5, Some("timeout 5")));
+        assert_eq!(outbox.record(1, &Delivery::Delivered, 0), Err(OutboxError::UnknownOrder(1)));
+    }
+
+    #[test]
+    fn rejected_entries_are_dead_lettered_at_once() {
+        let (outbox, _dir) = outbox();
+        outbox.enqueue(1, "{}".to_string(), 0).unwrap();
+        outbox.enqueue(2, "{}".to_string(), 0).unwrap();
+        outbox.record(1, &Delivery::Rejected("400 Bad Request".to_string()), 0).unwrap();
+        outbox.record(2, &Delivery::Delivered, 0).unwrap();
+
+        assert!(outbox.pending().unwrap().is_empty());
+        let dead = outbox.dead_letters().unwrap();
+        assert_eq!(dead.iter().map(|entry| entry.order_number).collect::<Vec<_>>(), vec![1]);
+        assert_eq!((dead[0].attempts, dead[0].last_error.as_deref()), (1, Some("400 Bad Request")));
+    }
+
+    #[test]
+    fn a_replayed_entry_is_due_again_with_the_same_idempotency_key() {
+        let (outbox, _dir) = outbox();
+        let queued = outbox.enqueue(7, "{\"x\":5}".to_string(), 0).unwrap();
+        outbox.record(7, &Delivery::Rejected("422".to_string()), 10).unwrap();
+        assert_eq!(outbox.replay(8, 100), Err(OutboxError::UnknownOrder(8)));
+
+        let replayed = outbox.replay(7, 100).unwrap();
+        assert_eq!(replayed.idempotency_key, queued.idempotency_key);
+        assert_eq!(replayed.idempotency_key, "saffron-order-7");
+        assert_eq!((replayed.attempts, replayed.next_attempt_at, replayed.body.as_str()), (0, 100, "{\"x\":5}"));
+        assert!(outbox.dead_letters().unwrap().is_empty());
+        assert_eq!(outbox.due(100).unwrap(), vec![replayed]);
+        assert_eq!(outbox.replay(7, 100), Err(OutboxError::UnknownOrder(7)));
+    }
+
+    #[test]
+    fn an_order_number_is_queued_only_once() {
+        let (outbox, _dir) = outbox();
+        outbox.enqueue(1, "first".to_string(), 0).unwrap();
+        assert_eq!(outbox.enqueue(1, "second".to_string(), 0), Err(OutboxError::Duplicate(1)));
+        assert_eq!(outbox.pending().unwrap()[0].body, "first");
+
+        // An order on the dead-letter list cannot be queued again either; it has to be replayed
+        outbox.record(1, &Delivery::Rejected("400".to_string()), 0).unwrap();
+        assert_eq!(outbox.enqueue(1, "third".to_string(), 0), Err(OutboxError::Duplicate(1)));
+        assert!(outbox.pending().unwrap().is_empty());
+    }
+}
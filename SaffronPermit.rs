@@ -0,0 +1,160 @@
+use web3::signing::{keccak256, recover};
+use web3::types::{Address, H256, U256};
+
+// The EIP-712 type of the domain a Saffron permit is signed for
+const DOMAIN_TYPE: &str = "EIP712Domain(string name,string version,uint256 chainId,address verifyingContract)";
+
+// The EIP-712 type of a permit, as defined by EIP-2612
+const PERMIT_TYPE: &str = "Permit(address owner,address spender,uint256 value,uint256 nonce,uint256 deadline)";
+
+// The version of the signing domain. Changing it invalidates every outstanding permit.
+pub const PERMIT_DOMAIN_VERSION: &str = "1";
+
+// Half the order of the secp256k1 curve. Signatures with an s value above this are the
+// malleable twin of a valid signature and are refused, as Ethereum does.
+const SECP256K1_HALF_ORDER: &str = "7fffffffffffffffffffffffffffffff5d576e7357a4501ddfe92f46681b20a0";
+
+// PermitDomain identifies the deployment a permit is valid for, so that a signature for one
+// chain or contract cannot be replayed on another
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PermitDomain {
+    // The token name, as returned by ERC20::name
+    pub name: String,
+    // The id of the chain the token is deployed on
+    pub chain_id: U256,
+    // The address of the deployed token contract
+    pub verifying_contract: Address,
+}
+
+impl PermitDomain {
+    // Return the EIP-712 domain separator of this domain
+    pub fn separator(&self) -> H256 {
+        let mut encoded = Vec::with_capacity(5 * 32);
+        encoded.extend_from_slice(&keccak256(DOMAIN_TYPE.as_bytes()));
+        encoded.extend_from_slice(&keccak256(self.name.as_bytes()));
+        encoded.extend_from_slice(&keccak256(PERMIT_DOMAIN_VERSION.as_bytes()));
+        encoded.extend_from_slice(&u256_word(self.chain_id));
+        encoded.extend_from_slice(&address_word(&self.verifying_contract));
+        H256(keccak256(&encoded))
+    }
+}
+
+// Permit is the message an owner signs to approve a spender without sending a transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Permit {
+    pub owner: Address,
+    pub spender: Address,
+    pub value: U256,
+    pub nonce: U256,
+    pub deadline: U256,
+}
+
+impl Permit {
+    // Return the EIP-712 digest of this permit in `domain`, which is what the owner signs
+    pub fn digest(&self, domain: &PermitDomain) -> H256 {
+        // Hash the permit's fields, each ABI-encoded as a 32-byte word
+        let mut encoded = Vec::with_capacity(6 * 32);
+        encoded.extend_from_slice(&keccak256(PERMIT_TYPE.as_bytes()));
+        encoded.extend_from_slice(&address_word(&self.owner));
+        encoded.extend_from_slice(&address_word(&self.spender));
+        encoded.extend_from_slice(&u256_word(self.value));
+        encoded.extend_from_slice(&u256_word(self.nonce));
+        encoded.extend_from_slice(&u256_word(self.deadline));
+        let struct_hash = keccak256(&encoded);
+
+        // Combine it with the domain as "\x19\x01" ‖ domainSeparator ‖ structHash
+        let mut message = Vec::with_capacity(2 + 2 * 32);
+        message.extend_from_slice(&[0x19, 0x01]);
+        message.extend_from_slice(domain.separator().as_bytes());
+        message.extend_from_slice(&struct_hash);
+        H256(keccak256(&message))
+    }
+}
+
+// Return the address whose key produced the signature (v, r, s) over `digest`, or None if the
+// signature is malformed or malleable. Both the 27/28 and the 0/1 forms of v are accepted.
+pub fn recover_signer(digest: &H256, v: u8, r: &H256, s: &H256) -> Option<Address> {
+    let recovery_id = match v {
+        27 | 28 => v - 27,
+        0 | 1 => v,
+        _ => return None,
+    };
+    let half_order: U256 = SECP256K1_HALF_ORDER.parse().ok()?;
+    if U256::from_big_endian(s.as_bytes()) > half_order {
+        return None;
+    }
+
+    let mut signature = [0u8; 64];
+    signature[..32].copy_from_slice(r.as_bytes());
+    signature[32..].copy_from_slice(s.as_bytes());
+    recover(digest.as_bytes(), &signature, recovery_id as i32).ok()
+}
+
+// ABI-encode an address as a 32-byte word, left-padded with zeros
+fn address_word(address: &Address) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    word[12..].copy_from_slice(address.as_bytes());
+    word
+}
+
+// ABI-encode a uint256 as a 32-byte big-endian word
+fn u256_word(value: U256) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    value.to_big_endian(&mut word);
+    word
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A permit for 1 token from the key 0x4c0883a6...3f362318 to the address ending in 2, signed
+    // for mainnet. The expected hashes and signature were produced by an independent EIP-712
+    // implementation.
+    fn permit() -> (PermitDomain, Permit) {
+        let domain = PermitDomain {
+            name: "Saffron Vendor".to_string(),
+            chain_id: U256::one(),
+            verifying_contract: "cccccccccccccccccccccccccccccccccccccccc".parse().unwrap(),
+        };
+        let permit = Permit {
+            owner: "2c7536e3605d9c16a7a3d7b1898e529396a65c23".parse().unwrap(),
+            spender: Address::from_low_u64_be(2),
+            value: U256::exp10(18),
+            nonce: U256::zero(),
+            deadline: U256::from(u64::MAX),
+        };
+        (domain, permit)
+    }
+
+    const R: &str = "1f8f2eaf3cf302c0bbb8fcb44b23bc5a5d1c9422366974feaf8fe7ca0edf8d55";
+    const S: &str = "0f99680dac1dec4b2c9a291b2569cdfffab0c1029e589bca1f2f54d774f5b512";
+
+    #[test]
+    fn digest_matches_the_eip_712_encoding() {
+        let (domain, permit) = permit();
+        let separator: H256 = "5a06886d40047752a13a84a16fa9e3dd0fe5c8348f1b46e7860c570cb4e38446".parse().unwrap();
+        let digest: H256 = "d42ee52029e3595f72ce69de506607a06d363657bf477f41458a25333ef3ef30".parse().unwrap();
+        assert_eq!(domain.separator(), separator);
+        assert_eq!(permit.digest(&domain), digest);
+    }
+
+    #[test]
+    fn signer_is_recovered_from_either_form_of_v() {
+        let (domain, permit) = permit();
+        let digest = permit.digest(&domain);
+        let (r, s) = (R.parse().unwrap(), S.parse().unwrap());
+        assert_eq!(recover_signer(&digest, 28, &r, &s), Some(permit.owner));
+        assert_eq!(recover_signer(&digest, 1, &r, &s), Some(permit.owner));
+        assert_eq!(recover_signer(&digest, 29, &r, &s), None);
+    }
+
+    #[test]
+    fn high_s_twin_of_a_valid_signature_is_refused() {
+        let (domain, permit) = permit();
+        let digest = permit.digest(&domain);
+        // The curve order minus S, which with the other v recovers the same key
+        let high_s: H256 = "f06697f253e213b4d365d6e4da9631febffe1be410f00471a0a309b55b408c2f".parse().unwrap();
+        assert_eq!(recover_signer(&digest, 27, &R.parse().unwrap(), &high_s), None);
+    }
+}
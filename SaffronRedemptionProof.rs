@@ -0,0 +1,449 @@
+use std::fmt;
+use std::path::Path;
+
+use web3::signing::keccak256;
+use web3::transports::Http;
+use web3::types::{Address, H256, U256};
+use web3::Web3;
+
+use crate::saffron_mailing_address::MailingAddress;
+use crate::saffron_outbox::{Outbox, OutboxEntry, OutboxError};
+use crate::saffron_permit::recover_signer;
+
+// The signature of the ERC-20 Transfer event, whose hash is the first topic of every Transfer log
+const TRANSFER_EVENT: &str = "Transfer(address,address,uint256)";
+
+// The tree claims are kept in, so they can share a database with the outbox
+const CLAIMS_TREE: &str = "redemption/claims";
+
+// ProofError describes why a redemption could not be verified or claimed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProofError {
+    // The transaction hash is not 32 bytes of hex
+    InvalidTransactionHash,
+    // The signature is not 65 bytes of hex
+    InvalidSignature,
+    // The grams asked for are not a whole number
+    InvalidQuantity,
+    // The node does not know the transaction, or it has not been mined yet
+    TransactionNotFound(H256),
+    // The transaction was mined but reverted
+    TransactionFailed(H256),
+    // The transaction does not have enough blocks on top of it yet
+    NotConfirmed { confirmations: u64, required: u64 },
+    // The transaction neither burned nor escrowed any $affron
+    NoRedemption(H256),
+    // The transaction moved $affron from more than one holder, so it cannot be tied to one
+    AmbiguousHolder(H256),
+    // The request was not signed by the holder whose tokens were redeemed
+    SignerMismatch { holder: Address, signer: Option<Address> },
+    // The tokens redeemed are not a whole number of grams
+    FractionalGrams(U256),
+    // The holder signed for a different number of grams than the transaction redeemed
+    QuantityMismatch { claimed: u64, redeemed: u64 },
+    // The redemption has already been used for an order
+    AlreadyClaimed { tx_hash: H256, order_number: u64 },
+    // The Ethereum node could not be reached
+    Node(String),
+    // The claims database could not be read or written
+    Storage(String),
+}
+
+impl fmt::Display for ProofError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProofError::InvalidTransactionHash => write!(f, "the transaction hash must be 32 bytes of hex"),
+            ProofError::InvalidSignature => write!(f, "the signature must be 65 bytes of hex"),
+            ProofError::InvalidQuantity => write!(f, "the grams must be a whole number"),
+            ProofError::TransactionNotFound(tx_hash) => write!(f, "transaction {:?} has not been mined", tx_hash),
+            ProofError::TransactionFailed(tx_hash) => write!(f, "transaction {:?} failed", tx_hash),
+            ProofError::NotConfirmed { confirmations, required } => write!(
+                f,
+                "the transaction has {} confirmations; wait for {} and try again",
+                confirmations, required
+            ),
+            ProofError::NoRedemption(tx_hash) => write!(f, "transaction {:?} did not redeem any $affron", tx_hash),
+            ProofError::AmbiguousHolder(tx_hash) => {
+                write!(f, "transaction {:?} redeemed tokens from more than one holder", tx_hash)
+            }
+            ProofError::SignerMismatch { holder, .. } => {
+                write!(f, "the redemption request must be signed by the holder {:?}", holder)
+            }
+            ProofError::FractionalGrams(value) => {
+                write!(f, "{} base units is not a whole number of grams", value)
+            }
+            ProofError::QuantityMismatch { claimed, redeemed } => write!(
+                f,
+                "the request was signed for {} grams but the transaction redeemed {}",
+                claimed, redeemed
+            ),
+            ProofError::AlreadyClaimed { tx_hash, order_number } => write!(
+                f,
+                "transaction {:?} has already been redeemed as order {}",
+                tx_hash, order_number
+            ),
+            ProofError::Node(message) => write!(f, "Ethereum node error: {}", message),
+            ProofError::Storage(message) => write!(f, "redemption claims storage error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ProofError {}
+
+// RedemptionClaim is a visitor's request to ship the saffron for a redemption: the hash of the
+// transaction that burned or escrowed their tokens, the grams it is for and where to ship them,
+// signed with the wallet that held the tokens
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedemptionClaim {
+    pub tx_hash: H256,
+    // The grams of saffron asked for, which must be what the transaction redeemed
+    pub grams: u64,
+    // The checked address the saffron is shipped to
+    pub mail_address: MailingAddress,
+    // The (v, r, s) signature over `claim_message(tx_hash, grams, mail_address)`
+    pub v: u8,
+    pub r: H256,
+    pub s: H256,
+}
+
+impl RedemptionClaim {
+    // Parse a claim from the hex transaction hash, the grams asked for, the checked address and
+    // the 65-byte hex signature returned by the wallet's personal_sign. The hash and signature
+    // may be given with or without a 0x prefix.
+    pub fn parse(tx_hash: &str, grams: &str, mail_address: &MailingAddress, signature: &str) -> Result<Self, ProofError> {
+        let tx_hash = decode_hex(tx_hash)
+            .filter(|bytes| bytes.len() == 32)
+            .map(|bytes| H256::from_slice(&bytes))
+            .ok_or(ProofError::InvalidTransactionHash)?;
+        let grams = grams.trim().parse().map_err(|_| ProofError::InvalidQuantity)?;
+        let signature = decode_hex(signature)
+            .filter(|bytes| bytes.len() == 65)
+            .ok_or(ProofError::InvalidSignature)?;
+        Ok(Self {
+            tx_hash,
+            grams,
+            mail_address: mail_address.clone(),
+            r: H256::from_slice(&signature[..32]),
+            s: H256::from_slice(&signature[32..64]),
+            v: signature[64],
+        })
+    }
+
+    // Return the address that signed the claim, if the signature is valid
+    pub fn signer(&self) -> Option<Address> {
+        let message = claim_message(&self.tx_hash, self.grams, &self.mail_address);
+        recover_signer(&personal_message_hash(&message), self.v, &self.r, &self.s)
+    }
+
+    // Return the grams `redemption` is worth, refusing it unless they are the grams the holder
+    // signed for
+    pub fn grams_of(&self, redemption: &VerifiedRedemption, decimals: u8) -> Result<u64, ProofError> {
+        let redeemed = redemption.grams(decimals)?;
+        if redeemed != self.grams {
+            return Err(ProofError::QuantityMismatch { claimed: self.grams, redeemed });
+        }
+        Ok(redeemed)
+    }
+}
+
+// Return the text the holder signs to claim the redemption made in `tx_hash`: `grams` of saffron
+// shipped to `mail_address`. The address is written out in its normalised form, as it will be
+// printed on the label, so the holder sees where the saffron goes and a signature cannot be
+// reused to send it elsewhere. The hash is written out as 0x and 64 lowercase hex digits, the
+// form wallets and explorers show it in, so the message does not depend on how the hash type
+// happens to format itself.
+pub fn claim_message(tx_hash: &H256, grams: u64, mail_address: &MailingAddress) -> String {
+    format!(
+        "Redeem $affron for saffron\nTransaction: 0x{}\nGrams: {}\nShip to:\n{}",
+        encode_hex(tx_hash.as_bytes()),
+        grams,
+        mail_address
+    )
+}
+
+// VerifiedRedemption is a redemption found on chain and signed for by its holder
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifiedRedemption {
+    // The transaction that burned or escrowed the tokens
+    pub tx_hash: H256,
+    // The block the transaction was mined in
+    pub block_number: u64,
+    // The holder whose tokens were redeemed
+    pub holder: Address,
+    // The tokens redeemed, in base units
+    pub value: U256,
+}
+
+impl VerifiedRedemption {
+    // Return the grams of saffron the redeemed tokens are worth, one gram per whole token.
+    // Redemptions of part of a gram are refused.
+    pub fn grams(&self, decimals: u8) -> Result<u64, ProofError> {
+        let (grams, remainder) = self.value.div_mod(U256::exp10(decimals as usize));
+        if !remainder.is_zero() || grams.is_zero() || grams > U256::from(u64::MAX) {
+            return Err(ProofError::FractionalGrams(self.value));
+        }
+        Ok(grams.as_u64())
+    }
+}
+
+// RedemptionVerifier checks redemption claims against the chain. A transaction counts as a
+// redemption if it succeeded and moved $affron from a single holder to the zero address (a
+// burn) or to the escrow account the vendor contract redeems from.
+pub struct RedemptionVerifier {
+    web3: Web3<Http>,
+    // The $affron token contract
+    token: Address,
+    // The account redeemed tokens are escrowed in
+    escrow: Address,
+    // The number of blocks, counting the transaction's own, a redemption must be buried under
+    confirmations: u64,
+}
+
+impl RedemptionVerifier {
+    // Create a verifier that asks the node at `rpc_url` about transfers of `token`
+    pub fn new(rpc_url: &str, token: Address, escrow: Address, confirmations: u64) -> Result<Self, ProofError> {
+        let transport = Http::new(rpc_url).map_err(|err| ProofError::Node(err.to_string()))?;
+        Ok(Self {
+            web3: Web3::new(transport),
+            token,
+            escrow,
+            confirmations,
+        })
+    }
+
+    // Check that `claim` names a confirmed redemption and was signed by its holder. The node is
+    // asked without blocking, so a slow node does not hold up the server's other requests.
+    pub async fn verify(&self, claim: &RedemptionClaim) -> Result<VerifiedRedemption, ProofError> {
+        let tx_hash = claim.tx_hash;
+        let receipt = self
+            .web3
+            .eth()
+            .transaction_receipt(tx_hash)
+            .await
+            .map_err(|err| ProofError::Node(err.to_string()))?
+            .ok_or(ProofError::TransactionNotFound(tx_hash))?;
+        if receipt.status.is_some_and(|status| status.is_zero()) {
+            return Err(ProofError::TransactionFailed(tx_hash));
+        }
+        let block_number = receipt.block_number.ok_or(ProofError::TransactionNotFound(tx_hash))?.as_u64();
+
+        // Make sure the transaction is deep enough that it will not be reorganised away
+        let latest = self
+            .web3
+            .eth()
+            .block_number()
+            .await
+            .map_err(|err| ProofError::Node(err.to_string()))?
+            .as_u64();
+        let confirmations = latest.saturating_sub(block_number) + 1;
+        if confirmations < self.confirmations {
+            return Err(ProofError::NotConfirmed { confirmations, required: self.confirmations });
+        }
+
+        // Add up the token's Transfer logs into the zero address or the escrow account
+        let transfer_topic = H256(keccak256(TRANSFER_EVENT.as_bytes()));
+        let mut holder = None;
+        let mut value = U256::zero();
+        for log in &receipt.logs {
+            if log.address != self.token || log.topics.len() != 3 || log.topics[0] != transfer_topic {
+                continue;
+            }
+            let from = Address::from_slice(&log.topics[1].as_bytes()[12..]);
+            let to = Address::from_slice(&log.topics[2].as_bytes()[12..]);
+            if to != Address::zero() && to != self.escrow {
+                continue;
+            }
+            if holder.is_some_and(|holder| holder != from) {
+                return Err(ProofError::AmbiguousHolder(tx_hash));
+            }
+            holder = Some(from);
+            value = value.saturating_add(U256::from_big_endian(&log.data.0));
+        }
+        let holder = holder.ok_or(ProofError::NoRedemption(tx_hash))?;
+        if value.is_zero() {
+            return Err(ProofError::NoRedemption(tx_hash));
+        }
+
+        // Only the holder may say where their saffron goes
+        let signer = claim.signer();
+        if signer != Some(holder) {
+            return Err(ProofError::SignerMismatch { holder, signer });
+        }
+        Ok(VerifiedRedemption { tx_hash, block_number, holder, value })
+    }
+}
+
+// RedemptionClaims remembers which redemption transactions have been turned into orders, so that
+// each burn ships once. Claims are kept in a sled database and taken atomically, so two
+// requests racing for the same transaction cannot both win.
+pub struct RedemptionClaims {
+    claims: sled::Tree,
+}
+
+impl RedemptionClaims {
+    // Open the claims in the database at `path`, creating it if it does not exist
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, ProofError> {
+        let db = sled::open(path).map_err(|err| ProofError::Storage(err.to_string()))?;
+        Self::open_in(&db)
+    }
+
+    // Open the claims in `db`. Keep them in the outbox's database to use claim_and_enqueue.
+    pub fn open_in(db: &sled::Db) -> Result<Self, ProofError> {
+        let claims = db.open_tree(CLAIMS_TREE).map_err(|err| ProofError::Storage(err.to_string()))?;
+        Ok(Self { claims })
+    }
+
+    // Return the order a transaction was claimed for, if it has been
+    pub fn order_for(&self, tx_hash: &H256) -> Result<Option<u64>, ProofError> {
+        let stored = self.claims.get(tx_hash.as_bytes()).map_err(|err| ProofError::Storage(err.to_string()))?;
+        Ok(stored.and_then(|bytes| decode_order_number(&bytes)))
+    }
+
+    // Claim `tx_hash` for `order_number` and queue the order's `body` in `outbox` in one
+    // transaction, so a transaction is never claimed without its order being queued, even if
+    // the server fails in between. `outbox` must have been opened in the claims' database.
+    pub fn claim_and_enqueue(
+        &self,
+        tx_hash: &H256,
+        order_number: u64,
+        outbox: &Outbox,
+        body: String,
+        now: u64,
+    ) -> Result<OutboxEntry, ProofError> {
+        let order = order_number.to_be_bytes();
+        match outbox.enqueue_guarded(&self.claims, tx_hash.as_bytes(), &order, order_number, body, now) {
+            Ok(entry) => Ok(entry),
+            Err(OutboxError::AlreadyTaken) => Err(ProofError::AlreadyClaimed {
+                tx_hash: *tx_hash,
+                order_number: self.order_for(tx_hash)?.unwrap_or(0),
+            }),
+            Err(err) => Err(ProofError::Storage(err.to_string())),
+        }
+    }
+}
+
+// Return the hash a wallet signs for `message` with personal_sign (EIP-191)
+fn personal_message_hash(message: &str) -> H256 {
+    let mut prefixed = format!("\x19Ethereum Signed Message:\n{}", message.len()).into_bytes();
+    prefixed.extend_from_slice(message.as_bytes());
+    H256(keccak256(&prefixed))
+}
+
+// Encode bytes as lowercase hex, without a 0x prefix
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Decode a hex string, with or without a 0x prefix
+fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    let value = value.trim();
+    let digits = value.strip_prefix("0x").unwrap_or(value);
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(digits.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+// Decode an order number stored as 8 big-endian bytes
+fn decode_order_number(bytes: &[u8]) -> Option<u64> {
+    let bytes: [u8; 8] = bytes.try_into().ok()?;
+    Some(u64::from_be_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A transaction hash with leading and trailing zero bytes, which a shortened form would drop
+    const TX_HASH: &str = "0x00ab00000000000000000000000000000000000000000000000000000000ff01";
+
+    #[test]
+    fn a_claim_is_taken_exactly_when_its_order_is_queued() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = sled::open(dir.path()).unwrap();
+        let claims = RedemptionClaims::open_in(&db).unwrap();
+        let outbox = Outbox::open_in(db, Default::default()).unwrap();
+        let tx_hash = H256::from_low_u64_be(1);
+
+        // An order number already in the outbox leaves the transaction unclaimed
+        outbox.enqueue(10_000_008, "{}".to_string(), 0).unwrap();
+        let result = claims.claim_and_enqueue(&tx_hash, 10_000_008, &outbox, "{}".to_string(), 0);
+        assert!(matches!(result, Err(ProofError::Storage(_))));
+        assert_eq!(claims.order_for(&tx_hash), Ok(None));
+
+        claims.claim_and_enqueue(&tx_hash, 10_000_016, &outbox, "{}".to_string(), 0).unwrap();
+        assert_eq!(claims.order_for(&tx_hash), Ok(Some(10_000_016)));
+        assert_eq!(outbox.pending().unwrap().len(), 2);
+
+        // A second claim of the same transaction queues nothing
+        let result = claims.claim_and_enqueue(&tx_hash, 10_000_024, &outbox, "{}".to_string(), 0);
+        assert_eq!(result, Err(ProofError::AlreadyClaimed { tx_hash, order_number: 10_000_016 }));
+        assert_eq!(outbox.pending().unwrap().len(), 2);
+    }
+
+    // Return the address the saffron is to be shipped to in these tests
+    fn mail_address() -> MailingAddress {
+        MailingAddress::parse(&crate::saffron_mailing_address::MailingAddressFields {
+            name: "Ada Lovelace".to_string(),
+            line1: "123 Main Street".to_string(),
+            city: "Springfield".to_string(),
+            state: "IL".to_string(),
+            zip: "62701".to_string(),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    // Sign the claim message for `grams` shipped to `mail_address` with the key 0x4c0883a6...3f362318,
+    // as a wallet's personal_sign would, and return the 65-byte signature as hex
+    fn sign(grams: u64, mail_address: &MailingAddress) -> String {
+        use web3::signing::{Key, SecretKey, SecretKeyRef};
+        let key = SecretKey::from_slice(&decode_hex("4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318").unwrap()).unwrap();
+        let tx_hash = H256::from_slice(&decode_hex(TX_HASH).unwrap());
+        let digest = personal_message_hash(&claim_message(&tx_hash, grams, mail_address));
+        let signature = SecretKeyRef::new(&key).sign_message(digest.as_bytes()).unwrap();
+        format!("{}{}{:02x}", encode_hex(signature.r.as_bytes()), encode_hex(signature.s.as_bytes()), signature.v + 27)
+    }
+
+    #[test]
+    fn claim_message_spells_out_the_whole_hash_the_grams_and_the_address() {
+        let tx_hash = H256::from_slice(&decode_hex(TX_HASH).unwrap());
+        assert_eq!(
+            claim_message(&tx_hash, 5, &mail_address()),
+            format!("Redeem $affron for saffron\nTransaction: {}\nGrams: 5\nShip to:\nADA LOVELACE\n123 MAIN ST\nSPRINGFIELD IL 62701", TX_HASH)
+        );
+    }
+
+    #[test]
+    fn claim_is_tied_to_the_wallet_that_signed_it() {
+        let holder: Address = "2c7536e3605d9c16a7a3d7b1898e529396a65c23".parse().unwrap();
+        let signature = sign(5, &mail_address());
+        let claim = RedemptionClaim::parse(TX_HASH, " 5 ", &mail_address(), &signature).unwrap();
+        assert_eq!(claim.signer(), Some(holder));
+
+        // The same signature does not speak for the holder once the grams or the address change
+        let claim = RedemptionClaim::parse(TX_HASH, "6", &mail_address(), &signature).unwrap();
+        assert_ne!(claim.signer(), Some(holder));
+        let elsewhere = MailingAddress { line1: "1 ELM ST".to_string(), ..mail_address() };
+        let claim = RedemptionClaim::parse(TX_HASH, "5", &elsewhere, &signature).unwrap();
+        assert_ne!(claim.signer(), Some(holder));
+
+        assert_eq!(RedemptionClaim::parse(TX_HASH, "5.5", &mail_address(), &signature), Err(ProofError::InvalidQuantity));
+    }
+
+    #[test]
+    fn the_redemption_must_be_for_the_grams_signed_for() {
+        let claim = RedemptionClaim::parse(TX_HASH, "5", &mail_address(), &sign(5, &mail_address())).unwrap();
+        let redemption = |grams: u64| VerifiedRedemption {
+            tx_hash: claim.tx_hash,
+            block_number: 1,
+            holder: Address::from_low_u64_be(1),
+            value: U256::from(grams) * U256::exp10(18),
+        };
+        assert_eq!(claim.grams_of(&redemption(5), 18), Ok(5));
+        assert_eq!(claim.grams_of(&redemption(50), 18), Err(ProofError::QuantityMismatch { claimed: 5, redeemed: 50 }));
+    }
+}
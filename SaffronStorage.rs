@@ -0,0 +1,632 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::Path;
+use std::sync::Mutex;
+
+use web3::types::{Address, H256, U256};
+
+use crate::saffron_vendor::{
+    LoggedEvent, OraclePrice, PriceGuard, PriceRequest, Redemption, Role, RoleChange, TransferFee, VestingSchedule,
+    DEFAULT_REDEMPTION_TTL,
+};
+
+// StorageError describes why the ledger state could not be read from or written to a store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StorageError {
+    // The storage backend failed, e.g. the database file could not be opened or written
+    Backend(String),
+    // A stored record could not be decoded
+    Corrupt(String),
+    // The database at the given path is open in another process, such as the server
+    Locked(String),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Backend(message) => write!(f, "storage backend error: {}", message),
+            StorageError::Corrupt(message) => write!(f, "corrupt ledger record: {}", message),
+            StorageError::Locked(path) => {
+                write!(f, "the ledger database at {} is in use by another process; stop it and try again", path)
+            }
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+// SupplyState holds the ledger's running totals and settings, which are rewritten with every
+// batch. A field missing from a record written by an older version takes the value a new ledger
+// starts with, so stores from before a field existed still load.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SupplyState {
+    // The total supply of the token
+    pub total_supply: U256,
+    // The grams of physical saffron in stock
+    pub inventory_grams: U256,
+    // The grams promised to pending redemptions
+    pub pending_redemption_grams: U256,
+    // The id the next redemption will be given
+    pub next_redemption_id: u64,
+    // The id the next vesting schedule will be given
+    pub next_vesting_id: u64,
+    // The id of the latest snapshot, or zero if none has been taken
+    pub current_snapshot_id: u64,
+    // The wei received from every purchase, after refunds
+    pub payments_received: U256,
+    // How many seconds a redemption may stay pending before it can be expired
+    pub redemption_ttl: u64,
+    // The chain id permits are signed for
+    pub chain_id: U256,
+    // The token contract address permits are signed for
+    pub contract_address: Address,
+    // The fee taken from transfers
+    pub transfer_fee: TransferFee,
+    // Whether the token is paused
+    pub paused: bool,
+    // The oracle price requests are sent to
+    pub oracle_address: Address,
+    // The number of price requests ever made
+    pub price_request_count: u64,
+    // The latest price reported by the oracle or set by an admin
+    pub latest_price: Option<OraclePrice>,
+    // The latest price that could be trusted
+    pub trusted_price: Option<U256>,
+    // The staleness and deviation limits on the oracle price
+    pub price_guard: PriceGuard,
+}
+
+// An empty store holds the state of a new ledger
+impl Default for SupplyState {
+    fn default() -> Self {
+        Self {
+            total_supply: U256::zero(),
+            inventory_grams: U256::zero(),
+            pending_redemption_grams: U256::zero(),
+            next_redemption_id: 0,
+            next_vesting_id: 0,
+            current_snapshot_id: 0,
+            payments_received: U256::zero(),
+            redemption_ttl: DEFAULT_REDEMPTION_TTL,
+            chain_id: U256::one(),
+            contract_address: Address::zero(),
+            transfer_fee: TransferFee::none(),
+            paused: false,
+            oracle_address: Address::zero(),
+            price_request_count: 0,
+            latest_price: None,
+            trusted_price: None,
+            price_guard: PriceGuard::default(),
+        }
+    }
+}
+
+// WriteOp is a single change to the stored ledger
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WriteOp {
+    // Set the balance of an account
+    Balance { account: Address, value: U256 },
+    // Set the allowance of a spender; an allowance of zero removes the record
+    Allowance { owner: Address, spender: Address, value: U256 },
+    // Insert or replace a redemption record
+    Redemption(Redemption),
+    // Append an event to the log
+    Event(LoggedEvent),
+    // Record the balance an account had at a snapshot
+    AccountSnapshot { account: Address, snapshot_id: u64, value: U256 },
+    // Record the total supply at a snapshot
+    TotalSupplySnapshot { snapshot_id: u64, value: U256 },
+    // Set the permit nonce of an owner
+    Nonce { owner: Address, nonce: U256 },
+    // Insert or replace a vesting schedule
+    Vesting(VestingSchedule),
+    // Give a role to an account, or take it away
+    Role { role: Role, account: Address, held: bool },
+    // Append an entry to the audit trail of role changes, at position `index`
+    RoleChange { index: u64, change: RoleChange },
+    // Exempt an account from transfer fees, or remove its exemption
+    FeeExempt { account: Address, exempt: bool },
+    // Add an account to the blocklist, or take it off
+    Blocked { account: Address, blocked: bool },
+    // Record a price request sent to the oracle, or remove it once it has been answered
+    PriceRequest { request_id: H256, request: Option<PriceRequest> },
+    // Replace the running totals and settings
+    Supply(Box<SupplyState>),
+}
+
+// WriteBatch is a group of changes that a store applies all together or not at all, so that a
+// transfer is never stored with only one side of it applied
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    ops: Vec<WriteOp>,
+}
+
+impl WriteBatch {
+    // Add a change to the batch
+    pub fn push(&mut self, op: WriteOp) {
+        self.ops.push(op);
+    }
+
+    // Return the changes in the order they were made
+    pub fn ops(&self) -> &[WriteOp] {
+        &self.ops
+    }
+
+    // Return true if the batch holds no changes
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    // Remove every change from the batch
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+}
+
+// StoredLedger is the whole ledger state as read back from a store
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StoredLedger {
+    pub balances: BTreeMap<Address, U256>,
+    pub allowances: BTreeMap<Address, BTreeMap<Address, U256>>,
+    pub redemptions: BTreeMap<u64, Redemption>,
+    pub events: Vec<LoggedEvent>,
+    pub account_snapshots: BTreeMap<Address, Vec<(u64, U256)>>,
+    pub total_supply_snapshots: Vec<(u64, U256)>,
+    pub nonces: BTreeMap<Address, U256>,
+    pub vesting: BTreeMap<u64, VestingSchedule>,
+    pub roles: BTreeMap<Role, BTreeSet<Address>>,
+    pub role_changes: Vec<RoleChange>,
+    pub fee_exempt: BTreeSet<Address>,
+    pub blocklist: BTreeSet<Address>,
+    pub price_requests: BTreeMap<H256, PriceRequest>,
+    pub supply: SupplyState,
+}
+
+impl StoredLedger {
+    // Apply the changes in `batch` to this state
+    fn apply(&mut self, batch: &WriteBatch) {
+        for op in batch.ops() {
+            match op {
+                WriteOp::Balance { account, value } => {
+                    self.balances.insert(*account, *value);
+                }
+                WriteOp::Allowance { owner, spender, value } => {
+                    if value.is_zero() {
+                        if let Some(spenders) = self.allowances.get_mut(owner) {
+                            spenders.remove(spender);
+                            if spenders.is_empty() {
+                                self.allowances.remove(owner);
+                            }
+                        }
+                    } else {
+                        self.allowances.entry(*owner).or_default().insert(*spender, *value);
+                    }
+                }
+                WriteOp::Redemption(redemption) => {
+                    self.redemptions.insert(redemption.id, redemption.clone());
+                }
+                WriteOp::Event(logged) => self.events.push(logged.clone()),
+                WriteOp::AccountSnapshot { account, snapshot_id, value } => {
+                    self.account_snapshots.entry(*account).or_default().push((*snapshot_id, *value));
+                }
+                WriteOp::TotalSupplySnapshot { snapshot_id, value } => {
+                    self.total_supply_snapshots.push((*snapshot_id, *value));
+                }
+                WriteOp::Nonce { owner, nonce } => {
+                    self.nonces.insert(*owner, *nonce);
+                }
+                WriteOp::Vesting(schedule) => {
+                    self.vesting.insert(schedule.id, schedule.clone());
+                }
+                WriteOp::Role { role, account, held } => {
+                    if *held {
+                        self.roles.entry(*role).or_default().insert(*account);
+                    } else if let Some(holders) = self.roles.get_mut(role) {
+                        holders.remove(account);
+                        if holders.is_empty() {
+                            self.roles.remove(role);
+                        }
+                    }
+                }
+                WriteOp::RoleChange { change, .. } => self.role_changes.push(change.clone()),
+                WriteOp::FeeExempt { account, exempt } => {
+                    if *exempt {
+                        self.fee_exempt.insert(*account);
+                    } else {
+                        self.fee_exempt.remove(account);
+                    }
+                }
+                WriteOp::Blocked { account, blocked } => {
+                    if *blocked {
+                        self.blocklist.insert(*account);
+                    } else {
+                        self.blocklist.remove(account);
+                    }
+                }
+                WriteOp::PriceRequest { request_id, request } => match request {
+                    Some(request) => {
+                        self.price_requests.insert(*request_id, request.clone());
+                    }
+                    None => {
+                        self.price_requests.remove(request_id);
+                    }
+                },
+                WriteOp::Supply(supply) => self.supply = supply.as_ref().clone(),
+            }
+        }
+    }
+}
+
+// LedgerStore is somewhere the Saffron ledger state lives between restarts
+pub trait LedgerStore {
+    // Read back the whole stored ledger. An empty store returns an empty ledger.
+    fn load(&self) -> Result<StoredLedger, StorageError>;
+
+    // Apply every change in `batch` atomically: after a crash either all of them are stored or
+    // none are
+    fn write(&self, batch: &WriteBatch) -> Result<(), StorageError>;
+}
+
+// MemoryStore keeps the ledger state in memory. It is lost when the process exits, which makes
+// it suitable for simulations and tests.
+#[derive(Default)]
+pub struct MemoryStore {
+    state: Mutex<StoredLedger>,
+}
+
+impl MemoryStore {
+    // Create an empty store
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl LedgerStore for MemoryStore {
+    fn load(&self) -> Result<StoredLedger, StorageError> {
+        let state = self.state.lock().map_err(|err| StorageError::Backend(err.to_string()))?;
+        Ok(state.clone())
+    }
+
+    fn write(&self, batch: &WriteBatch) -> Result<(), StorageError> {
+        // The lock is held for the whole batch, so readers never see part of it
+        let mut state = self.state.lock().map_err(|err| StorageError::Backend(err.to_string()))?;
+        state.apply(batch);
+        Ok(())
+    }
+}
+
+// Key prefixes of the records in a SledStore. Ids are stored big-endian so records sort in order.
+const BALANCE_PREFIX: &[u8] = b"balance/";
+const ALLOWANCE_PREFIX: &[u8] = b"allowance/";
+const REDEMPTION_PREFIX: &[u8] = b"redemption/";
+const EVENT_PREFIX: &[u8] = b"event/";
+const ACCOUNT_SNAPSHOT_PREFIX: &[u8] = b"snapshot/account/";
+const TOTAL_SUPPLY_SNAPSHOT_PREFIX: &[u8] = b"snapshot/supply/";
+const NONCE_PREFIX: &[u8] = b"nonce/";
+const VESTING_PREFIX: &[u8] = b"vesting/";
+const ROLE_PREFIX: &[u8] = b"role/";
+const ROLE_CHANGE_PREFIX: &[u8] = b"role-change/";
+const FEE_EXEMPT_PREFIX: &[u8] = b"fee-exempt/";
+const BLOCKED_PREFIX: &[u8] = b"blocked/";
+const PRICE_REQUEST_PREFIX: &[u8] = b"price-request/";
+const SUPPLY_KEY: &[u8] = b"supply";
+
+// SledStore keeps the ledger state in an embedded sled database on disk
+pub struct SledStore {
+    db: sled::Db,
+}
+
+impl SledStore {
+    // Open the database at `path`, creating it if it does not exist. Only one process can have
+    // the database open at a time; opening it while another holds it fails with Locked.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StorageError> {
+        let path = path.as_ref();
+        let db = sled::open(path).map_err(|err| match err {
+            // sled takes an exclusive lock on the database file and names it in the error
+            sled::Error::Io(io) if io.to_string().starts_with("could not acquire lock") => {
+                StorageError::Locked(path.display().to_string())
+            }
+            err => StorageError::Backend(err.to_string()),
+        })?;
+        Ok(Self { db })
+    }
+
+    // Read and decode every record whose key starts with `prefix`
+    fn scan<T: for<'de> Deserialize<'de>>(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, T)>, StorageError> {
+        self.db
+            .scan_prefix(prefix)
+            .map(|entry| {
+                let (key, value) = entry.map_err(|err| StorageError::Backend(err.to_string()))?;
+                let decoded = serde_json::from_slice(&value).map_err(|err| StorageError::Corrupt(err.to_string()))?;
+                Ok((key[prefix.len()..].to_vec(), decoded))
+            })
+            .collect()
+    }
+}
+
+// Join a key prefix and the parts that identify a record
+fn record_key(prefix: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    let mut key = prefix.to_vec();
+    for part in parts {
+        key.extend_from_slice(part);
+    }
+    key
+}
+
+// Decode a big-endian id taken from a record key
+fn decode_id(bytes: &[u8]) -> Result<u64, StorageError> {
+    let bytes: [u8; 8] = bytes.try_into().map_err(|_| StorageError::Corrupt("malformed id in key".to_string()))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+// Decode an account address taken from a record key
+fn decode_address(bytes: &[u8]) -> Result<Address, StorageError> {
+    if bytes.len() != Address::len_bytes() {
+        return Err(StorageError::Corrupt("malformed address in key".to_string()));
+    }
+    Ok(Address::from_slice(bytes))
+}
+
+// Split a record key into the account address it starts with and the rest of the key
+fn split_address(key: &[u8]) -> Result<(Address, &[u8]), StorageError> {
+    if key.len() < Address::len_bytes() {
+        return Err(StorageError::Corrupt("malformed address in key".to_string()));
+    }
+    let (account, rest) = key.split_at(Address::len_bytes());
+    Ok((Address::from_slice(account), rest))
+}
+
+// Return the byte a role is keyed under. The role itself is stored as the record's value.
+fn role_tag(role: Role) -> u8 {
+    match role {
+        Role::Owner => 0,
+        Role::Minter => 1,
+        Role::OracleAdmin => 2,
+        Role::Fulfiller => 3,
+    }
+}
+
+// Encode a record for storage
+fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, StorageError> {
+    serde_json::to_vec(value).map_err(|err| StorageError::Corrupt(err.to_string()))
+}
+
+impl LedgerStore for SledStore {
+    fn load(&self) -> Result<StoredLedger, StorageError> {
+        let mut ledger = StoredLedger::default();
+        for (key, value) in self.scan::<U256>(BALANCE_PREFIX)? {
+            ledger.balances.insert(decode_address(&key)?, value);
+        }
+        for (key, value) in self.scan::<U256>(ALLOWANCE_PREFIX)? {
+            // The key holds the owner followed by the spender
+            let (owner, spender) = split_address(&key)?;
+            ledger.allowances.entry(owner).or_default().insert(decode_address(spender)?, value);
+        }
+        for (_, redemption) in self.scan::<Redemption>(REDEMPTION_PREFIX)? {
+            ledger.redemptions.insert(redemption.id, redemption);
+        }
+        ledger.events = self.scan::<LoggedEvent>(EVENT_PREFIX)?.into_iter().map(|(_, logged)| logged).collect();
+        for (key, value) in self.scan::<U256>(ACCOUNT_SNAPSHOT_PREFIX)? {
+            // The key holds the account followed by the snapshot id, so checkpoints come back in
+            // ascending id order for each account
+            let (account, snapshot_id) = split_address(&key)?;
+            ledger.account_snapshots.entry(account).or_default().push((decode_id(snapshot_id)?, value));
+        }
+        for (key, value) in self.scan::<U256>(TOTAL_SUPPLY_SNAPSHOT_PREFIX)? {
+            ledger.total_supply_snapshots.push((decode_id(&key)?, value));
+        }
+        for (key, nonce) in self.scan::<U256>(NONCE_PREFIX)? {
+            ledger.nonces.insert(decode_address(&key)?, nonce);
+        }
+        for (_, schedule) in self.scan::<VestingSchedule>(VESTING_PREFIX)? {
+            ledger.vesting.insert(schedule.id, schedule);
+        }
+        for (key, role) in self.scan::<Role>(ROLE_PREFIX)? {
+            // The key holds the role's tag followed by the account
+            let account = key.get(1..).ok_or_else(|| StorageError::Corrupt("malformed role key".to_string()))?;
+            ledger.roles.entry(role).or_default().insert(decode_address(account)?);
+        }
+        ledger.role_changes = self.scan::<RoleChange>(ROLE_CHANGE_PREFIX)?.into_iter().map(|(_, change)| change).collect();
+        for (key, _) in self.scan::<bool>(FEE_EXEMPT_PREFIX)? {
+            ledger.fee_exempt.insert(decode_address(&key)?);
+        }
+        for (key, _) in self.scan::<bool>(BLOCKED_PREFIX)? {
+            ledger.blocklist.insert(decode_address(&key)?);
+        }
+        for (_, request) in self.scan::<PriceRequest>(PRICE_REQUEST_PREFIX)? {
+            ledger.price_requests.insert(request.request_id, request);
+        }
+        if let Some(supply) = self.db.get(SUPPLY_KEY).map_err(|err| StorageError::Backend(err.to_string()))? {
+            ledger.supply = serde_json::from_slice(&supply).map_err(|err| StorageError::Corrupt(err.to_string()))?;
+        }
+        Ok(ledger)
+    }
+
+    fn write(&self, batch: &WriteBatch) -> Result<(), StorageError> {
+        // Collect every change into one sled batch, which is applied atomically
+        let mut sled_batch = sled::Batch::default();
+        for op in batch.ops() {
+            match op {
+                WriteOp::Balance { account, value } => {
+                    sled_batch.insert(record_key(BALANCE_PREFIX, &[account.as_bytes()]), encode(value)?);
+                }
+                WriteOp::Allowance { owner, spender, value } => {
+                    let key = record_key(ALLOWANCE_PREFIX, &[owner.as_bytes(), spender.as_bytes()]);
+                    if value.is_zero() {
+                        sled_batch.remove(key);
+                    } else {
+                        sled_batch.insert(key, encode(value)?);
+                    }
+                }
+                WriteOp::Redemption(redemption) => {
+                    let key = record_key(REDEMPTION_PREFIX, &[&redemption.id.to_be_bytes()]);
+                    sled_batch.insert(key, encode(redemption)?);
+                }
+                WriteOp::Event(logged) => {
+                    let key = record_key(EVENT_PREFIX, &[&logged.log_index.to_be_bytes()]);
+                    sled_batch.insert(key, encode(logged)?);
+                }
+                WriteOp::AccountSnapshot { account, snapshot_id, value } => {
+                    let key = record_key(ACCOUNT_SNAPSHOT_PREFIX, &[account.as_bytes(), &snapshot_id.to_be_bytes()]);
+                    sled_batch.insert(key, encode(value)?);
+                }
+                WriteOp::TotalSupplySnapshot { snapshot_id, value } => {
+                    let key = record_key(TOTAL_SUPPLY_SNAPSHOT_PREFIX, &[&snapshot_id.to_be_bytes()]);
+                    sled_batch.insert(key, encode(value)?);
+                }
+                WriteOp::Nonce { owner, nonce } => {
+                    sled_batch.insert(record_key(NONCE_PREFIX, &[owner.as_bytes()]), encode(nonce)?);
+                }
+                WriteOp::Vesting(schedule) => {
+                    let key = record_key(VESTING_PREFIX, &[&schedule.id.to_be_bytes()]);
+                    sled_batch.insert(key, encode(schedule)?);
+                }
+                WriteOp::Role { role, account, held } => {
+                    let key = record_key(ROLE_PREFIX, &[&[role_tag(*role)], account.as_bytes()]);
+                    if *held {
+                        sled_batch.insert(key, encode(role)?);
+                    } else {
+                        sled_batch.remove(key);
+                    }
+                }
+                WriteOp::RoleChange { index, change } => {
+                    sled_batch.insert(record_key(ROLE_CHANGE_PREFIX, &[&index.to_be_bytes()]), encode(change)?);
+                }
+                WriteOp::FeeExempt { account, exempt } => {
+                    let key = record_key(FEE_EXEMPT_PREFIX, &[account.as_bytes()]);
+                    if *exempt {
+                        sled_batch.insert(key, encode(exempt)?);
+                    } else {
+                        sled_batch.remove(key);
+                    }
+                }
+                WriteOp::Blocked { account, blocked } => {
+                    let key = record_key(BLOCKED_PREFIX, &[account.as_bytes()]);
+                    if *blocked {
+                        sled_batch.insert(key, encode(blocked)?);
+                    } else {
+                        sled_batch.remove(key);
+                    }
+                }
+                WriteOp::PriceRequest { request_id, request } => {
+                    let key = record_key(PRICE_REQUEST_PREFIX, &[request_id.as_bytes()]);
+                    match request {
+                        Some(request) => sled_batch.insert(key, encode(request)?),
+                        None => sled_batch.remove(key),
+                    }
+                }
+                WriteOp::Supply(supply) => {
+                    sled_batch.insert(SUPPLY_KEY, encode(supply)?);
+                }
+            }
+        }
+
+        // Apply the batch and wait for it to reach the disk
+        self.db.apply_batch(sled_batch).map_err(|err| StorageError::Backend(err.to_string()))?;
+        self.db.flush().map_err(|err| StorageError::Backend(err.to_string()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_database_open_elsewhere_is_reported_as_locked() {
+        let held = test_support::TempSledStore::new();
+        let path = held.path().to_path_buf();
+        assert_eq!(SledStore::open(&path).err(), Some(StorageError::Locked(path.display().to_string())));
+    }
+
+    #[test]
+    fn keys_too_short_for_their_addresses_are_reported_as_corrupt() {
+        let keys: [&[u8]; 4] = [b"balance/\x01\x02", b"allowance/\x01\x02", b"snapshot/account/\x01", b"role/"];
+        for key in keys {
+            let held = test_support::TempSledStore::new();
+            held.store.db.insert(key, encode(&U256::one()).unwrap()).unwrap();
+            assert!(matches!(held.store.load(), Err(StorageError::Corrupt(_))), "{:?}", key);
+        }
+
+        // An allowance key with a whole owner but a partial spender is caught too
+        let held = test_support::TempSledStore::new();
+        let key = record_key(ALLOWANCE_PREFIX, &[Address::from_low_u64_be(1).as_bytes(), &[7; 3]]);
+        held.store.db.insert(key, encode(&U256::one()).unwrap()).unwrap();
+        assert!(matches!(held.store.load(), Err(StorageError::Corrupt(_))));
+    }
+
+    #[test]
+    fn supply_records_from_before_a_field_existed_still_load() {
+        // A record as written before snapshots, vesting, purchases or settings were stored
+        let old = br#"{"total_supply":"0xa","inventory_grams":"0xc","pending_redemption_grams":"0x2","next_redemption_id":3}"#;
+        let supply: SupplyState = serde_json::from_slice(old).unwrap();
+        assert_eq!(supply.total_supply, U256::from(10));
+        assert_eq!(supply.next_redemption_id, 3);
+        assert_eq!(supply.next_vesting_id, 0);
+        assert_eq!(supply.current_snapshot_id, 0);
+        assert_eq!(supply.redemption_ttl, DEFAULT_REDEMPTION_TTL);
+        assert_eq!(supply.chain_id, U256::one());
+        assert_eq!(supply.transfer_fee, TransferFee::none());
+    }
+}
+
+// Fixtures shared by the tests of the modules that keep their state in a store
+#[cfg(test)]
+pub(crate) mod test_support {
+    use super::{LedgerStore, MemoryStore, SledStore};
+    use std::fmt::Debug;
+    use std::path::Path;
+    use std::thread;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    // TempSledStore is a sled store in a fresh temporary directory, which is removed when the
+    // store is dropped
+    pub(crate) struct TempSledStore {
+        // Declared before the directory so the database is closed before it is removed
+        pub(crate) store: SledStore,
+        dir: TempDir,
+    }
+
+    impl TempSledStore {
+        pub(crate) fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let store = SledStore::open(dir.path()).unwrap();
+            Self { store, dir }
+        }
+
+        // Return the directory the database is kept in
+        pub(crate) fn path(&self) -> &Path {
+            self.dir.path()
+        }
+
+        // Close the database and open it again, as a restarted server would
+        pub(crate) fn reopen(self) -> Self {
+            let TempSledStore { store, dir } = self;
+            drop(store);
+            let store = reopen_when_released(|| SledStore::open(dir.path()));
+            Self { store, dir }
+        }
+    }
+
+    // Open a sled database that was just closed by calling `open` until it succeeds. sled's
+    // background flusher keeps the database locked for a moment after the last handle to it is
+    // dropped, so the first attempts may find it still in use. Gives up after about a second.
+    pub(crate) fn reopen_when_released<T, E: Debug>(open: impl Fn() -> Result<T, E>) -> T {
+        for _ in 0..100 {
+            if let Ok(opened) = open() {
+                return opened;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        open().unwrap()
+    }
+
+    // Run `test` against each kind of store: one in memory and one in a fresh sled database
+    pub(crate) fn with_each_store(test: impl Fn(&dyn LedgerStore)) {
+        test(&MemoryStore::new());
+        test(&TempSledStore::new().store);
+    }
+}
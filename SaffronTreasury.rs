@@ -0,0 +1,522 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use chrono::{TimeZone, Utc};
+use web3::types::{Address, U256};
+
+use crate::saffron_vendor::{CallContext, Role, Saffron, SaffronEvent};
+
+// The number of seconds in a day, the window the daily withdrawal limit applies to
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// TreasuryError describes why a treasury operation was refused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TreasuryError {
+    // The caller does not hold the owner role on the ledger
+    Unauthorized,
+    // The zero address cannot receive payouts
+    ZeroAddress,
+    // Withdrawals of nothing are refused
+    InvalidAmount,
+    // The address is not one of the configured payout addresses
+    UnknownPayoutAddress(Address),
+    // The treasury holds less than the withdrawal asks for
+    InsufficientFunds { requested: U256, available: U256 },
+    // The withdrawal is larger than a single withdrawal may be
+    WithdrawalLimitExceeded { requested: U256, limit: U256 },
+    // The withdrawal would take the day's withdrawals over the daily limit
+    DailyLimitExceeded { requested: U256, withdrawn_today: U256, limit: U256 },
+    // Arithmetic on the totals overflowed
+    Overflow,
+    // The treasury file could not be read or written
+    Storage(String),
+}
+
+impl fmt::Display for TreasuryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TreasuryError::Unauthorized => write!(f, "only an owner may manage the treasury"),
+            TreasuryError::ZeroAddress => write!(f, "the zero address cannot receive payouts"),
+            TreasuryError::InvalidAmount => write!(f, "the withdrawal amount must be greater than zero"),
+            TreasuryError::UnknownPayoutAddress(address) => write!(f, "{:?} is not a payout address", address),
+            TreasuryError::InsufficientFunds { requested, available } => {
+                write!(f, "withdrawal of {} wei exceeds the {} wei held", requested, available)
+            }
+            TreasuryError::WithdrawalLimitExceeded { requested, limit } => {
+                write!(f, "withdrawal of {} wei exceeds the limit of {} wei per withdrawal", requested, limit)
+            }
+            TreasuryError::DailyLimitExceeded { requested, withdrawn_today, limit } => write!(
+                f,
+                "withdrawal of {} wei on top of {} wei already withdrawn today exceeds the daily limit of {} wei",
+                requested, withdrawn_today, limit
+            ),
+            TreasuryError::Overflow => write!(f, "arithmetic overflow"),
+            TreasuryError::Storage(message) => write!(f, "treasury storage error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for TreasuryError {}
+
+// Sale is one purchase of tokens, as read from the ledger's Bought events
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sale {
+    // The position of the Bought event in the ledger's log
+    pub log_index: u64,
+    pub buyer: Address,
+    // The tokens issued, in base units
+    pub tokens: U256,
+    // The wei kept for the tokens
+    pub revenue: U256,
+    // The block timestamp of the sale
+    pub timestamp: u64,
+}
+
+// Withdrawal is ETH taken out of the treasury, which the caller then sends to `payout`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Withdrawal {
+    // The order the withdrawal was made in, starting from one
+    pub id: u64,
+    // The owner who made it
+    pub by: Address,
+    // The payout address the ETH goes to
+    pub payout: Address,
+    // The wei withdrawn
+    pub amount: U256,
+    // The block timestamp of the withdrawal
+    pub timestamp: u64,
+}
+
+// WithdrawalLimits caps how much ETH may leave the treasury. A limit left as None is no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WithdrawalLimits {
+    // The most a single withdrawal may take, in wei
+    pub per_withdrawal: Option<U256>,
+    // The most all withdrawals in one UTC day may take together, in wei
+    pub per_day: Option<U256>,
+}
+
+// ReportPeriod is the length of the periods a revenue report is broken into, in UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportPeriod {
+    Day,
+    Month,
+}
+
+// RevenueRow totals the sales and withdrawals of one period
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RevenueRow {
+    // The period, as YYYY-MM-DD for days or YYYY-MM for months
+    pub period: String,
+    // The number of sales
+    pub sales: u64,
+    // The tokens sold, in base units
+    pub tokens: U256,
+    // The wei received
+    pub revenue: U256,
+    // The wei withdrawn
+    pub withdrawn: U256,
+}
+
+// Treasury keeps the books on the ETH taken in by token sales. It reads sales from the ledger's
+// log rather than being called by it, so the ledger stays unaware of it; withdrawals are checked
+// against the ledger's owner role. The treasury only records withdrawals: sending the ETH to the
+// payout address is up to the caller.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Treasury {
+    // Every sale, oldest first
+    sales: Vec<Sale>,
+    // Every withdrawal, oldest first
+    withdrawals: Vec<Withdrawal>,
+    // The addresses withdrawals may be paid to
+    payout_addresses: BTreeSet<Address>,
+    // The caps on withdrawals
+    limits: WithdrawalLimits,
+    // The position in the ledger's log up to which events have been read
+    next_log_index: usize,
+}
+
+impl Treasury {
+    // Create an empty treasury with no payout addresses and no withdrawal limits
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Read a treasury from the JSON file at `path`. A missing file holds an empty treasury.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, TreasuryError> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::new());
+        }
+        let contents = fs::read_to_string(path).map_err(|err| TreasuryError::Storage(err.to_string()))?;
+        serde_json::from_str(&contents).map_err(|err| TreasuryError::Storage(err.to_string()))
+    }
+
+    // Write the treasury to the JSON file at `path`. The file is written next to it under a
+    // temporary name and then renamed over it, so a crash mid-write leaves the old books intact
+    // rather than a truncated file.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), TreasuryError> {
+        let path = path.as_ref();
+        let contents = serde_json::to_string_pretty(self).map_err(|err| TreasuryError::Storage(err.to_string()))?;
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+
+        let mut file = fs::File::create(&temp_path).map_err(|err| TreasuryError::Storage(err.to_string()))?;
+        file.write_all(contents.as_bytes())
+            .and_then(|()| file.sync_all())
+            .map_err(|err| TreasuryError::Storage(err.to_string()))?;
+        fs::rename(&temp_path, path).map_err(|err| TreasuryError::Storage(err.to_string()))
+    }
+
+    // Record the sales logged by the ledger since the last sync, returning how many were new
+    pub fn sync(&mut self, saffron: &Saffron) -> usize {
+        let log = saffron.event_log();
+        let before = self.sales.len();
+        for logged in &log[self.next_log_index.min(log.len())..] {
+            if let SaffronEvent::Bought { buyer, value, cost, .. } = logged.event {
+                self.sales.push(Sale {
+                    log_index: logged.log_index,
+                    buyer,
+                    tokens: value,
+                    revenue: cost,
+                    timestamp: logged.timestamp,
+                });
+            }
+        }
+        self.next_log_index = log.len();
+        self.sales.len() - before
+    }
+
+    // Return every recorded sale, oldest first
+    pub fn sales(&self) -> &[Sale] {
+        &self.sales
+    }
+
+    // Return every withdrawal, oldest first
+    pub fn withdrawals(&self) -> &[Withdrawal] {
+        &self.withdrawals
+    }
+
+    // Return the wei received from every recorded sale
+    pub fn total_revenue(&self) -> U256 {
+        self.sales.iter().fold(U256::zero(), |total, sale| total.saturating_add(sale.revenue))
+    }
+
+    // Return the wei withdrawn so far
+    pub fn total_withdrawn(&self) -> U256 {
+        self.withdrawals.iter().fold(U256::zero(), |total, withdrawal| total.saturating_add(withdrawal.amount))
+    }
+
+    // Return the wei received and not yet withdrawn
+    pub fn balance(&self) -> U256 {
+        self.total_revenue().saturating_sub(self.total_withdrawn())
+    }
+
+    // Return the addresses withdrawals may be paid to
+    pub fn payout_addresses(&self) -> &BTreeSet<Address> {
+        &self.payout_addresses
+    }
+
+    // Allow withdrawals to be paid to `payout`. Only a ledger owner may do this.
+    pub fn add_payout_address(&mut self, saffron: &Saffron, ctx: &CallContext, payout: Address) -> Result<(), TreasuryError> {
+        require_owner(saffron, ctx)?;
+        if payout.is_zero() {
+            return Err(TreasuryError::ZeroAddress);
+        }
+        self.payout_addresses.insert(payout);
+        Ok(())
+    }
+
+    // Stop withdrawals being paid to `payout`. Only a ledger owner may do this.
+    pub fn remove_payout_address(&mut self, saffron: &Saffron, ctx: &CallContext, payout: &Address) -> Result<(), TreasuryError> {
+        require_owner(saffron, ctx)?;
+        if !self.payout_addresses.remove(payout) {
+            return Err(TreasuryError::UnknownPayoutAddress(*payout));
+        }
+        Ok(())
+    }
+
+    // Return the caps on withdrawals
+    pub fn limits(&self) -> WithdrawalLimits {
+        self.limits
+    }
+
+    // Set the caps on withdrawals. Only a ledger owner may do this.
+    pub fn set_limits(&mut self, saffron: &Saffron, ctx: &CallContext, limits: WithdrawalLimits) -> Result<(), TreasuryError> {
+        require_owner(saffron, ctx)?;
+        self.limits = limits;
+        Ok(())
+    }
+
+    // Withdraw `amount` wei to the configured payout address `payout`, within the withdrawal
+    // limits. Only a ledger owner may withdraw. The latest sales are synced first, and the
+    // amount is checked against the payments the ledger itself has received, so the books can
+    // never pay out more than came in.
+    pub fn withdraw(&mut self, saffron: &Saffron, ctx: &CallContext, payout: Address, amount: U256) -> Result<Withdrawal, TreasuryError> {
+        require_owner(saffron, ctx)?;
+        if amount.is_zero() {
+            return Err(TreasuryError::InvalidAmount);
+        }
+        if !self.payout_addresses.contains(&payout) {
+            return Err(TreasuryError::UnknownPayoutAddress(payout));
+        }
+        self.sync(saffron);
+        let available = saffron.payments_received().saturating_sub(self.total_withdrawn());
+        if amount > available {
+            return Err(TreasuryError::InsufficientFunds { requested: amount, available });
+        }
+        if let Some(limit) = self.limits.per_withdrawal {
+            if amount > limit {
+                return Err(TreasuryError::WithdrawalLimitExceeded { requested: amount, limit });
+            }
+        }
+        if let Some(limit) = self.limits.per_day {
+            // The day is the UTC calendar day the withdrawal is made on
+            let today = ctx.timestamp / SECONDS_PER_DAY;
+            let withdrawn_today = self
+                .withdrawals
+                .iter()
+                .filter(|withdrawal| withdrawal.timestamp / SECONDS_PER_DAY == today)
+                .fold(U256::zero(), |total, withdrawal| total.saturating_add(withdrawal.amount));
+            let after = withdrawn_today.checked_add(amount).ok_or(TreasuryError::Overflow)?;
+            if after > limit {
+                return Err(TreasuryError::DailyLimitExceeded { requested: amount, withdrawn_today, limit });
+            }
+        }
+
+        let withdrawal = Withdrawal {
+            id: self.withdrawals.len() as u64 + 1,
+            by: ctx.sender,
+            payout,
+            amount,
+            timestamp: ctx.timestamp,
+        };
+        self.withdrawals.push(withdrawal.clone());
+        Ok(withdrawal)
+    }
+
+    // Total the sales and withdrawals per UTC day or month, oldest period first. Periods with
+    // neither are left out.
+    pub fn revenue_report(&self, period: ReportPeriod) -> Vec<RevenueRow> {
+        let mut rows: BTreeMap<String, RevenueRow> = BTreeMap::new();
+        for sale in &self.sales {
+            let key = period_key(sale.timestamp, period);
+            let row = rows.entry(key.clone()).or_insert_with(|| RevenueRow { period: key, ..Default::default() });
+            row.sales += 1;
+            row.tokens = row.tokens.saturating_add(sale.tokens);
+            row.revenue = row.revenue.saturating_add(sale.revenue);
+        }
+        for withdrawal in &self.withdrawals {
+            let key = period_key(withdrawal.timestamp, period);
+            let row = rows.entry(key.clone()).or_insert_with(|| RevenueRow { period: key, ..Default::default() });
+            row.withdrawn = row.withdrawn.saturating_add(withdrawal.amount);
+        }
+        rows.into_values().collect()
+    }
+
+    // Return the revenue report as CSV with a header line. Amounts are in base units and wei,
+    // so spreadsheets do not round them.
+    pub fn revenue_csv(&self, period: ReportPeriod) -> String {
+        let mut csv = String::from("period,sales,tokens,revenue_wei,withdrawn_wei\n");
+        for row in self.revenue_report(period) {
+            csv.push_str(&format!("{},{},{},{},{}\n", row.period, row.sales, row.tokens, row.revenue, row.withdrawn));
+        }
+        csv
+    }
+}
+
+// Refuse callers that do not hold the owner role on the ledger
+fn require_owner(saffron: &Saffron, ctx: &CallContext) -> Result<(), TreasuryError> {
+    if saffron.has_role(Role::Owner, &ctx.sender) {
+        Ok(())
+    } else {
+        Err(TreasuryError::Unauthorized)
+    }
+}
+
+// Return the UTC day (YYYY-MM-DD) or month (YYYY-MM) the Unix timestamp falls in. A timestamp
+// chrono cannot represent falls back to the epoch.
+fn period_key(timestamp: u64, period: ReportPeriod) -> String {
+    let date = Utc
+        .timestamp_opt(timestamp as i64, 0)
+        .single()
+        .unwrap_or_else(|| Utc.timestamp_opt(0, 0).unwrap());
+    match period {
+        ReportPeriod::Day => date.format("%Y-%m-%d").to_string(),
+        ReportPeriod::Month => date.format("%Y-%m").to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Return a ledger whose owner, the address ending in 1, has sold a token for 1000 wei
+    fn ledger_with_a_sale() -> Saffron {
+        let owner = Address::from_low_u64_be(1);
+        let ctx = CallContext::new(owner, U256::zero(), 1, 1_000);
+        let mut saffron = Saffron::new("Saffron Vendor".to_string(), "$affron".to_string(), 18, owner).unwrap();
+        saffron.grant_role(&ctx, Role::OracleAdmin, &owner).unwrap();
+        saffron.add_inventory(&ctx, U256::from(10)).unwrap();
+        saffron.override_price(&ctx, U256::from(1_000)).unwrap();
+        saffron.buy(&CallContext::new(Address::from_low_u64_be(2), U256::from(1_000), 2, 1_001)).unwrap();
+        saffron
+    }
+
+    // Sell a token for 1000 wei to the address ending in 2 at `timestamp`, refreshing the price
+    // first so it is not stale
+    fn buy_at(saffron: &mut Saffron, timestamp: u64) {
+        let owner = CallContext::new(Address::from_low_u64_be(1), U256::zero(), 2, timestamp);
+        saffron.override_price(&owner, U256::from(1_000)).unwrap();
+        saffron.buy(&CallContext::new(Address::from_low_u64_be(2), U256::from(1_000), 2, timestamp)).unwrap();
+    }
+
+    // Return a treasury that may pay the address ending in 9, with the given limits
+    fn treasury_with_limits(saffron: &Saffron, limits: WithdrawalLimits) -> Treasury {
+        let owner = CallContext::new(Address::from_low_u64_be(1), U256::zero(), 3, 1_002);
+        let mut treasury = Treasury::new();
+        treasury.add_payout_address(saffron, &owner, Address::from_low_u64_be(9)).unwrap();
+        treasury.set_limits(saffron, &owner, limits).unwrap();
+        treasury
+    }
+
+    // Return a call from the owner at `timestamp`
+    fn owner_at(timestamp: u64) -> CallContext {
+        CallContext::new(Address::from_low_u64_be(1), U256::zero(), 4, timestamp)
+    }
+
+    #[test]
+    fn revenue_is_bucketed_by_utc_day_and_month() {
+        let mut saffron = ledger_with_a_sale();
+        // The last second of 1970-01-01, the first of 1970-01-02 and a day in February
+        buy_at(&mut saffron, SECONDS_PER_DAY - 1);
+        buy_at(&mut saffron, SECONDS_PER_DAY);
+        buy_at(&mut saffron, 40 * SECONDS_PER_DAY);
+        let mut treasury = treasury_with_limits(&saffron, WithdrawalLimits::default());
+        treasury.withdraw(&saffron, &owner_at(SECONDS_PER_DAY + 5), Address::from_low_u64_be(9), U256::from(300)).unwrap();
+        let tokens = treasury.sales()[0].tokens;
+
+        let days = treasury.revenue_report(ReportPeriod::Day);
+        let periods: Vec<&str> = days.iter().map(|row| row.period.as_str()).collect();
+        assert_eq!(periods, vec!["1970-01-01", "1970-01-02", "1970-02-10"]);
+        assert_eq!(days[0], RevenueRow {
+            period: "1970-01-01".to_string(),
+            sales: 2,
+            tokens: tokens * 2,
+            revenue: U256::from(2_000),
+            withdrawn: U256::zero(),
+        });
+        assert_eq!(days[1].sales, 1);
+        assert_eq!(days[1].withdrawn, U256::from(300));
+        assert_eq!(days[2].revenue, U256::from(1_000));
+
+        let months = treasury.revenue_report(ReportPeriod::Month);
+        assert_eq!(months, vec![
+            RevenueRow {
+                period: "1970-01".to_string(),
+                sales: 3,
+                tokens: tokens * 3,
+                revenue: U256::from(3_000),
+                withdrawn: U256::from(300),
+            },
+            RevenueRow {
+                period: "1970-02".to_string(),
+                sales: 1,
+                tokens,
+                revenue: U256::from(1_000),
+                withdrawn: U256::zero(),
+            },
+        ]);
+    }
+
+    #[test]
+    fn the_csv_export_has_a_header_and_one_line_per_period() {
+        let mut saffron = ledger_with_a_sale();
+        buy_at(&mut saffron, 40 * SECONDS_PER_DAY);
+        let mut treasury = Treasury::new();
+        assert_eq!(treasury.revenue_csv(ReportPeriod::Month), "period,sales,tokens,revenue_wei,withdrawn_wei\n");
+
+        treasury.sync(&saffron);
+        let tokens = treasury.sales()[0].tokens;
+        assert_eq!(
+            treasury.revenue_csv(ReportPeriod::Month),
+            format!("period,sales,tokens,revenue_wei,withdrawn_wei\n1970-01,1,{0},1000,0\n1970-02,1,{0},1000,0\n", tokens)
+        );
+    }
+
+    #[test]
+    fn a_withdrawal_over_the_single_limit_is_refused() {
+        let mut saffron = ledger_with_a_sale();
+        buy_at(&mut saffron, 1_001);
+        let limits = WithdrawalLimits { per_withdrawal: Some(U256::from(500)), per_day: None };
+        let mut treasury = treasury_with_limits(&saffron, limits);
+        let payout = Address::from_low_u64_be(9);
+
+        let result = treasury.withdraw(&saffron, &owner_at(1_003), payout, U256::from(501));
+        assert_eq!(result, Err(TreasuryError::WithdrawalLimitExceeded { requested: U256::from(501), limit: U256::from(500) }));
+        assert!(treasury.withdrawals().is_empty());
+        treasury.withdraw(&saffron, &owner_at(1_003), payout, U256::from(500)).unwrap();
+        treasury.withdraw(&saffron, &owner_at(1_004), payout, U256::from(500)).unwrap();
+        assert_eq!(treasury.total_withdrawn(), U256::from(1_000));
+    }
+
+    #[test]
+    fn the_daily_limit_resets_at_the_utc_day_boundary() {
+        let mut saffron = ledger_with_a_sale();
+        buy_at(&mut saffron, 1_001);
+        let limits = WithdrawalLimits { per_withdrawal: None, per_day: Some(U256::from(700)) };
+        let mut treasury = treasury_with_limits(&saffron, limits);
+        let payout = Address::from_low_u64_be(9);
+
+        treasury.withdraw(&saffron, &owner_at(1_003), payout, U256::from(400)).unwrap();
+        let result = treasury.withdraw(&saffron, &owner_at(SECONDS_PER_DAY - 1), payout, U256::from(301));
+        assert_eq!(
+            result,
+            Err(TreasuryError::DailyLimitExceeded {
+                requested: U256::from(301),
+                withdrawn_today: U256::from(400),
+                limit: U256::from(700),
+            })
+        );
+        treasury.withdraw(&saffron, &owner_at(SECONDS_PER_DAY - 1), payout, U256::from(300)).unwrap();
+
+        // The first second of the next day starts a fresh allowance
+        treasury.withdraw(&saffron, &owner_at(SECONDS_PER_DAY), payout, U256::from(700)).unwrap();
+        assert_eq!(treasury.total_withdrawn(), U256::from(1_400));
+    }
+
+    #[test]
+    fn withdrawals_see_sales_without_a_sync_and_never_exceed_the_payments() {
+        let saffron = ledger_with_a_sale();
+        let owner = CallContext::new(Address::from_low_u64_be(1), U256::zero(), 3, 1_002);
+        let payout = Address::from_low_u64_be(9);
+        let mut treasury = Treasury::new();
+        treasury.add_payout_address(&saffron, &owner, payout).unwrap();
+
+        treasury.withdraw(&saffron, &owner, payout, U256::from(600)).unwrap();
+        assert_eq!(treasury.sales().len(), 1);
+        let result = treasury.withdraw(&saffron, &owner, payout, U256::from(401));
+        assert_eq!(result, Err(TreasuryError::InsufficientFunds { requested: U256::from(401), available: U256::from(400) }));
+    }
+
+    #[test]
+    fn saved_books_replace_the_old_file_whole() {
+        let saffron = ledger_with_a_sale();
+        let path = std::env::temp_dir().join(format!("saffron-treasury-{}.json", std::process::id()));
+        let mut treasury = Treasury::new();
+        treasury.save(&path).unwrap();
+        treasury.sync(&saffron);
+        treasury.save(&path).unwrap();
+
+        assert_eq!(Treasury::load(&path).unwrap().sales(), treasury.sales());
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        assert!(!PathBuf::from(temp_path).exists());
+        let _ = fs::remove_file(&path);
+    }
+}
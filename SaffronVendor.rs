@@ -1,10 +1,677 @@
-use web3::types::{Address, U256};
-use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use web3::types::{Address, H160, H256, U256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use openzeppelin_solidity::{
-    contracts::{SafeMath, Burnable},
-    traits::{ERC20, Chainlink},
+use crate::saffron_fulfillment::{
+    order_id_for_redemption, FulfillmentError, FulfillmentProvider, FulfillmentRequest, FulfillmentStatus, ShippingAddress,
 };
+use web3::signing::keccak256;
+
+use crate::saffron_permit::{recover_signer, Permit, PermitDomain};
+use crate::saffron_storage::{LedgerStore, StorageError, SupplyState, WriteBatch, WriteOp};
+
+// CallContext carries what a contract would otherwise read from the transaction: who is calling,
+// how much ETH (in wei) they sent and the block the call runs in. Every mutating
+// method on Saffron takes one explicitly, so the same ledger code can be driven by an in-process
+// simulator, a test harness or the redemption server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallContext {
+    // The address making the call (msg.sender)
+    pub sender: Address,
+    // The amount of wei sent with the call (msg.value)
+    pub value: U256,
+    // The number of the block the call is executed in
+    pub block_number: u64,
+    // The block timestamp the call is executed at, in seconds since the Unix epoch
+    pub timestamp: u64,
+}
+
+impl CallContext {
+    // Create a context for a call from `sender` carrying `value` wei in block `block_number`
+    // at `timestamp`
+    pub fn new(sender: Address, value: U256, block_number: u64, timestamp: u64) -> Self {
+        Self { sender, value, block_number, timestamp }
+    }
+
+    // Create a context for a call from `sender` that sends no ETH, made outside of any block
+    // (block zero) and timestamped with the current system time
+    pub fn from_sender(sender: Address) -> Self {
+        // A clock set before the epoch is treated as the epoch itself
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+        Self::new(sender, U256::zero(), 0, timestamp)
+    }
+}
+
+// The account that holds tokens while their redemption is waiting to ship. Nobody has the key
+// to it; only the ledger moves tokens in and out of it.
+pub const ESCROW_ADDRESS: Address = H160([
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x5a, 0xfe,
+]);
+
+// The account that holds tokens allocated to vesting schedules until they are released. Like
+// the escrow account, only the ledger moves tokens in and out of it.
+pub const VESTING_ADDRESS: Address = H160([
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x5a, 0xf1,
+]);
+
+// How long a redemption may wait for fulfilment before its holder can be refunded: 30 days
+pub const DEFAULT_REDEMPTION_TTL: u64 = 30 * 24 * 60 * 60;
+
+// The longest an oracle price is trusted for before a fresh one is needed: one hour
+pub const DEFAULT_PRICE_HEARTBEAT: u64 = 60 * 60;
+
+// The largest move between consecutive oracle prices accepted without an admin confirming it,
+// in basis points: 10%
+pub const DEFAULT_MAX_PRICE_DEVIATION_BPS: u64 = 1_000;
+
+// The highest transfer fee the ledger accepts, in basis points (hundredths of a percent): 10%
+pub const MAX_TRANSFER_FEE_BPS: u16 = 1_000;
+
+// The most decimals a token can have: one whole token is 10^decimals base units, and 10^78 does
+// not fit in a U256
+pub const MAX_DECIMALS: u8 = 77;
+
+// SaffronError describes why a state-changing operation on the Saffron ledger was refused.
+// Every mutating method checks its arithmetic and returns one of these instead of wrapping
+// around or silently returning false, and a failed operation leaves the ledger unchanged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SaffronError {
+    // The account does not hold enough tokens for the operation
+    InsufficientBalance { needed: U256, available: U256 },
+    // The spender has not been approved for enough tokens by the owner
+    InsufficientAllowance { needed: U256, available: U256 },
+    // A balance, allowance or the total supply would exceed U256::MAX
+    Overflow,
+    // Minting would take the total supply past the amount the ledger is allowed to issue
+    SupplyCapExceeded { cap: U256, requested: U256 },
+    // Redeeming or removing stock would take more grams than the recorded inventory holds
+    InsufficientInventory { needed: U256, available: U256 },
+    // The amount is not valid for the operation, e.g. a redemption of a fraction of a gram
+    InvalidAmount,
+    // The permit's deadline passed before it was submitted
+    PermitExpired { deadline: U256, now: u64 },
+    // The permit was not signed by the owner it names
+    InvalidSignature,
+    // The transfer fee rate is above MAX_TRANSFER_FEE_BPS
+    FeeRateTooHigh { rate_bps: u16, max_bps: u16 },
+    // No redemption with this id exists
+    UnknownRedemption(u64),
+    // No snapshot with this id has been taken
+    UnknownSnapshot(u64),
+    // Token movement is paused
+    Paused,
+    // The account is on the blocklist and may not send or receive tokens
+    Blocked(Address),
+    // No vesting schedule with this id exists
+    UnknownVesting(u64),
+    // The vesting schedule cannot be revoked, either because it was created irrevocable or
+    // because it has already been revoked
+    NotRevocable(u64),
+    // No oracle address has been set, so prices cannot be requested
+    OracleNotSet,
+    // No pending price request has this id
+    UnknownPriceRequest(H256),
+    // The oracle has not reported a price yet
+    NoPrice,
+    // The latest price is older than the heartbeat allows
+    StalePrice { updated_at: u64, now: u64, heartbeat: u64 },
+    // The latest price moved further from the last trusted price than allowed
+    PriceDeviation { reference: U256, reported: U256, deviation_bps: u64, max_bps: u64 },
+    // The payment does not buy a single base unit of the token at the current price
+    PaymentTooSmall { payment: U256, wei_per_gram: U256 },
+    // The redemption has already been fulfilled, cancelled or expired
+    RedemptionNotPending(u64),
+    // The redemption cannot be expired before its deadline
+    RedemptionNotExpired { id: u64, expires_at: u64 },
+    // A shipping order has been placed for the redemption, so only the fulfilment provider can
+    // say whether it is safe to refund
+    FulfillmentOrderPlaced(u64),
+    // The fulfilment provider could not place, check or cancel a shipping order
+    Fulfillment(FulfillmentError),
+    // The caller is not permitted to perform the operation
+    Unauthorized,
+    // The zero address was given where a real account is required
+    ZeroAddress,
+    // The token cannot have this many decimals
+    InvalidDecimals { decimals: u8, max: u8 },
+    // The ledger could not be read from its store
+    Storage(StorageError),
+}
+
+impl fmt::Display for SaffronError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaffronError::InsufficientBalance { needed, available } => {
+                write!(f, "insufficient balance: needed {}, available {}", needed, available)
+            }
+            SaffronError::InsufficientAllowance { needed, available } => {
+                write!(f, "insufficient allowance: needed {}, available {}", needed, available)
+            }
+            SaffronError::Overflow => write!(f, "arithmetic overflow"),
+            SaffronError::SupplyCapExceeded { cap, requested } => {
+                write!(f, "supply cap of {} exceeded by request for {}", cap, requested)
+            }
+            SaffronError::InsufficientInventory { needed, available } => {
+                write!(f, "insufficient inventory: needed {} grams, available {} grams", needed, available)
+            }
+            SaffronError::InvalidAmount => write!(f, "invalid amount"),
+            SaffronError::PermitExpired { deadline, now } => {
+                write!(f, "permit expired at {}, it is now {}", deadline, now)
+            }
+            SaffronError::InvalidSignature => write!(f, "invalid permit signature"),
+            SaffronError::FeeRateTooHigh { rate_bps, max_bps } => {
+                write!(f, "transfer fee of {} basis points is above the maximum of {}", rate_bps, max_bps)
+            }
+            SaffronError::UnknownRedemption(id) => write!(f, "no redemption with id {}", id),
+            SaffronError::UnknownSnapshot(id) => write!(f, "no snapshot with id {}", id),
+            SaffronError::Paused => write!(f, "token movement is paused"),
+            SaffronError::Blocked(account) => write!(f, "account {:?} is blocked", account),
+            SaffronError::UnknownVesting(id) => write!(f, "no vesting schedule with id {}", id),
+            SaffronError::NotRevocable(id) => write!(f, "vesting schedule {} cannot be revoked", id),
+            SaffronError::OracleNotSet => write!(f, "no oracle address has been set"),
+            SaffronError::UnknownPriceRequest(id) => write!(f, "no pending price request {:?}", id),
+            SaffronError::NoPrice => write!(f, "the oracle has not reported a price yet"),
+            SaffronError::StalePrice { updated_at, now, heartbeat } => write!(
+                f,
+                "oracle price from {} is stale at {}: prices are only trusted for {} seconds",
+                updated_at, now, heartbeat
+            ),
+            SaffronError::PriceDeviation { reference, reported, deviation_bps, max_bps } => write!(
+                f,
+                "oracle price {} moved {} basis points from {}, more than the {} allowed",
+                reported, deviation_bps, reference, max_bps
+            ),
+            SaffronError::PaymentTooSmall { payment, wei_per_gram } => write!(
+                f,
+                "a payment of {} wei buys no tokens at {} wei per gram",
+                payment, wei_per_gram
+            ),
+            SaffronError::RedemptionNotPending(id) => write!(f, "redemption {} is no longer pending", id),
+            SaffronError::RedemptionNotExpired { id, expires_at } => {
+                write!(f, "redemption {} does not expire until {}", id, expires_at)
+            }
+            SaffronError::FulfillmentOrderPlaced(id) => {
+                write!(f, "redemption {} has a shipping order; cancel it through the fulfillment provider", id)
+            }
+            SaffronError::Fulfillment(err) => write!(f, "{}", err),
+            SaffronError::Unauthorized => write!(f, "caller is not authorized"),
+            SaffronError::ZeroAddress => write!(f, "the zero address is not a valid account"),
+            SaffronError::InvalidDecimals { decimals, max } => {
+                write!(f, "a token cannot have {} decimals; the most is {}", decimals, max)
+            }
+            SaffronError::Storage(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for SaffronError {}
+
+impl From<FulfillmentError> for SaffronError {
+    fn from(err: FulfillmentError) -> Self {
+        SaffronError::Fulfillment(err)
+    }
+}
+
+impl From<StorageError> for SaffronError {
+    fn from(err: StorageError) -> Self {
+        SaffronError::Storage(err)
+    }
+}
+
+// Role names the permissions an address can hold on the ledger. The owner administers every
+// role, including its own; the other roles each unlock one group of privileged operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Role {
+    // May grant and revoke every role
+    Owner,
+    // May mint new tokens
+    Minter,
+    // May change the oracle address used for pricing
+    OracleAdmin,
+    // May confirm that a redemption has been shipped
+    Fulfiller,
+}
+
+// RoleAction records what happened to a role assignment
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RoleAction {
+    // An owner gave the role to the account
+    Granted,
+    // An owner took the role away from the account
+    Revoked,
+    // The account gave up the role itself
+    Renounced,
+}
+
+// RoleChange is one entry in the audit trail of role assignments kept by the ledger
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoleChange {
+    // The role that changed
+    pub role: Role,
+    // The account that gained or lost the role
+    pub account: Address,
+    // Whether the role was granted, revoked or renounced
+    pub action: RoleAction,
+    // The address that made the change
+    pub changed_by: Address,
+    // The block timestamp of the change
+    pub timestamp: u64,
+}
+
+// SaffronEvent is an event emitted by the ledger, mirroring the events the Solidity contract
+// logs. Addresses are the indexed fields that off-chain services filter on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SaffronEvent {
+    // Tokens moved from one holder to another
+    Transfer { from: Address, to: Address, value: U256 },
+    // An owner set the allowance of a spender
+    Approval { owner: Address, spender: Address, value: U256 },
+    // New tokens were created for an account
+    Mint { to: Address, value: U256 },
+    // A holder destroyed tokens
+    Burn { from: Address, value: U256 },
+    // A holder asked to redeem tokens for physical saffron and the tokens went into escrow
+    RedeemRequest { id: u64, holder: Address, value: U256 },
+    // A redemption shipped and its escrowed tokens were burned
+    Redeem { id: u64, holder: Address, value: U256 },
+    // A redemption was cancelled or expired and its escrowed tokens went back to the holder
+    RedeemRefund { id: u64, holder: Address, value: U256 },
+    // A snapshot of every balance and the total supply was taken
+    Snapshot { id: u64 },
+    // An owner paused token movement
+    Paused { by: Address },
+    // An owner resumed token movement
+    Unpaused { by: Address },
+    // An owner put an account on the blocklist
+    Blocked { account: Address, by: Address },
+    // An owner took an account off the blocklist
+    Unblocked { account: Address, by: Address },
+    // Tokens were allocated to a beneficiary under a vesting schedule
+    VestingCreated { id: u64, beneficiary: Address, value: U256 },
+    // Vested tokens were released into a beneficiary's balance
+    VestingReleased { id: u64, beneficiary: Address, value: U256 },
+    // A vesting schedule was revoked and its unvested tokens returned
+    VestingRevoked { id: u64, beneficiary: Address, returned: U256 },
+    // A price was requested from the oracle; the oracle node listens for this event
+    PriceRequested { request_id: H256, oracle: Address, job_id: H256, payment: U256 },
+    // The oracle answered a price request
+    PriceFulfilled { request_id: H256, oracle: Address, wei_per_gram: U256, round_id: u64 },
+    // A buyer paid ETH for newly issued tokens; `payments_received` is the running total of
+    // every payment kept, as in SaffronVendor.sol's Bought event
+    Bought { buyer: Address, value: U256, cost: U256, payments_received: U256 },
+}
+
+// EventKind names the variants of SaffronEvent without their data, for filtering
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    Transfer,
+    Approval,
+    Mint,
+    Burn,
+    RedeemRequest,
+    Redeem,
+    RedeemRefund,
+    Snapshot,
+    Paused,
+    Unpaused,
+    Blocked,
+    Unblocked,
+    VestingCreated,
+    VestingReleased,
+    VestingRevoked,
+    PriceRequested,
+    PriceFulfilled,
+    Bought,
+}
+
+impl SaffronEvent {
+    // Return the kind of this event
+    pub fn kind(&self) -> EventKind {
+        match self {
+            SaffronEvent::Transfer { .. } => EventKind::Transfer,
+            SaffronEvent::Approval { .. } => EventKind::Approval,
+            SaffronEvent::Mint { .. } => EventKind::Mint,
+            SaffronEvent::Burn { .. } => EventKind::Burn,
+            SaffronEvent::RedeemRequest { .. } => EventKind::RedeemRequest,
+            SaffronEvent::Redeem { .. } => EventKind::Redeem,
+            SaffronEvent::RedeemRefund { .. } => EventKind::RedeemRefund,
+            SaffronEvent::Snapshot { .. } => EventKind::Snapshot,
+            SaffronEvent::Paused { .. } => EventKind::Paused,
+            SaffronEvent::Unpaused { .. } => EventKind::Unpaused,
+            SaffronEvent::Blocked { .. } => EventKind::Blocked,
+            SaffronEvent::Unblocked { .. } => EventKind::Unblocked,
+            SaffronEvent::VestingCreated { .. } => EventKind::VestingCreated,
+            SaffronEvent::VestingReleased { .. } => EventKind::VestingReleased,
+            SaffronEvent::VestingRevoked { .. } => EventKind::VestingRevoked,
+            SaffronEvent::PriceRequested { .. } => EventKind::PriceRequested,
+            SaffronEvent::PriceFulfilled { .. } => EventKind::PriceFulfilled,
+            SaffronEvent::Bought { .. } => EventKind::Bought,
+        }
+    }
+
+    // Return the indexed addresses of this event
+    pub fn indexed_addresses(&self) -> Vec<Address> {
+        match self {
+            SaffronEvent::Transfer { from, to, .. } => vec![*from, *to],
+            SaffronEvent::Approval { owner, spender, .. } => vec![*owner, *spender],
+            SaffronEvent::Mint { to, .. } => vec![*to],
+            SaffronEvent::Burn { from, .. } => vec![*from],
+            SaffronEvent::RedeemRequest { holder, .. }
+            | SaffronEvent::Redeem { holder, .. }
+            | SaffronEvent::RedeemRefund { holder, .. } => vec![*holder],
+            SaffronEvent::Snapshot { .. } => Vec::new(),
+            SaffronEvent::Paused { by } | SaffronEvent::Unpaused { by } => vec![*by],
+            SaffronEvent::Blocked { account, by } | SaffronEvent::Unblocked { account, by } => vec![*account, *by],
+            SaffronEvent::VestingCreated { beneficiary, .. }
+            | SaffronEvent::VestingReleased { beneficiary, .. }
+            | SaffronEvent::VestingRevoked { beneficiary, .. } => vec![*beneficiary],
+            SaffronEvent::PriceRequested { oracle, .. } | SaffronEvent::PriceFulfilled { oracle, .. } => vec![*oracle],
+            SaffronEvent::Bought { buyer, .. } => vec![*buyer],
+        }
+    }
+}
+
+// LoggedEvent is an event together with where it sits in the ledger's history
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LoggedEvent {
+    // The position of the event in the log, starting from zero
+    pub log_index: u64,
+    // The block the event was emitted in
+    pub block_number: u64,
+    // The block timestamp the event was emitted at
+    pub timestamp: u64,
+    // The event itself
+    pub event: SaffronEvent,
+}
+
+// EventFilter selects events from the ledger's log. Every field left as None matches any event,
+// and the block range is inclusive at both ends.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EventFilter {
+    // Only match events with this address among their indexed fields
+    pub address: Option<Address>,
+    // Only match events of this kind
+    pub kind: Option<EventKind>,
+    // Only match events emitted in this block or later
+    pub from_block: Option<u64>,
+    // Only match events emitted in this block or earlier
+    pub to_block: Option<u64>,
+}
+
+impl EventFilter {
+    // Return true if `logged` passes every condition of the filter
+    pub fn matches(&self, logged: &LoggedEvent) -> bool {
+        self.address.is_none_or(|address| logged.event.indexed_addresses().contains(&address))
+            && self.kind.is_none_or(|kind| logged.event.kind() == kind)
+            && self.from_block.is_none_or(|from| logged.block_number >= from)
+            && self.to_block.is_none_or(|to| logged.block_number <= to)
+    }
+}
+
+// FeeDestination is where the fee taken from a transfer goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FeeDestination {
+    // The fee is destroyed, shrinking the total supply
+    Burn,
+    // The fee is credited to the treasury address
+    Treasury(Address),
+}
+
+// TransferFee is the share of every transfer that is taken as a fee, like the burnTaxRate of the
+// BurnTaxToken example. The sender pays the full amount and the recipient receives it less the fee.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransferFee {
+    // The fee rate in basis points, at most MAX_TRANSFER_FEE_BPS
+    pub rate_bps: u16,
+    // Where the fee goes
+    pub destination: FeeDestination,
+}
+
+impl TransferFee {
+    // A fee of nothing, which is what a new ledger starts with
+    pub fn none() -> Self {
+        Self { rate_bps: 0, destination: FeeDestination::Burn }
+    }
+
+    // Return the fee due on a transfer of `value`, rounded down
+    pub fn fee_for(&self, value: U256) -> Result<U256, SaffronError> {
+        let scaled = value.checked_mul(U256::from(self.rate_bps)).ok_or(SaffronError::Overflow)?;
+        Ok(scaled / U256::from(10_000))
+    }
+}
+
+impl Default for TransferFee {
+    fn default() -> Self {
+        Self::none()
+    }
+}
+
+// RedemptionStatus is where a redemption is in its life: it starts out pending with the tokens in
+// escrow, then either ships (and the tokens are burned) or is refunded to the holder.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RedemptionStatus {
+    // Waiting for the saffron to ship
+    Pending,
+    // Shipped under the given tracking number
+    Fulfilled { tracking_number: String },
+    // Cancelled before shipping
+    Cancelled,
+    // Not fulfilled before its deadline
+    Expired,
+}
+
+// Redemption is the ledger's record of one request to exchange tokens for physical saffron
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Redemption {
+    // The id of the redemption, unique within the ledger
+    pub id: u64,
+    // The address that redeemed the tokens
+    pub holder: Address,
+    // The number of tokens redeemed, in base units
+    pub value: U256,
+    // The number of grams of saffron to ship
+    pub grams: U256,
+    // A hash of the shipping recipient, so the mailing address itself stays off the ledger
+    pub recipient_hash: H256,
+    // Where the redemption is in its life
+    pub status: RedemptionStatus,
+    // The block timestamp the redemption was requested at
+    pub requested_at: u64,
+    // The block timestamp after which the holder can be refunded if nothing has shipped
+    pub expires_at: u64,
+    // The block timestamp the redemption was fulfilled, cancelled or expired at
+    pub settled_at: Option<u64>,
+    // The block timestamp a shipping order was first placed for the redemption at, if one was
+    #[serde(default)]
+    pub order_placed_at: Option<u64>,
+}
+
+// VestingSchedule releases an allocation of tokens to a beneficiary over time. Nothing vests
+// before the cliff; after it, tokens vest linearly from the start until the end of the duration.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VestingSchedule {
+    // The id of the schedule, unique within the ledger
+    pub id: u64,
+    // The account the tokens vest to
+    pub beneficiary: Address,
+    // The owner that funded the schedule, who gets unvested tokens back if it is revoked
+    pub funder: Address,
+    // The number of tokens allocated, in base units
+    pub total: U256,
+    // The number of tokens already released to the beneficiary
+    pub released: U256,
+    // The timestamp vesting is measured from
+    pub start: u64,
+    // The timestamp before which nothing vests
+    pub cliff: u64,
+    // The number of seconds from the start until everything has vested
+    pub duration: u64,
+    // Whether an owner may revoke the unvested part of the allocation
+    pub revocable: bool,
+    // The timestamp the schedule was revoked at; vesting stops there
+    pub revoked_at: Option<u64>,
+}
+
+impl VestingSchedule {
+    // Return the number of tokens vested at `timestamp`, whether released or not. Tokens already
+    // released count as vested at any timestamp, so a timestamp earlier than a past release
+    // never reports less than was handed out.
+    pub fn vested_at(&self, timestamp: u64) -> U256 {
+        self.scheduled_at(timestamp).max(self.released)
+    }
+
+    // Return the number of tokens still to vest after `timestamp`. A revoked schedule has
+    // nothing left to vest; its unvested tokens went back to the funder.
+    pub fn unvested_at(&self, timestamp: u64) -> U256 {
+        if self.revoked_at.is_some() {
+            return U256::zero();
+        }
+        self.total.saturating_sub(self.vested_at(timestamp))
+    }
+
+    // Return the number of vested tokens at `timestamp` that have not been released yet
+    pub fn releasable_at(&self, timestamp: u64) -> U256 {
+        self.vested_at(timestamp).saturating_sub(self.released)
+    }
+
+    // Return the number of tokens the schedule itself has vested by `timestamp`, ignoring what
+    // has been released
+    fn scheduled_at(&self, timestamp: u64) -> U256 {
+        // Vesting stops when the schedule is revoked
+        let timestamp = self.revoked_at.map_or(timestamp, |revoked_at| timestamp.min(revoked_at));
+        if timestamp < self.cliff {
+            return U256::zero();
+        }
+        let elapsed = timestamp.saturating_sub(self.start);
+        if elapsed >= self.duration {
+            return self.total;
+        }
+        // The multiplication cannot overflow a real allocation; treat one that does as unvested
+        self.total
+            .checked_mul(U256::from(elapsed))
+            .map_or(U256::zero(), |scaled| scaled / U256::from(self.duration))
+    }
+}
+
+// PriceCallback names the contract function the oracle answers a price request through
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PriceCallback {
+    // The contract to call back
+    pub address: Address,
+    // The 4-byte selector of the function to call
+    pub function_selector: [u8; 4],
+}
+
+// PriceRequest is a request for the price of saffron sent to the oracle and not yet answered
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PriceRequest {
+    // The id the oracle must answer with
+    pub request_id: H256,
+    // The oracle job that produces the price
+    pub job_id: H256,
+    // The LINK paid to the oracle for the answer
+    pub payment: U256,
+    // Where the answer is delivered
+    pub callback: PriceCallback,
+    // The oracle the request was sent to
+    pub oracle: Address,
+    // The address that made the request
+    pub requester: Address,
+    // The block timestamp the request was made at
+    pub requested_at: u64,
+}
+
+// PriceSource says where a price came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PriceSource {
+    // The oracle reported it in answer to a request
+    Oracle { request_id: H256 },
+    // An oracle admin set it by hand
+    Override { by: Address },
+}
+
+// OraclePrice is the latest price of saffron reported by the oracle or set by an admin
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OraclePrice {
+    // The price of one gram of saffron, in wei
+    pub wei_per_gram: U256,
+    // The block timestamp the price was reported at
+    pub updated_at: u64,
+    // The oracle's round the price belongs to
+    pub round_id: u64,
+    // Where the price came from
+    pub source: PriceSource,
+    // How far the price moved from the last trusted price, in basis points
+    pub deviation_bps: u64,
+}
+
+// PriceGuard decides when the oracle price can be trusted for pricing purchases
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PriceGuard {
+    // The number of seconds a price stays usable after it was reported
+    pub heartbeat: u64,
+    // The largest move from the last trusted price accepted, in basis points
+    pub max_deviation_bps: u64,
+}
+
+impl Default for PriceGuard {
+    fn default() -> Self {
+        Self {
+            heartbeat: DEFAULT_PRICE_HEARTBEAT,
+            max_deviation_bps: DEFAULT_MAX_PRICE_DEVIATION_BPS,
+        }
+    }
+}
+
+// BatchSummary totals a batch transfer, as worked out before any of it is applied
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchSummary {
+    // The number of transfers in the batch
+    pub transfers: usize,
+    // The number of distinct recipients, not counting the sender
+    pub recipients: usize,
+    // The number of tokens the sender pays, in base units
+    pub total: U256,
+    // The part of the total taken as transfer fees
+    pub fees: U256,
+    // The sender's balance once the batch has been applied
+    pub sender_balance_after: U256,
+}
+
+// Purchase is the outcome of buying tokens with ETH
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Purchase {
+    // The tokens issued to the buyer, in base units
+    pub tokens: U256,
+    // The price the purchase was made at, in wei per gram
+    pub wei_per_gram: U256,
+    // The part of the payment kept for the tokens, in wei
+    pub cost: U256,
+    // The part of the payment too small to buy another base unit, in wei, which must be
+    // returned to the buyer
+    pub refund: U256,
+}
+
+// ReservesReport compares the tokens in circulation with the saffron held to back them. One
+// whole token (10^decimals base units) is backed by one gram.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReservesReport {
+    // The total supply of the token, in base units
+    pub total_supply: U256,
+    // The total supply expressed in grams, rounded up so a partial gram still needs backing
+    pub supply_grams: U256,
+    // The grams of saffron recorded as in stock
+    pub inventory_grams: U256,
+    // The grams of saffron promised to redemptions that have not shipped yet
+    pub pending_redemption_grams: U256,
+    // True if the stock covers every token in circulation
+    pub fully_backed: bool,
+}
 
 // Saffron is a smart contract that represents a token that can be transferred between addresses
 // and burned (destroyed). It also has an associated oracle address that can be set and retrieved.
@@ -19,12 +686,105 @@ pub struct Saffron {
     total_supply: U256,
     // A map of addresses to balances for each address that holds the token
     balances: BTreeMap<Address, U256>,
+    // A map of owner addresses to the amount each spender is allowed to transfer on their behalf
+    allowances: BTreeMap<Address, BTreeMap<Address, U256>>,
     // The address of the oracle associated with the token
     oracle_address: Address,
+    // The grams of physical saffron in stock backing the token
+    inventory_grams: U256,
+    // Every redemption by id
+    redemptions: BTreeMap<u64, Redemption>,
+    // The id the next redemption will be given
+    next_redemption_id: u64,
+    // The grams of saffron promised to pending redemptions, still counted in inventory_grams
+    pending_redemption_grams: U256,
+    // How many seconds a redemption may stay pending before it can be expired
+    redemption_ttl: u64,
+    // The number of permits each owner has used, which the next permit must be signed with
+    nonces: BTreeMap<Address, U256>,
+    // The id of the chain the token is deployed on, part of the permit signing domain
+    chain_id: U256,
+    // The address of the deployed token contract, part of the permit signing domain
+    contract_address: Address,
+    // The fee taken from transfers between accounts that are not exempt
+    transfer_fee: TransferFee,
+    // Addresses, such as the vendor, whose transfers are never charged a fee
+    fee_exempt: BTreeSet<Address>,
+    // While true, transfers, mints, burns and redemption requests are refused
+    paused: bool,
+    // Addresses that may not send or receive tokens
+    blocklist: BTreeSet<Address>,
+    // Every vesting schedule by id
+    vesting: BTreeMap<u64, VestingSchedule>,
+    // Price requests sent to the oracle and not yet answered, by request id
+    pending_price_requests: BTreeMap<H256, PriceRequest>,
+    // The number of price requests ever made, which makes each request id unique
+    price_request_count: u64,
+    // The latest price reported by the oracle, if it has reported one
+    latest_price: Option<OraclePrice>,
+    // The latest price that passed the deviation check or was set by an admin, which the next
+    // report is compared against
+    trusted_price: Option<U256>,
+    // The staleness and deviation limits on the oracle price
+    price_guard: PriceGuard,
+    // The id the next vesting schedule will be given
+    next_vesting_id: u64,
+    // The wei received from every purchase, after refunds
+    payments_received: U256,
+    // The addresses holding each role
+    roles: BTreeMap<Role, BTreeSet<Address>>,
+    // Every role grant, revocation and renunciation, oldest first
+    role_changes: Vec<RoleChange>,
+    // Every event emitted by the ledger, oldest first
+    events: Vec<LoggedEvent>,
+    // The id of the latest snapshot, or zero if none has been taken
+    current_snapshot_id: u64,
+    // For each account whose balance changed after a snapshot, the balance it had at each such
+    // snapshot, as (snapshot id, balance) pairs in ascending id order. Accounts untouched since
+    // a snapshot have no entry for it; their current balance is their balance at it.
+    account_snapshots: BTreeMap<Address, Vec<(u64, U256)>>,
+    // The same for the total supply
+    total_supply_snapshots: Vec<(u64, U256)>,
+    // The changes made since the ledger was last committed to a store
+    journal: WriteBatch,
+}
+
+// ERC20 is the token interface of the ERC-20 standard, as OpenZeppelin's ERC20 contract exposes
+// it. The state-changing functions take the call's context, since there is no transaction to
+// read msg.sender and the block from, and report success as a bool.
+pub trait ERC20 {
+    // Return the name of the token
+    fn name(&self) -> &str;
+
+    // Return the symbol of the token
+    fn symbol(&self) -> &str;
+
+    // Return the number of decimal places of the token
+    fn decimals(&self) -> u8;
+
+    // Return the total supply of the token
+    fn total_supply(&self) -> U256;
+
+    // Return the balance of `owner`
+    fn balance_of(&self, owner: &Address) -> U256;
+
+    // Move `value` tokens from the caller to `to`
+    fn transfer(&mut self, ctx: &CallContext, to: &Address, value: U256) -> bool;
+
+    // Return the amount `spender` may still transfer on behalf of `owner`
+    fn allowance(&self, owner: &Address, spender: &Address) -> U256;
+
+    // Allow `spender` to transfer up to `value` of the caller's tokens
+    fn approve(&mut self, ctx: &CallContext, spender: &Address, value: U256) -> bool;
+
+    // Move `value` tokens from `from` to `to`, using up the allowance `from` granted the caller
+    fn transfer_from(&mut self, ctx: &CallContext, from: &Address, to: &Address, value: U256) -> bool;
 }
 
 // Implement the ERC20 trait for the Saffron struct. This provides functions for interacting
-// with the token according to the ERC20 standard.
+// with the token according to the ERC20 standard. The state-changing functions delegate to the
+// checked methods on Saffron, so their events are logged in the caller's block, and report the
+// outcome as the bool the standard expects.
 impl ERC20 for Saffron {
     // Return the name of the token
     fn name(&self) -> &str {
@@ -52,183 +812,2087 @@ impl ERC20 for Saffron {
         *self.balances.get(owner).unwrap_or(&U256::zero())
     }
 
-    // Transfer the specified amount of token from the `from` address to the `to` address
-    fn transfer(&mut self, from: &Address, to: &Address, value: U256) -> bool {
-        // Get the balance of the `from` address
-        let from_balance = self.balance_
-
+    // Transfer the specified amount of token from the caller to the `to` address
+    fn transfer(&mut self, ctx: &CallContext, to: &Address, value: U256) -> bool {
+        Saffron::transfer(self, ctx, to, value).is_ok()
+    }
 
-        // Calculate the new balance of the `from` address after the transfer
-        let new_from_balance = from_balance - value;
-        // Calculate the new balance of the `to` address after the transfer
-        let new_to_balance = self.balance_of(to) + value;
+    // Return the amount the `spender` address is still allowed to transfer on behalf of `owner`
+    fn allowance(&self, owner: &Address, spender: &Address) -> U256 {
+        // If the owner has approved the spender, return the remaining allowance. Otherwise, return 0.
+        self.allowances
+            .get(owner)
+            .and_then(|spenders| spenders.get(spender))
+            .copied()
+            .unwrap_or_else(U256::zero)
+    }
 
-        // Update the balances in the map
-        self.balances.insert(*from, new_from_balance);
-        self.balances.insert(*to, new_to_balance);
+    // Allow the `spender` address to transfer up to `value` tokens on behalf of the caller
+    fn approve(&mut self, ctx: &CallContext, spender: &Address, value: U256) -> bool {
+        Saffron::approve(self, ctx, spender, value).is_ok()
+    }
 
-        // Return true to indicate that the transfer was successful
-        true
+    // Transfer `value` tokens from the `from` address to the `to` address on behalf of the caller
+    fn transfer_from(&mut self, ctx: &CallContext, from: &Address, to: &Address, value: U256) -> bool {
+        Saffron::transfer_from(self, ctx, from, to, value).is_ok()
     }
 }
 
-// Implement the Burnable trait for the Saffron struct. This provides a function for burning
-// (destroying) a specified amount of the token.
-impl Burnable for Saffron {
-    // Burn the specified amount of the token
-    fn burn(&mut self, value: U256) -> bool {
-        // Get the address of the caller
-        let sender = web3::transaction::Transaction::sender();
-        // Get the balance of the caller
-        let balance = self.balance_of(&sender);
-        // If the balance is less than the amount being burned, return false
-        if balance < value {
-            return false;
+// Implement functions specific to the Saffron contract
+impl Saffron {
+    // Create a new Saffron contract with the specified name, symbol and decimal places. Nothing
+    // is in circulation or in stock yet: tokens are only issued by minting or selling them
+    // against inventory. The `owner` address starts out holding the owner role and can grant
+    // the others. More than MAX_DECIMALS decimals are refused, since a whole token would not fit
+    // in a U256.
+    pub fn new(name: String, symbol: String, decimals: u8, owner: Address) -> Result<Self, SaffronError> {
+        if decimals > MAX_DECIMALS {
+            return Err(SaffronError::InvalidDecimals { decimals, max: MAX_DECIMALS });
         }
+        let mut roles = BTreeMap::new();
+        roles.insert(Role::Owner, BTreeSet::from([owner]));
+        // The first owner is stored with the first commit like any later grant
+        let mut journal = WriteBatch::default();
+        journal.push(WriteOp::Role { role: Role::Owner, account: owner, held: true });
+        Ok(Self {
+            name,
+            symbol,
+            decimals,
+            total_supply: U256::zero(),
+            balances: BTreeMap::new(),
+            allowances: BTreeMap::new(),
+            oracle_address: Address::zero(),
+            inventory_grams: U256::zero(),
+            redemptions: BTreeMap::new(),
+            next_redemption_id: 1,
+            pending_redemption_grams: U256::zero(),
+            redemption_ttl: DEFAULT_REDEMPTION_TTL,
+            nonces: BTreeMap::new(),
+            chain_id: U256::one(),
+            contract_address: Address::zero(),
+            transfer_fee: TransferFee::none(),
+            fee_exempt: BTreeSet::new(),
+            paused: false,
+            blocklist: BTreeSet::new(),
+            vesting: BTreeMap::new(),
+            pending_price_requests: BTreeMap::new(),
+            price_request_count: 0,
+            latest_price: None,
+            trusted_price: None,
+            price_guard: PriceGuard::default(),
+            next_vesting_id: 1,
+            payments_received: U256::zero(),
+            roles,
+            role_changes: Vec::new(),
+            events: Vec::new(),
+            current_snapshot_id: 0,
+            account_snapshots: BTreeMap::new(),
+            total_supply_snapshots: Vec::new(),
+            journal,
+        })
+    }
 
-        // Calculate the new balance of the caller after the burn
-        let new_balance = balance - value;
-        // Update the balance in the map
-        self.balances.insert(sender, new_balance);
-        // Decrease the total supply of the token by the amount being burned
-        self.total_supply -= value;
+    // Rebuild a ledger from the state kept in `store`, settings such as the transfer fee, the
+    // pause switch, the blocklist and the oracle's address, guard and prices included. Roles are
+    // kept in the store; `owner` is only given the owner role if the store has never recorded
+    // any roles, i.e. the first time the ledger is opened, and None leaves such a ledger
+    // without one.
+    pub fn restore(name: String, symbol: String, decimals: u8, owner: Option<Address>, store: &dyn LedgerStore) -> Result<Self, SaffronError> {
+        let stored = store.load()?;
+        let mut saffron = Self::new(name, symbol, decimals, owner.unwrap_or_default())?;
+        // A store with a role change on record has had its roles written, even if every role
+        // has since been renounced
+        let roles_recorded = !stored.roles.is_empty() || !stored.role_changes.is_empty();
+        if roles_recorded || owner.is_none() {
+            // The stored roles stand, so the grant `new` journaled is dropped
+            saffron.roles = stored.roles;
+            saffron.journal.clear();
+        }
+        saffron.role_changes = stored.role_changes;
+        saffron.balances = stored.balances;
+        saffron.allowances = stored.allowances;
+        saffron.redemptions = stored.redemptions;
+        saffron.events = stored.events;
+        saffron.total_supply = stored.supply.total_supply;
+        saffron.inventory_grams = stored.supply.inventory_grams;
+        saffron.pending_redemption_grams = stored.supply.pending_redemption_grams;
+        // An empty store has never handed out a redemption id
+        saffron.next_redemption_id = stored.supply.next_redemption_id.max(1);
+        saffron.current_snapshot_id = stored.supply.current_snapshot_id;
+        saffron.account_snapshots = stored.account_snapshots;
+        saffron.total_supply_snapshots = stored.total_supply_snapshots;
+        saffron.nonces = stored.nonces;
+        saffron.vesting = stored.vesting;
+        saffron.next_vesting_id = stored.supply.next_vesting_id.max(1);
+        saffron.payments_received = stored.supply.payments_received;
+        saffron.redemption_ttl = stored.supply.redemption_ttl;
+        saffron.chain_id = stored.supply.chain_id;
+        saffron.contract_address = stored.supply.contract_address;
+        saffron.transfer_fee = stored.supply.transfer_fee;
+        saffron.fee_exempt = stored.fee_exempt;
+        saffron.paused = stored.supply.paused;
+        saffron.blocklist = stored.blocklist;
+        saffron.oracle_address = stored.supply.oracle_address;
+        saffron.pending_price_requests = stored.price_requests;
+        saffron.price_request_count = stored.supply.price_request_count;
+        saffron.latest_price = stored.supply.latest_price;
+        saffron.trusted_price = stored.supply.trusted_price;
+        saffron.price_guard = stored.supply.price_guard;
+        Ok(saffron)
+    }
 
-        // Return true to indicate that the burn was successful
-        true
+    // Write every change made since the last commit to `store` as one atomic batch. Call this
+    // after each operation so that nothing is stored half-applied; if the write fails the
+    // changes stay in the journal and are retried by the next commit.
+    pub fn commit(&mut self, store: &dyn LedgerStore) -> Result<(), StorageError> {
+        // The running totals and settings are written with every batch, since changes such as
+        // restocking touch nothing else
+        let mut batch = self.journal.clone();
+        batch.push(WriteOp::Supply(Box::new(SupplyState {
+            total_supply: self.total_supply,
+            inventory_grams: self.inventory_grams,
+            pending_redemption_grams: self.pending_redemption_grams,
+            next_redemption_id: self.next_redemption_id,
+            next_vesting_id: self.next_vesting_id,
+            current_snapshot_id: self.current_snapshot_id,
+            payments_received: self.payments_received,
+            redemption_ttl: self.redemption_ttl,
+            chain_id: self.chain_id,
+            contract_address: self.contract_address,
+            transfer_fee: self.transfer_fee,
+            paused: self.paused,
+            oracle_address: self.oracle_address,
+            price_request_count: self.price_request_count,
+            latest_price: self.latest_price,
+            trusted_price: self.trusted_price,
+            price_guard: self.price_guard,
+        })));
+        store.write(&batch)?;
+        self.journal.clear();
+        Ok(())
     }
-}
 
-// Implement the Chainlink trait for the Saffron struct. This provides functions for getting
-// and setting the oracle address associated with the token.
-impl Chainlink for Saffron {
     // Return the oracle address associated with the token
-    fn oracle_address(&self) -> Address {
+    pub fn oracle_address(&self) -> Address {
         self.oracle_address
     }
 
-    // Set the oracle address associated with the token
-    fn set_oracle_address(&mut self, oracle_address: Address) {
+    // Set the oracle address associated with the token. Only an oracle admin may do this, since
+    // whoever controls the oracle controls the price.
+    pub fn set_oracle_address(&mut self, ctx: &CallContext, oracle_address: Address) -> Result<(), SaffronError> {
+        self.require_role(ctx, Role::OracleAdmin)?;
         self.oracle_address = oracle_address;
+        Ok(())
     }
-}
-//This implementation provides two functions: oracle_address and set_oracle_address. The oracle_address function returns the address of the oracle associated with the Saffron contract, and the set_oracle_address function allows the caller to set the oracle address for the contract. The oracle address is stored in the oracle_address field of the Saffron struct.
 
+    // Return the latest price of saffron reported by the oracle, if there is one
+    pub fn latest_price(&self) -> Option<OraclePrice> {
+        self.latest_price
+    }
 
-    // Return the oracle address associated with the token
-    fn oracle_address(&self) -> Address {
-        self.oracle_address
+    // Return the limits the oracle price must meet to be used
+    pub fn price_guard(&self) -> PriceGuard {
+        self.price_guard
     }
 
-    // Set the oracle address associated with the token
-    fn set_oracle_address(&mut self, oracle_address: Address) {
-        self.oracle_address = oracle_address;
+    // Set the limits the oracle price must meet to be used. Only an oracle admin may do this.
+    pub fn set_price_guard(&mut self, ctx: &CallContext, guard: PriceGuard) -> Result<(), SaffronError> {
+        self.require_role(ctx, Role::OracleAdmin)?;
+        self.price_guard = guard;
+        Ok(())
     }
-}
 
-// Bring the functions from the SafeMath contract into the scope of the Saffron struct
-use SafeMath for Saffron;
+    // Return the price of one gram of saffron in wei to use for a purchase at the caller's
+    // timestamp. Fails if the oracle has not reported, if its latest price is older than the
+    // heartbeat, or if it moved further from the last trusted price than allowed.
+    pub fn current_price(&self, ctx: &CallContext) -> Result<U256, SaffronError> {
+        let price = self.latest_price.ok_or(SaffronError::NoPrice)?;
+        let heartbeat = self.price_guard.heartbeat;
+        if ctx.timestamp.saturating_sub(price.updated_at) > heartbeat {
+            return Err(SaffronError::StalePrice { updated_at: price.updated_at, now: ctx.timestamp, heartbeat });
+        }
+        if self.trusted_price != Some(price.wei_per_gram) {
+            return Err(SaffronError::PriceDeviation {
+                reference: self.trusted_price.unwrap_or_default(),
+                reported: price.wei_per_gram,
+                deviation_bps: price.deviation_bps,
+                max_bps: self.price_guard.max_deviation_bps,
+            });
+        }
+        Ok(price.wei_per_gram)
+    }
 
-// Implement functions specific to the Saffron contract
-impl Saffron {
-    // Create a new Saffron contract with the specified name, symbol, decimal places, and total
-    // supply
-    pub fn new(name: String, symbol: String, decimals: u8, total_supply: U256) -> Self {
-        Self {
-            name,
-            symbol,
-            decimals,
-            total_supply,
-            balances: BTreeMap::new(),
-            oracle_address: Address::zero(),
+    // Set the price of one gram of saffron by hand, e.g. while the oracle is down or to confirm
+    // a large move it reported. The price is trusted as of the caller's timestamp and becomes
+    // the reference for later oracle reports. Only an oracle admin may override the price.
+    pub fn override_price(&mut self, ctx: &CallContext, wei_per_gram: U256) -> Result<(), SaffronError> {
+        self.require_role(ctx, Role::OracleAdmin)?;
+        if wei_per_gram.is_zero() {
+            return Err(SaffronError::InvalidAmount);
         }
+        let round_id = self.latest_price.map_or(0, |price| price.round_id);
+        self.trusted_price = Some(wei_per_gram);
+        self.latest_price = Some(OraclePrice {
+            wei_per_gram,
+            updated_at: ctx.timestamp,
+            round_id,
+            source: PriceSource::Override { by: ctx.sender },
+            deviation_bps: 0,
+        });
+        Ok(())
     }
 
-    // Mint (create) a specified amount of the token and assign it to the caller
-    pub fn mint(&mut self, value: U256) {
-        // Get the address of the caller
-        let sender = web3::transaction::Transaction::sender();
-        // Get the current balance of the caller
-        let current_balance = self.balance_of(&sender);
-        // Calculate the new balance of the caller after the mint
-        let new_balance = current_balance + value;
-        // Update the balance in the map
-        self.balances.insert(sender, new_balance);
-        // Increase the total supply of the token by the amount being minted
-        self.total_supply += value;
-    }
-// Redeem (exchange for something else) a specified amount of the token from the caller
-    pub fn redeem(&mut self, value: U256) -> bool {
-        // Get the address of the caller
-        let sender = web3::transaction::Transaction::sender();
-        // Get the balance of the caller
-        let balance = self.balance_of(&sender);
-        // If the balance is less than the amount being redeemed, return false
-        if balance < value {
-            return false;
-        }
-
-        // Calculate the new balance of the caller after the redeem
-        let new_balance = balance - value;
-        // Update the balance in the map
-        self.balances.insert(sender, new_balance);
-        // Decrease the total supply of the token by the amount being redeemed
-        self.total_supply -= value;
-
-        // Return true to indicate that the redeem was successful
-        true
-    }
-//This function allows the caller to redeem a specified amount of the Saffron token. It does this by first checking the balance of the caller to ensure that there are enough tokens to redeem. If the balance is insufficient, the function returns false. If the balance is sufficient, the function calculates the new balance of the caller after the redeem, updates the balance in the map, and decreases the total supply of the token by the amount being redeemed. Finally, the function returns true to indicate that the redeem was successful.
-
-
-           // Get the fulfillment order details for the specified item and quantity
-    pub fn get_fulfillment_order_details(
-        &self,
-        item: String,
-        quantity: U256,
-    ) -> Result<(String, String, String), String> {
-        // Create an AWS client for interacting with the Inventory Management service
-        let client = Client::new(
-            StaticProvider::new_minimal("ACCESS_KEY", "SECRET_KEY"),
-            Region::Custom {
-                name: "REGION".to_string(),
-                endpoint: "ENDPOINT".to_string(),
-            },
-        );
+    // Return the price requests waiting for an answer from the oracle
+    pub fn pending_price_requests(&self) -> impl Iterator<Item = &PriceRequest> {
+        self.pending_price_requests.values()
+    }
+
+    // Ask the oracle for the current price of a gram of saffron by running `job_id`, paying it
+    // `payment` and having it answer through `callback`. The oracle node picks the request up
+    // from the PriceRequested event. Only an oracle admin may request prices. Returns the id the
+    // oracle must answer with.
+    pub fn request_price(
+        &mut self,
+        ctx: &CallContext,
+        job_id: H256,
+        payment: U256,
+        callback: PriceCallback,
+    ) -> Result<H256, SaffronError> {
+        self.require_role(ctx, Role::OracleAdmin)?;
+        if self.oracle_address.is_zero() {
+            return Err(SaffronError::OracleNotSet);
+        }
+
+        // Derive the request id from this contract and a running count, as Chainlink does
+        let mut seed = self.contract_address.as_bytes().to_vec();
+        seed.extend_from_slice(&self.price_request_count.to_be_bytes());
+        let request_id = H256(keccak256(&seed));
+        self.price_request_count += 1;
+
+        let oracle = self.oracle_address;
+        let request = PriceRequest {
+            request_id,
+            job_id,
+            payment,
+            callback,
+            oracle,
+            requester: ctx.sender,
+            requested_at: ctx.timestamp,
+        };
+        self.journal.push(WriteOp::PriceRequest { request_id, request: Some(request.clone()) });
+        self.pending_price_requests.insert(request_id, request);
+        self.emit(ctx, SaffronEvent::PriceRequested { request_id, oracle, job_id, payment });
+        Ok(request_id)
+    }
+
+    // Accept the oracle's answer to price request `request_id`: one gram of saffron costs
+    // `wei_per_gram` in round `round_id`. Only the oracle the request was sent to may answer,
+    // and each request can be answered once.
+    pub fn fulfill_price(
+        &mut self,
+        ctx: &CallContext,
+        request_id: H256,
+        wei_per_gram: U256,
+        round_id: u64,
+    ) -> Result<(), SaffronError> {
+        let request = self
+            .pending_price_requests
+            .get(&request_id)
+            .ok_or(SaffronError::UnknownPriceRequest(request_id))?;
+        if ctx.sender != request.oracle || ctx.sender != self.oracle_address {
+            return Err(SaffronError::Unauthorized);
+        }
+
+        // Measure the move from the last trusted price. A price that moved too far is kept, so
+        // admins can see it, but is not trusted until an admin overrides it.
+        self.pending_price_requests.remove(&request_id);
+        self.journal.push(WriteOp::PriceRequest { request_id, request: None });
+        let deviation_bps = self.trusted_price.map_or(0, |trusted| deviation_bps(trusted, wei_per_gram));
+        if deviation_bps <= self.price_guard.max_deviation_bps {
+            self.trusted_price = Some(wei_per_gram);
+        }
+        self.latest_price = Some(OraclePrice {
+            wei_per_gram,
+            updated_at: ctx.timestamp,
+            round_id,
+            source: PriceSource::Oracle { request_id },
+            deviation_bps,
+        });
+        self.emit(ctx, SaffronEvent::PriceFulfilled { request_id, oracle: ctx.sender, wei_per_gram, round_id });
+        Ok(())
+    }
+
+    // Return the grams of physical saffron in stock backing the token
+    pub fn inventory_grams(&self) -> U256 {
+        self.inventory_grams
+    }
+
+    // Return the most the total supply may grow to: one whole token for every gram in stock
+    pub fn supply_cap(&self) -> U256 {
+        // A cap too large to represent is no cap at all
+        self.inventory_grams.checked_mul(self.one_gram()).unwrap_or_else(U256::max_value)
+    }
+
+    // Record `grams` of newly stocked saffron. Only an owner may change the inventory.
+    pub fn add_inventory(&mut self, ctx: &CallContext, grams: U256) -> Result<(), SaffronError> {
+        self.require_role(ctx, Role::Owner)?;
+        self.inventory_grams = self.inventory_grams.checked_add(grams).ok_or(SaffronError::Overflow)?;
+        Ok(())
+    }
+
+    // Record `grams` of saffron leaving stock other than through redemption, e.g. spoilage.
+    // Only an owner may change the inventory, and never below what backs the tokens in
+    // circulation.
+    pub fn remove_inventory(&mut self, ctx: &CallContext, grams: U256) -> Result<(), SaffronError> {
+        self.require_role(ctx, Role::Owner)?;
+        // Grams promised to pending redemptions cannot be written off; their tokens are still in
+        // circulation, so they are covered by the supply
+        let backing = self.supply_grams().max(self.pending_redemption_grams);
+        let available = self.inventory_grams.saturating_sub(backing);
+        if available < grams {
+            return Err(SaffronError::InsufficientInventory { needed: grams, available });
+        }
+        self.inventory_grams -= grams;
+        Ok(())
+    }
+
+    // Take a snapshot of every balance and the total supply and return its id. Only an owner
+    // may take snapshots. Nothing is copied now; balances are recorded as they next change.
+    pub fn take_snapshot(&mut self, ctx: &CallContext) -> Result<u64, SaffronError> {
+        self.require_role(ctx, Role::Owner)?;
+        self.current_snapshot_id += 1;
+        let id = self.current_snapshot_id;
+        self.emit(ctx, SaffronEvent::Snapshot { id });
+        Ok(id)
+    }
+
+    // Return the balance `account` had when snapshot `snapshot_id` was taken
+    pub fn balance_of_at(&self, account: &Address, snapshot_id: u64) -> Result<U256, SaffronError> {
+        let recorded = self.account_snapshots.get(account).map_or(&[][..], |checkpoints| &checkpoints[..]);
+        Ok(self.value_at(recorded, snapshot_id)?.unwrap_or_else(|| self.balance_of(account)))
+    }
+
+    // Return the total supply when snapshot `snapshot_id` was taken
+    pub fn total_supply_at(&self, snapshot_id: u64) -> Result<U256, SaffronError> {
+        Ok(self.value_at(&self.total_supply_snapshots, snapshot_id)?.unwrap_or(self.total_supply))
+    }
+
+    // Look up the value recorded for snapshot `snapshot_id` in `checkpoints`. The first
+    // checkpoint at or after the snapshot holds the value as it was then; if there is none the
+    // value has not changed since, and None is returned.
+    fn value_at(&self, checkpoints: &[(u64, U256)], snapshot_id: u64) -> Result<Option<U256>, SaffronError> {
+        if snapshot_id == 0 || snapshot_id > self.current_snapshot_id {
+            return Err(SaffronError::UnknownSnapshot(snapshot_id));
+        }
+        let index = checkpoints.partition_point(|(id, _)| *id < snapshot_id);
+        Ok(checkpoints.get(index).map(|(_, value)| *value))
+    }
+
+    // Report how the tokens in circulation compare with the saffron in stock
+    pub fn reserves_report(&self) -> ReservesReport {
+        let supply_grams = self.supply_grams();
+        ReservesReport {
+            total_supply: self.total_supply,
+            supply_grams,
+            inventory_grams: self.inventory_grams,
+            pending_redemption_grams: self.pending_redemption_grams,
+            fully_backed: self.inventory_grams >= supply_grams,
+        }
+    }
+
+    // Return true if `account` holds `role`
+    pub fn has_role(&self, role: Role, account: &Address) -> bool {
+        self.roles.get(&role).is_some_and(|holders| holders.contains(account))
+    }
+
+    // Return every logged event, oldest first, so off-chain services can replay the ledger
+    pub fn event_log(&self) -> &[LoggedEvent] {
+        &self.events
+    }
+
+    // Return the logged events that pass `filter`, oldest first
+    pub fn events<'a>(&'a self, filter: &'a EventFilter) -> impl Iterator<Item = &'a LoggedEvent> + 'a {
+        self.events.iter().filter(move |logged| filter.matches(logged))
+    }
+
+    // Return the audit trail of role changes, oldest first
+    pub fn role_changes(&self) -> &[RoleChange] {
+        &self.role_changes
+    }
+
+    // Give `role` to `account`. Only an owner may grant roles.
+    pub fn grant_role(&mut self, ctx: &CallContext, role: Role, account: &Address) -> Result<(), SaffronError> {
+        self.require_role(ctx, Role::Owner)?;
+        if account.is_zero() {
+            return Err(SaffronError::ZeroAddress);
+        }
 
-        // Create a fulfillment order request for the specified item and quantity
-        let request = CreateFulfillmentOrderRequest {
-            displayable_order_id: Some("SAFFRON_ORDER_1".to_string()),
-            displayable_order_date_time: Some("2022-01-01T00:00:00Z".to_string()),
-            displayable_order_comment: Some("Order for Saffron token redemption".to_string()),
-            items: vec![(item, quantity)],
-            shipping_speed_category: Some("Standard".to_string()),
-            ..Default::default()
+        // Only record a change if the account did not already hold the role
+        if self.roles.entry(role).or_default().insert(*account) {
+            self.journal.push(WriteOp::Role { role, account: *account, held: true });
+            self.record_role_change(ctx, role, account, RoleAction::Granted);
+        }
+        Ok(())
+    }
+
+    // Take `role` away from `account`. Only an owner may revoke roles.
+    pub fn revoke_role(&mut self, ctx: &CallContext, role: Role, account: &Address) -> Result<(), SaffronError> {
+        self.require_role(ctx, Role::Owner)?;
+        if self.remove_role(role, account) {
+            self.record_role_change(ctx, role, account, RoleAction::Revoked);
+        }
+        Ok(())
+    }
+
+    // Give up `role` held by the caller
+    pub fn renounce_role(&mut self, ctx: &CallContext, role: Role) -> Result<(), SaffronError> {
+        if self.remove_role(role, &ctx.sender) {
+            self.record_role_change(ctx, role, &ctx.sender, RoleAction::Renounced);
+        }
+        Ok(())
+    }
+
+    // Return true if token movement is paused
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // Pause transfers, mints, burns and redemption requests, e.g. during a fulfilment partner
+    // outage. Pending redemptions can still be fulfilled or refunded. Only an owner may pause.
+    pub fn pause(&mut self, ctx: &CallContext) -> Result<(), SaffronError> {
+        self.require_role(ctx, Role::Owner)?;
+        if !self.paused {
+            self.paused = true;
+            self.emit(ctx, SaffronEvent::Paused { by: ctx.sender });
+        }
+        Ok(())
+    }
+
+    // Resume token movement. Only an owner may unpause.
+    pub fn unpause(&mut self, ctx: &CallContext) -> Result<(), SaffronError> {
+        self.require_role(ctx, Role::Owner)?;
+        if self.paused {
+            self.paused = false;
+            self.emit(ctx, SaffronEvent::Unpaused { by: ctx.sender });
+        }
+        Ok(())
+    }
+
+    // Return true if `account` is on the blocklist
+    pub fn is_blocked(&self, account: &Address) -> bool {
+        self.blocklist.contains(account)
+    }
+
+    // Stop `account` from sending or receiving tokens, e.g. because its key was compromised.
+    // Only an owner may block accounts.
+    pub fn block_account(&mut self, ctx: &CallContext, account: &Address) -> Result<(), SaffronError> {
+        self.require_role(ctx, Role::Owner)?;
+        if self.blocklist.insert(*account) {
+            self.journal.push(WriteOp::Blocked { account: *account, blocked: true });
+            self.emit(ctx, SaffronEvent::Blocked { account: *account, by: ctx.sender });
+        }
+        Ok(())
+    }
+
+    // Let a blocked account send and receive tokens again. Only an owner may unblock accounts.
+    pub fn unblock_account(&mut self, ctx: &CallContext, account: &Address) -> Result<(), SaffronError> {
+        self.require_role(ctx, Role::Owner)?;
+        if self.blocklist.remove(account) {
+            self.journal.push(WriteOp::Blocked { account: *account, blocked: false });
+            self.emit(ctx, SaffronEvent::Unblocked { account: *account, by: ctx.sender });
+        }
+        Ok(())
+    }
+
+    // Transfer the specified amount of token from the caller to the `to` address
+    pub fn transfer(&mut self, ctx: &CallContext, to: &Address, value: U256) -> Result<(), SaffronError> {
+        self.require_not_paused()?;
+        self.move_balance(ctx, &ctx.sender, to, value)
+    }
+
+    // Check that every transfer in `transfers` from the caller would go through, without
+    // applying any of them, and return the batch's totals. Used to dry-run a batch.
+    pub fn check_batch_transfer(&self, ctx: &CallContext, transfers: &[(Address, U256)]) -> Result<BatchSummary, SaffronError> {
+        self.require_not_paused()?;
+        self.require_not_blocked(&ctx.sender)?;
+
+        // Add up what is sent, the fees on it and what each account ends up receiving
+        let mut total = U256::zero();
+        let mut fees = U256::zero();
+        let mut credits: BTreeMap<Address, U256> = BTreeMap::new();
+        for (to, value) in transfers {
+            if to.is_zero() {
+                return Err(SaffronError::ZeroAddress);
+            }
+            self.require_not_blocked(to)?;
+            // A transfer to the sender moves nothing and is left out of the totals, but the
+            // balance still has to cover it at its place in the batch
+            if *to == ctx.sender {
+                self.checked_debit(&ctx.sender, total.checked_add(*value).ok_or(SaffronError::Overflow)?)?;
+                continue;
+            }
+            total = total.checked_add(*value).ok_or(SaffronError::Overflow)?;
+            let fee = if self.is_fee_exempt(&ctx.sender) || self.is_fee_exempt(to) {
+                U256::zero()
+            } else {
+                self.transfer_fee.fee_for(*value)?
+            };
+            fees = fees.checked_add(fee).ok_or(SaffronError::Overflow)?;
+            let credit = credits.entry(*to).or_default();
+            *credit = credit.checked_add(*value - fee).ok_or(SaffronError::Overflow)?;
+        }
+        if let FeeDestination::Treasury(treasury) = self.transfer_fee.destination {
+            let credit = credits.entry(treasury).or_default();
+            *credit = credit.checked_add(fees).ok_or(SaffronError::Overflow)?;
+        }
+
+        // The sender must cover the whole batch and no recipient's balance may overflow
+        let sender_balance_after = self.checked_debit(&ctx.sender, total)?;
+        for (account, credit) in &credits {
+            self.checked_credit(account, *credit)?;
+        }
+
+        Ok(BatchSummary {
+            transfers: transfers.len(),
+            recipients: transfers.iter().map(|(to, _)| to).filter(|to| **to != ctx.sender).collect::<BTreeSet<_>>().len(),
+            total,
+            fees,
+            sender_balance_after,
+        })
+    }
+
+    // Send each (recipient, amount) pair in `transfers` from the caller. Either every transfer
+    // is applied or, if any of them would fail, none is. Returns the batch's totals.
+    pub fn batch_transfer(&mut self, ctx: &CallContext, transfers: &[(Address, U256)]) -> Result<BatchSummary, SaffronError> {
+        // Once the whole batch has been checked, none of the transfers below can fail
+        let summary = self.check_batch_transfer(ctx, transfers)?;
+        for (to, value) in transfers {
+            self.move_balance(ctx, &ctx.sender, to, *value)?;
+        }
+        Ok(summary)
+    }
+
+    // Move `value` tokens from the `from` address to the `to` address and log the transfer
+    fn move_balance(&mut self, ctx: &CallContext, from: &Address, to: &Address, value: U256) -> Result<(), SaffronError> {
+        // Tokens cannot be sent to the zero address; that is what burn is for
+        if to.is_zero() {
+            return Err(SaffronError::ZeroAddress);
+        }
+        // Blocked accounts can neither send nor receive
+        self.require_not_blocked(from)?;
+        self.require_not_blocked(to)?;
+        // Check that the `from` address holds enough tokens before touching any balance
+        let new_from_balance = self.checked_debit(from, value)?;
+        // A transfer to yourself changes no balance once the balance check has passed
+        if from == to {
+            self.emit(ctx, SaffronEvent::Transfer { from: *from, to: *to, value });
+            return Ok(());
+        }
+
+        // Work out the fee and what the recipient receives after it
+        let fee = if self.is_fee_exempt(from) || self.is_fee_exempt(to) {
+            U256::zero()
+        } else {
+            self.transfer_fee.fee_for(value)?
         };
+        let received = value - fee;
+        // Calculate the new balance of the `to` address after the transfer
+        let new_to_balance = self.checked_credit(to, received)?;
+        // Work out where the fee ends up. The treasury is always exempt, so it is neither the
+        // sender nor the recipient here.
+        let mut new_total_supply = self.total_supply;
+        let mut treasury_credit = None;
+        if !fee.is_zero() {
+            match self.transfer_fee.destination {
+                FeeDestination::Burn => {
+                    new_total_supply = self.total_supply.checked_sub(fee).ok_or(SaffronError::Overflow)?;
+                }
+                FeeDestination::Treasury(treasury) => {
+                    treasury_credit = Some((treasury, self.checked_credit(&treasury, fee)?));
+                }
+            }
+        }
+
+        // Every new value is known to be valid, so update the ledger
+        self.put_balance(*from, new_from_balance);
+        self.put_balance(*to, new_to_balance);
+        self.emit(ctx, SaffronEvent::Transfer { from: *from, to: *to, value: received });
+        if let Some((treasury, new_treasury_balance)) = treasury_credit {
+            self.put_balance(treasury, new_treasury_balance);
+            self.emit(ctx, SaffronEvent::Transfer { from: *from, to: treasury, value: fee });
+        } else if !fee.is_zero() {
+            self.set_total_supply(new_total_supply);
+            self.emit(ctx, SaffronEvent::Burn { from: *from, value: fee });
+        }
+        Ok(())
+    }
+
+    // Return the fee currently taken from transfers
+    pub fn transfer_fee(&self) -> TransferFee {
+        self.transfer_fee
+    }
 
-        // Send the request to create the fulfillment order
-        let result = client.create_fulfillment_order(request).sync();
-
-        // If the request was successful, return the order ID, tracking number, and carrier code.
-        // Otherwise, return an error message.
-        match result {
-            Ok(order) => Ok((
-                order.fulfillment_order_id,
-                order.tracking_number.unwrap(),
-                order.carrier_code.unwrap(),
-            )),
-            Err(err) => Err(format!("Error creating fulfillment order: {}", err)),
-        }
-    }
-}
-/
-Copy code
-[package]
-name = "saffron"
-version = "0.1.0"
-authors = ["Your Name <you@example.com>"]
-
-[dependencies]
-openzeppelin-solidity = "2.5.2"
-web3 = "0.21.0"
-aws-sdk = "0.59.0"
-*/
+    // Set the fee taken from transfers. Only an owner may change it, and never above
+    // MAX_TRANSFER_FEE_BPS.
+    pub fn set_transfer_fee(&mut self, ctx: &CallContext, fee: TransferFee) -> Result<(), SaffronError> {
+        self.require_role(ctx, Role::Owner)?;
+        if fee.rate_bps > MAX_TRANSFER_FEE_BPS {
+            return Err(SaffronError::FeeRateTooHigh { rate_bps: fee.rate_bps, max_bps: MAX_TRANSFER_FEE_BPS });
+        }
+        if let FeeDestination::Treasury(treasury) = fee.destination {
+            if treasury.is_zero() {
+                return Err(SaffronError::ZeroAddress);
+            }
+        }
+        self.transfer_fee = fee;
+        Ok(())
+    }
+
+    // Exempt `account` from transfer fees, or remove its exemption. Only an owner may do this.
+    pub fn set_fee_exempt(&mut self, ctx: &CallContext, account: &Address, exempt: bool) -> Result<(), SaffronError> {
+        self.require_role(ctx, Role::Owner)?;
+        if exempt {
+            self.fee_exempt.insert(*account);
+        } else {
+            self.fee_exempt.remove(account);
+        }
+        self.journal.push(WriteOp::FeeExempt { account: *account, exempt });
+        Ok(())
+    }
+
+    // Return true if transfers to or from `account` are never charged a fee. Escrow, vesting and
+    // the treasury are always exempt, so redemptions, vesting and fee collection are never taxed.
+    pub fn is_fee_exempt(&self, account: &Address) -> bool {
+        *account == ESCROW_ADDRESS
+            || *account == VESTING_ADDRESS
+            || self.transfer_fee.destination == FeeDestination::Treasury(*account)
+            || self.fee_exempt.contains(account)
+    }
+
+    // Allow the `spender` address to transfer up to `value` tokens on behalf of the caller.
+    // As in the ERC20 standard, this replaces any previous allowance rather than adding to it.
+    pub fn approve(&mut self, ctx: &CallContext, spender: &Address, value: U256) -> Result<(), SaffronError> {
+        // The zero address can neither grant nor receive an allowance
+        if ctx.sender.is_zero() || spender.is_zero() {
+            return Err(SaffronError::ZeroAddress);
+        }
+
+        // Record the new allowance for the owner/spender pair
+        self.set_allowance(&ctx.sender, spender, value);
+        self.emit(ctx, SaffronEvent::Approval { owner: ctx.sender, spender: *spender, value });
+        Ok(())
+    }
+
+    // Return the nonce the next permit signed by `owner` must carry
+    pub fn nonces(&self, owner: &Address) -> U256 {
+        self.nonces.get(owner).copied().unwrap_or_else(U256::zero)
+    }
+
+    // Return the EIP-712 domain permits for this token are signed in
+    pub fn permit_domain(&self) -> PermitDomain {
+        PermitDomain {
+            name: self.name.clone(),
+            chain_id: self.chain_id,
+            verifying_contract: self.contract_address,
+        }
+    }
+
+    // Set the chain id and contract address permits are signed for. Only an owner may change
+    // them, and doing so invalidates every permit signed for the old values.
+    pub fn set_permit_domain(&mut self, ctx: &CallContext, chain_id: U256, contract_address: Address) -> Result<(), SaffronError> {
+        self.require_role(ctx, Role::Owner)?;
+        self.chain_id = chain_id;
+        self.contract_address = contract_address;
+        Ok(())
+    }
+
+    // Approve `spender` for `value` tokens of `owner` using a signature instead of a call from
+    // the owner (EIP-2612), so that holders without ETH for gas can approve through a relayer.
+    // Anyone may submit the permit. It must be signed by `owner` over their current nonce and
+    // submitted no later than `deadline`; each permit can be used once.
+    #[allow(clippy::too_many_arguments)]
+    pub fn permit(
+        &mut self,
+        ctx: &CallContext,
+        owner: &Address,
+        spender: &Address,
+        value: U256,
+        deadline: U256,
+        v: u8,
+        r: H256,
+        s: H256,
+    ) -> Result<(), SaffronError> {
+        if U256::from(ctx.timestamp) > deadline {
+            return Err(SaffronError::PermitExpired { deadline, now: ctx.timestamp });
+        }
+        if owner.is_zero() || spender.is_zero() {
+            return Err(SaffronError::ZeroAddress);
+        }
+
+        // Check the signature over the permit with the owner's current nonce
+        let nonce = self.nonces(owner);
+        let permit = Permit { owner: *owner, spender: *spender, value, nonce, deadline };
+        let digest = permit.digest(&self.permit_domain());
+        if recover_signer(&digest, v, &r, &s) != Some(*owner) {
+            return Err(SaffronError::InvalidSignature);
+        }
+
+        // Use up the nonce so the permit cannot be replayed, then record the allowance
+        let next_nonce = nonce.checked_add(U256::one()).ok_or(SaffronError::Overflow)?;
+        self.nonces.insert(*owner, next_nonce);
+        self.journal.push(WriteOp::Nonce { owner: *owner, nonce: next_nonce });
+        self.set_allowance(owner, spender, value);
+        self.emit(ctx, SaffronEvent::Approval { owner: *owner, spender: *spender, value });
+        Ok(())
+    }
+
+    // Transfer `value` tokens from the `from` address to the `to` address on behalf of the caller,
+    // using up part of the allowance `from` has granted to the caller
+    pub fn transfer_from(
+        &mut self,
+        ctx: &CallContext,
+        from: &Address,
+        to: &Address,
+        value: U256,
+    ) -> Result<(), SaffronError> {
+        self.require_not_paused()?;
+        // A blocked spender cannot move tokens on anyone's behalf
+        let spender = &ctx.sender;
+        self.require_not_blocked(spender)?;
+        // Get the allowance the `from` address has granted to the caller
+        let allowance = self.allowance(from, spender);
+        // If the spender is not allowed to move this many tokens, refuse the transfer
+        let remaining = allowance.checked_sub(value).ok_or(SaffronError::InsufficientAllowance {
+            needed: value,
+            available: allowance,
+        })?;
+
+        // Move the tokens and only use up the allowance if the transfer went through
+        self.move_balance(ctx, from, to, value)?;
+        self.set_allowance(from, spender, remaining);
+        Ok(())
+    }
+
+    // Increase the allowance the caller has granted to `spender` by `added_value`. This avoids the
+    // approve race where a spender uses the old allowance right before it is replaced.
+    pub fn increase_allowance(&mut self, ctx: &CallContext, spender: &Address, added_value: U256) -> Result<(), SaffronError> {
+        // Get the current allowance and add to it, refusing the change if it would overflow
+        let current = self.allowance(&ctx.sender, spender);
+        let new_allowance = current.checked_add(added_value).ok_or(SaffronError::Overflow)?;
+        self.approve(ctx, spender, new_allowance)
+    }
+
+    // Decrease the allowance the caller has granted to `spender` by `subtracted_value`
+    pub fn decrease_allowance(&mut self, ctx: &CallContext, spender: &Address, subtracted_value: U256) -> Result<(), SaffronError> {
+        // Get the current allowance. It cannot be decreased below zero.
+        let current = self.allowance(&ctx.sender, spender);
+        let new_allowance = current.checked_sub(subtracted_value).ok_or(SaffronError::InsufficientAllowance {
+            needed: subtracted_value,
+            available: current,
+        })?;
+
+        // Record the reduced allowance
+        self.approve(ctx, spender, new_allowance)
+    }
+
+    // Mint (create) a specified amount of the token and assign it to the caller, who must hold
+    // the minter role
+    pub fn mint(&mut self, ctx: &CallContext, value: U256) -> Result<(), SaffronError> {
+        self.require_role(ctx, Role::Minter)?;
+        self.require_not_paused()?;
+        self.require_not_blocked(&ctx.sender)?;
+        // Calculate the new balance of the caller and the new total supply after the mint
+        let sender = ctx.sender;
+        let new_balance = self.checked_credit(&sender, value)?;
+        let new_total_supply = self.total_supply.checked_add(value).ok_or(SaffronError::Overflow)?;
+        // Refuse to issue tokens that are not backed by saffron in stock
+        let cap = self.supply_cap();
+        if new_total_supply > cap {
+            return Err(SaffronError::SupplyCapExceeded { cap, requested: value });
+        }
+
+        // Update the balance in the map and increase the total supply by the amount minted
+        self.put_balance(sender, new_balance);
+        self.set_total_supply(new_total_supply);
+        self.emit(ctx, SaffronEvent::Mint { to: sender, value });
+        Ok(())
+    }
+
+    // Burn (destroy) the specified amount of the token from the caller
+    pub fn burn(&mut self, ctx: &CallContext, value: U256) -> Result<(), SaffronError> {
+        self.require_not_paused()?;
+        self.require_not_blocked(&ctx.sender)?;
+        self.burn_from_balance(&ctx.sender, value)?;
+        self.emit(ctx, SaffronEvent::Burn { from: ctx.sender, value });
+        Ok(())
+    }
+
+    // Return the wei received from every purchase, after refunds
+    pub fn payments_received(&self) -> U256 {
+        self.payments_received
+    }
+
+    // Work out what a payment of `payment` wei buys at the current oracle price without issuing
+    // anything
+    pub fn quote(&self, ctx: &CallContext, payment: U256) -> Result<Purchase, SaffronError> {
+        let wei_per_gram = self.current_price(ctx)?;
+
+        // One gram is one whole token, so the payment buys payment * 10^decimals / price base
+        // units. Rounding down means the buyer never gets more than they paid for.
+        let tokens = payment
+            .checked_mul(self.one_gram())
+            .ok_or(SaffronError::Overflow)?
+            / wei_per_gram;
+        if tokens.is_zero() {
+            return Err(SaffronError::PaymentTooSmall { payment, wei_per_gram });
+        }
+
+        // Charge for those tokens rounding up, so the ledger never gives away a fraction of a
+        // wei, and hand back the dust that could not buy another base unit
+        let (cost, remainder) = tokens
+            .checked_mul(wei_per_gram)
+            .ok_or(SaffronError::Overflow)?
+            .div_mod(self.one_gram());
+        let cost = if remainder.is_zero() { cost } else { cost + 1 };
+        Ok(Purchase { tokens, wei_per_gram, cost, refund: payment - cost })
+    }
+
+    // Buy tokens with the ETH sent with the call (ctx.value), as buyAffron does in
+    // SaffronVendor.sol. The tokens are priced at the current oracle price and issued to the
+    // caller, within the supply cap. The returned refund is the dust left over, which the caller
+    // of this method must send back to the buyer; only the cost is added to the payments
+    // received.
+    pub fn buy(&mut self, ctx: &CallContext) -> Result<Purchase, SaffronError> {
+        self.require_not_paused()?;
+        self.require_not_blocked(&ctx.sender)?;
+        if ctx.value.is_zero() {
+            return Err(SaffronError::InvalidAmount);
+        }
+        let purchase = self.quote(ctx, ctx.value)?;
+
+        // Calculate the buyer's new balance, the new total supply and the new running total of
+        // payments before changing anything
+        let buyer = ctx.sender;
+        let new_balance = self.checked_credit(&buyer, purchase.tokens)?;
+        let new_total_supply = self.total_supply.checked_add(purchase.tokens).ok_or(SaffronError::Overflow)?;
+        let payments_received = self.payments_received.checked_add(purchase.cost).ok_or(SaffronError::Overflow)?;
+        // Refuse to sell tokens that are not backed by saffron in stock
+        let cap = self.supply_cap();
+        if new_total_supply > cap {
+            return Err(SaffronError::SupplyCapExceeded { cap, requested: purchase.tokens });
+        }
+
+        self.put_balance(buyer, new_balance);
+        self.set_total_supply(new_total_supply);
+        self.payments_received = payments_received;
+        self.emit(ctx, SaffronEvent::Mint { to: buyer, value: purchase.tokens });
+        self.emit(ctx, SaffronEvent::Bought { buyer, value: purchase.tokens, cost: purchase.cost, payments_received });
+        Ok(purchase)
+    }
+
+    // Return the vesting schedule with the given id, if there is one
+    pub fn vesting_schedule(&self, id: u64) -> Option<&VestingSchedule> {
+        self.vesting.get(&id)
+    }
+
+    // Return every vesting schedule of `beneficiary`, oldest first
+    pub fn vesting_schedules_of<'a>(&'a self, beneficiary: &'a Address) -> impl Iterator<Item = &'a VestingSchedule> + 'a {
+        self.vesting.values().filter(move |schedule| schedule.beneficiary == *beneficiary)
+    }
+
+    // Return the number of tokens of schedule `id` vested at `timestamp`
+    pub fn vested_amount(&self, id: u64, timestamp: u64) -> Result<U256, SaffronError> {
+        Ok(self.vesting_by_id(id)?.vested_at(timestamp))
+    }
+
+    // Return the number of tokens of schedule `id` not yet vested at `timestamp`
+    pub fn unvested_amount(&self, id: u64, timestamp: u64) -> Result<U256, SaffronError> {
+        Ok(self.vesting_by_id(id)?.unvested_at(timestamp))
+    }
+
+    // Allocate `total` tokens from the caller to `beneficiary`, vesting linearly over `duration`
+    // seconds from `start` with nothing vesting before `start + cliff_duration`. The tokens move
+    // out of the caller's balance straight away. Only an owner may create schedules. Returns the
+    // id of the new schedule.
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_vesting(
+        &mut self,
+        ctx: &CallContext,
+        beneficiary: &Address,
+        total: U256,
+        start: u64,
+        cliff_duration: u64,
+        duration: u64,
+        revocable: bool,
+    ) -> Result<u64, SaffronError> {
+        self.require_role(ctx, Role::Owner)?;
+        self.require_not_paused()?;
+        if beneficiary.is_zero() {
+            return Err(SaffronError::ZeroAddress);
+        }
+        // The cliff must fall within the vesting period, which must not be empty
+        if total.is_zero() || duration == 0 || cliff_duration > duration {
+            return Err(SaffronError::InvalidAmount);
+        }
+        let cliff = start.checked_add(cliff_duration).ok_or(SaffronError::Overflow)?;
+        start.checked_add(duration).ok_or(SaffronError::Overflow)?;
+
+        // Set the tokens aside in the vesting account
+        self.move_balance(ctx, &ctx.sender, &VESTING_ADDRESS, total)?;
+
+        let id = self.next_vesting_id;
+        self.next_vesting_id += 1;
+        self.put_vesting(VestingSchedule {
+            id,
+            beneficiary: *beneficiary,
+            funder: ctx.sender,
+            total,
+            released: U256::zero(),
+            start,
+            cliff,
+            duration,
+            revocable,
+            revoked_at: None,
+        });
+        self.emit(ctx, SaffronEvent::VestingCreated { id, beneficiary: *beneficiary, value: total });
+        Ok(id)
+    }
+
+    // Move the tokens of schedule `id` that have vested by the caller's timestamp, and were not
+    // released before, into the beneficiary's spendable balance. Anyone may trigger a release;
+    // the tokens always go to the beneficiary. Returns the number of tokens released.
+    pub fn release(&mut self, ctx: &CallContext, id: u64) -> Result<U256, SaffronError> {
+        self.require_not_paused()?;
+        let mut schedule = self.vesting_by_id(id)?.clone();
+        let releasable = schedule.releasable_at(ctx.timestamp);
+        if releasable.is_zero() {
+            return Ok(releasable);
+        }
+
+        self.move_balance(ctx, &VESTING_ADDRESS, &schedule.beneficiary, releasable)?;
+        schedule.released += releasable;
+        let beneficiary = schedule.beneficiary;
+        self.put_vesting(schedule);
+        self.emit(ctx, SaffronEvent::VestingReleased { id, beneficiary, value: releasable });
+        Ok(releasable)
+    }
+
+    // Stop schedule `id` from vesting any further and return its unvested tokens to the owner
+    // that funded it. Tokens already vested stay releasable to the beneficiary. Only an owner
+    // may revoke, and only schedules created as revocable. Returns the number of tokens returned.
+    pub fn revoke_vesting(&mut self, ctx: &CallContext, id: u64) -> Result<U256, SaffronError> {
+        self.require_role(ctx, Role::Owner)?;
+        let mut schedule = self.vesting_by_id(id)?.clone();
+        if !schedule.revocable || schedule.revoked_at.is_some() {
+            return Err(SaffronError::NotRevocable(id));
+        }
+
+        // Everything not vested by now goes back to the funder
+        let returned = schedule.unvested_at(ctx.timestamp);
+        if !returned.is_zero() {
+            self.move_balance(ctx, &VESTING_ADDRESS, &schedule.funder, returned)?;
+        }
+        // Vesting stops here, leaving what has vested releasable
+        schedule.revoked_at = Some(ctx.timestamp);
+        let beneficiary = schedule.beneficiary;
+        self.put_vesting(schedule);
+        self.emit(ctx, SaffronEvent::VestingRevoked { id, beneficiary, returned });
+        Ok(returned)
+    }
+
+    // Return the vesting schedule with the given id, or an error if there is none
+    fn vesting_by_id(&self, id: u64) -> Result<&VestingSchedule, SaffronError> {
+        self.vesting.get(&id).ok_or(SaffronError::UnknownVesting(id))
+    }
+
+    // Return the redemption with the given id, if there is one
+    pub fn redemption(&self, id: u64) -> Option<&Redemption> {
+        self.redemptions.get(&id)
+    }
+
+    // Return every redemption made by `holder`, oldest first
+    pub fn redemptions_of<'a>(&'a self, holder: &'a Address) -> impl Iterator<Item = &'a Redemption> + 'a {
+        self.redemptions.values().filter(move |redemption| redemption.holder == *holder)
+    }
+
+    // Set how many seconds a redemption may stay pending before it can be expired. Only an
+    // owner may change this, and it only applies to redemptions requested afterwards.
+    pub fn set_redemption_ttl(&mut self, ctx: &CallContext, ttl: u64) -> Result<(), SaffronError> {
+        self.require_role(ctx, Role::Owner)?;
+        self.redemption_ttl = ttl;
+        Ok(())
+    }
+
+    // Ask to redeem `value` tokens from the caller for physical saffron shipped to the recipient
+    // whose details hash to `recipient_hash`. The tokens move into escrow and the grams are set
+    // aside from the inventory until the redemption is fulfilled, cancelled or expired. Returns
+    // the id of the new redemption.
+    pub fn request_redemption(&mut self, ctx: &CallContext, value: U256, recipient_hash: H256) -> Result<u64, SaffronError> {
+        self.require_not_paused()?;
+        // Only whole grams can be redeemed
+        let (grams, remainder) = value.div_mod(self.one_gram());
+        if grams.is_zero() || !remainder.is_zero() {
+            return Err(SaffronError::InvalidAmount);
+        }
+        // Check the grams are in stock and not already promised to another redemption
+        self.require_available_inventory(grams)?;
+        let expires_at = ctx.timestamp.checked_add(self.redemption_ttl).ok_or(SaffronError::Overflow)?;
+
+        // Move the tokens into escrow, which also checks the caller's balance
+        self.move_balance(ctx, &ctx.sender, &ESCROW_ADDRESS, value)?;
+        self.pending_redemption_grams += grams;
+
+        // Record the redemption under a new id
+        let id = self.next_redemption_id;
+        self.next_redemption_id += 1;
+        self.put_redemption(Redemption {
+            id,
+            holder: ctx.sender,
+            value,
+            grams,
+            recipient_hash,
+            status: RedemptionStatus::Pending,
+            requested_at: ctx.timestamp,
+            expires_at,
+            settled_at: None,
+            order_placed_at: None,
+        });
+        self.emit(ctx, SaffronEvent::RedeemRequest { id, holder: ctx.sender, value });
+        Ok(id)
+    }
+
+    // Confirm that redemption `id` has shipped under `tracking_number`. The escrowed tokens are
+    // burned and the grams leave the inventory. Only a fulfiller may confirm shipments.
+    pub fn fulfill_redemption(&mut self, ctx: &CallContext, id: u64, tracking_number: String) -> Result<(), SaffronError> {
+        self.require_role(ctx, Role::Fulfiller)?;
+        let (holder, value, grams) = self.pending_redemption(id)?;
+
+        // Burn the escrowed tokens and take the shipped saffron out of stock
+        self.burn_from_balance(&ESCROW_ADDRESS, value)?;
+        self.inventory_grams -= grams;
+        self.pending_redemption_grams -= grams;
+
+        self.settle_redemption(ctx, id, RedemptionStatus::Fulfilled { tracking_number });
+        self.emit(ctx, SaffronEvent::Redeem { id, holder, value });
+        Ok(())
+    }
+
+    // Cancel redemption `id` before it ships and refund its tokens. The holder or a fulfiller
+    // may cancel. Once a shipping order has been placed the redemption can only be cancelled
+    // through cancel_fulfillment_order, which asks the provider whether it has shipped.
+    pub fn cancel_redemption(&mut self, ctx: &CallContext, id: u64) -> Result<(), SaffronError> {
+        let (holder, _, _) = self.pending_redemption(id)?;
+        if ctx.sender != holder {
+            self.require_role(ctx, Role::Fulfiller)?;
+        }
+        self.require_no_fulfillment_order(id)?;
+        self.refund_redemption(ctx, id, RedemptionStatus::Cancelled)
+    }
+
+    // Expire redemption `id` once its deadline has passed without a shipment and refund its
+    // tokens. Anyone may expire an overdue redemption, unless a shipping order has been placed
+    // for it: the saffron may be on its way, so it must be cancelled through the provider.
+    pub fn expire_redemption(&mut self, ctx: &CallContext, id: u64) -> Result<(), SaffronError> {
+        self.pending_redemption(id)?;
+        let expires_at = self.redemptions[&id].expires_at;
+        if ctx.timestamp < expires_at {
+            return Err(SaffronError::RedemptionNotExpired { id, expires_at });
+        }
+        self.require_no_fulfillment_order(id)?;
+        self.refund_redemption(ctx, id, RedemptionStatus::Expired)
+    }
+
+    // Refuse to settle redemption `id` without the provider once a shipping order exists for it
+    fn require_no_fulfillment_order(&self, id: u64) -> Result<(), SaffronError> {
+        if self.redemptions[&id].order_placed_at.is_some() {
+            return Err(SaffronError::FulfillmentOrderPlaced(id));
+        }
+        Ok(())
+    }
+
+    // Return the holder, value and grams of redemption `id`, refusing redemptions that are
+    // unknown or already settled
+    fn pending_redemption(&self, id: u64) -> Result<(Address, U256, U256), SaffronError> {
+        let redemption = self.redemptions.get(&id).ok_or(SaffronError::UnknownRedemption(id))?;
+        if redemption.status != RedemptionStatus::Pending {
+            return Err(SaffronError::RedemptionNotPending(id));
+        }
+        Ok((redemption.holder, redemption.value, redemption.grams))
+    }
+
+    // Return the escrowed tokens of pending redemption `id` to its holder and release its grams
+    fn refund_redemption(&mut self, ctx: &CallContext, id: u64, status: RedemptionStatus) -> Result<(), SaffronError> {
+        let (holder, value, grams) = self.pending_redemption(id)?;
+        self.move_balance(ctx, &ESCROW_ADDRESS, &holder, value)?;
+        self.pending_redemption_grams -= grams;
+
+        self.settle_redemption(ctx, id, status);
+        self.emit(ctx, SaffronEvent::RedeemRefund { id, holder, value });
+        Ok(())
+    }
+
+    // Move redemption `id` out of the pending state
+    fn settle_redemption(&mut self, ctx: &CallContext, id: u64, status: RedemptionStatus) {
+        if let Some(redemption) = self.redemptions.get(&id) {
+            let mut settled = redemption.clone();
+            settled.status = status;
+            settled.settled_at = Some(ctx.timestamp);
+            self.put_redemption(settled);
+        }
+    }
+
+    // Remove `value` tokens from `owner` and from the total supply
+    fn burn_from_balance(&mut self, owner: &Address, value: U256) -> Result<(), SaffronError> {
+        // Calculate the new balance of the owner and the new total supply after the burn
+        let new_balance = self.checked_debit(owner, value)?;
+        let new_total_supply = self.total_supply.checked_sub(value).ok_or(SaffronError::Overflow)?;
+
+        // Update the balance in the map and decrease the total supply by the amount burned
+        self.put_balance(*owner, new_balance);
+        self.set_total_supply(new_total_supply);
+        Ok(())
+    }
+
+    // Return the number of base units in one whole token, which is backed by one gram
+    fn one_gram(&self) -> U256 {
+        U256::exp10(self.decimals as usize)
+    }
+
+    // Return the total supply in grams, rounded up so a partial gram still needs backing
+    fn supply_grams(&self) -> U256 {
+        let (whole_grams, remainder) = self.total_supply.div_mod(self.one_gram());
+        if remainder.is_zero() { whole_grams } else { whole_grams + 1 }
+    }
+
+    // Refuse to set aside `grams` unless that many are in stock and not already promised to a
+    // pending redemption. Pending grams never exceed the inventory, so the subtraction is safe.
+    fn require_available_inventory(&self, grams: U256) -> Result<(), SaffronError> {
+        let available = self.inventory_grams - self.pending_redemption_grams;
+        if available < grams {
+            return Err(SaffronError::InsufficientInventory { needed: grams, available });
+        }
+        Ok(())
+    }
+
+    // Return what the balance of `owner` would be after removing `value` tokens, without
+    // changing it
+    fn checked_debit(&self, owner: &Address, value: U256) -> Result<U256, SaffronError> {
+        let balance = self.balance_of(owner);
+        balance.checked_sub(value).ok_or(SaffronError::InsufficientBalance {
+            needed: value,
+            available: balance,
+        })
+    }
+
+    // Return what the balance of `owner` would be after adding `value` tokens, without
+    // changing it
+    fn checked_credit(&self, owner: &Address, value: U256) -> Result<U256, SaffronError> {
+        self.balance_of(owner).checked_add(value).ok_or(SaffronError::Overflow)
+    }
+
+    // Refuse the call unless the caller holds `role`
+    fn require_role(&self, ctx: &CallContext, role: Role) -> Result<(), SaffronError> {
+        if self.has_role(role, &ctx.sender) {
+            Ok(())
+        } else {
+            Err(SaffronError::Unauthorized)
+        }
+    }
+
+    // Refuse the call while token movement is paused
+    fn require_not_paused(&self) -> Result<(), SaffronError> {
+        if self.paused {
+            return Err(SaffronError::Paused);
+        }
+        Ok(())
+    }
+
+    // Refuse the call if `account` is on the blocklist
+    fn require_not_blocked(&self, account: &Address) -> Result<(), SaffronError> {
+        if self.is_blocked(account) {
+            return Err(SaffronError::Blocked(*account));
+        }
+        Ok(())
+    }
+
+    // Remove `account` from the holders of `role` and journal the change, returning true if it
+    // held the role
+    fn remove_role(&mut self, role: Role, account: &Address) -> bool {
+        let removed = self.roles.get_mut(&role).is_some_and(|holders| holders.remove(account));
+        if removed {
+            self.journal.push(WriteOp::Role { role, account: *account, held: false });
+        }
+        removed
+    }
+
+    // Append `event` to the log at the caller's block
+    fn emit(&mut self, ctx: &CallContext, event: SaffronEvent) {
+        let logged = LoggedEvent {
+            log_index: self.events.len() as u64,
+            block_number: ctx.block_number,
+            timestamp: ctx.timestamp,
+            event,
+        };
+        self.journal.push(WriteOp::Event(logged.clone()));
+        self.events.push(logged);
+    }
+
+    // Set the balance of `account` and journal the change
+    fn put_balance(&mut self, account: Address, value: U256) {
+        // Keep the balance the account had at the latest snapshot before changing it
+        if self.needs_checkpoint(self.account_snapshots.get(&account)) {
+            let checkpoint = (self.current_snapshot_id, self.balance_of(&account));
+            self.account_snapshots.entry(account).or_default().push(checkpoint);
+            self.journal.push(WriteOp::AccountSnapshot { account, snapshot_id: checkpoint.0, value: checkpoint.1 });
+        }
+        self.balances.insert(account, value);
+        self.journal.push(WriteOp::Balance { account, value });
+    }
+
+    // Set the total supply, keeping the value it had at the latest snapshot
+    fn set_total_supply(&mut self, value: U256) {
+        if self.needs_checkpoint(Some(&self.total_supply_snapshots)) {
+            let checkpoint = (self.current_snapshot_id, self.total_supply);
+            self.total_supply_snapshots.push(checkpoint);
+            self.journal.push(WriteOp::TotalSupplySnapshot { snapshot_id: checkpoint.0, value: checkpoint.1 });
+        }
+        self.total_supply = value;
+    }
+
+    // Return true if a value with these checkpoints is about to change for the first time
+    // since the latest snapshot
+    fn needs_checkpoint(&self, checkpoints: Option<&Vec<(u64, U256)>>) -> bool {
+        let last_recorded = checkpoints.and_then(|checkpoints| checkpoints.last()).map_or(0, |(id, _)| *id);
+        self.current_snapshot_id > last_recorded
+    }
+
+    // Insert or replace a vesting schedule and journal the change
+    fn put_vesting(&mut self, schedule: VestingSchedule) {
+        self.journal.push(WriteOp::Vesting(schedule.clone()));
+        self.vesting.insert(schedule.id, schedule);
+    }
+
+    // Insert or replace a redemption record and journal the change
+    fn put_redemption(&mut self, redemption: Redemption) {
+        self.journal.push(WriteOp::Redemption(redemption.clone()));
+        self.redemptions.insert(redemption.id, redemption);
+    }
+
+    // Append a role change made by the caller to the audit trail and journal it
+    fn record_role_change(&mut self, ctx: &CallContext, role: Role, account: &Address, action: RoleAction) {
+        let change = RoleChange {
+            role,
+            account: *account,
+            action,
+            changed_by: ctx.sender,
+            timestamp: ctx.timestamp,
+        };
+        self.journal.push(WriteOp::RoleChange { index: self.role_changes.len() as u64, change: change.clone() });
+        self.role_changes.push(change);
+    }
+
+    // Store the allowance for an owner/spender pair, removing the entry once it reaches zero so
+    // that exhausted allowances do not linger in the map, and journal the change
+    fn set_allowance(&mut self, owner: &Address, spender: &Address, value: U256) {
+        self.journal.push(WriteOp::Allowance { owner: *owner, spender: *spender, value });
+        if value.is_zero() {
+            // Remove the spender, and the owner as well if they have no allowances left
+            if let Some(spenders) = self.allowances.get_mut(owner) {
+                spenders.remove(spender);
+                if spenders.is_empty() {
+                    self.allowances.remove(owner);
+                }
+            }
+        } else {
+            self.allowances.entry(*owner).or_default().insert(*spender, value);
+        }
+    }
+
+    // Place the shipping order for pending redemption `id` with `provider`, shipping to
+    // `destination`, and return the order's status. Only a fulfiller may place orders. The order
+    // id is derived from the redemption id, so placing the same redemption again does not ship it
+    // twice.
+    pub fn place_fulfillment_order(
+        &mut self,
+        ctx: &CallContext,
+        id: u64,
+        destination: ShippingAddress,
+        provider: &dyn FulfillmentProvider,
+    ) -> Result<FulfillmentStatus, SaffronError> {
+        self.require_role(ctx, Role::Fulfiller)?;
+        self.pending_redemption(id)?;
+
+        // Providers count grams in a u64, which any real shipment fits in
+        let redemption = &self.redemptions[&id];
+        if redemption.grams > U256::from(u64::MAX) {
+            return Err(SaffronError::InvalidAmount);
+        }
+        let request = FulfillmentRequest::for_redemption(id, redemption.grams.as_u64(), redemption.requested_at, destination);
+
+        // Record the order before sending it. A request that fails may still have reached the
+        // provider, so from here on the redemption is only refunded once the provider confirms
+        // nothing has shipped.
+        if redemption.order_placed_at.is_none() {
+            let mut placed = redemption.clone();
+            placed.order_placed_at = Some(ctx.timestamp);
+            self.put_redemption(placed);
+        }
+        Ok(provider.create_order(&request)?)
+    }
+
+    // Check the shipping order of pending redemption `id` with `provider` and settle the
+    // redemption to match: fulfil it once it has shipped, refund it if the provider cancelled or
+    // cannot ship it, and leave it pending while there is no tracking number yet. Only a
+    // fulfiller may do this. Returns the redemption's status afterwards.
+    pub fn sync_fulfillment(
+        &mut self,
+        ctx: &CallContext,
+        id: u64,
+        provider: &dyn FulfillmentProvider,
+    ) -> Result<RedemptionStatus, SaffronError> {
+        self.require_role(ctx, Role::Fulfiller)?;
+        self.pending_redemption(id)?;
+
+        match provider.order_status(&order_id_for_redemption(id))? {
+            FulfillmentStatus::Processing => {}
+            FulfillmentStatus::Shipped { tracking_number, .. } => {
+                self.fulfill_redemption(ctx, id, tracking_number)?;
+            }
+            FulfillmentStatus::Cancelled | FulfillmentStatus::Unfulfillable(_) => {
+                self.refund_redemption(ctx, id, RedemptionStatus::Cancelled)?;
+            }
+        }
+        Ok(self.redemptions[&id].status.clone())
+    }
+
+    // Cancel the shipping order of pending redemption `id` with `provider`, then cancel the
+    // redemption and refund its tokens. The provider is asked first so that a redemption whose
+    // saffron has already shipped is never refunded as well.
+    pub fn cancel_fulfillment_order(
+        &mut self,
+        ctx: &CallContext,
+        id: u64,
+        provider: &dyn FulfillmentProvider,
+    ) -> Result<(), SaffronError> {
+        let (holder, _, _) = self.pending_redemption(id)?;
+        if ctx.sender != holder {
+            self.require_role(ctx, Role::Fulfiller)?;
+        }
+
+        // An order that was never placed has nothing to call back
+        match provider.cancel_order(&order_id_for_redemption(id)) {
+            Ok(()) | Err(FulfillmentError::UnknownOrder(_)) => {}
+            Err(err) => return Err(err.into()),
+        }
+        self.refund_redemption(ctx, id, RedemptionStatus::Cancelled)
+    }
+}
+
+// Return how far `reported` is from `reference` in basis points of `reference`. Any move away
+// from a zero reference counts as the largest possible deviation.
+fn deviation_bps(reference: U256, reported: U256) -> u64 {
+    let difference = if reported > reference { reported - reference } else { reference - reported };
+    if difference.is_zero() {
+        return 0;
+    }
+    if reference.is_zero() {
+        return u64::MAX;
+    }
+    difference
+        .checked_mul(U256::from(10_000))
+        .map_or(u64::MAX, |scaled| {
+            let bps = scaled / reference;
+            if bps > U256::from(u64::MAX) { u64::MAX } else { bps.as_u64() }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::saffron_storage::test_support::{with_each_store, TempSledStore};
+    use crate::saffron_storage::MemoryStore;
+
+    // Return a ledger of 10 whole tokens, every one of them held by its owner, the address
+    // ending in 1
+    fn ledger() -> Saffron {
+        let owner = Address::from_low_u64_be(1);
+        let ctx = CallContext::new(owner, U256::zero(), 1, 1_000);
+        let mut saffron = Saffron::new("Saffron Vendor".to_string(), "$affron".to_string(), 18, owner).unwrap();
+        saffron.grant_role(&ctx, Role::Minter, &owner).unwrap();
+        saffron.add_inventory(&ctx, U256::from(10)).unwrap();
+        saffron.mint(&ctx, U256::exp10(19)).unwrap();
+        saffron
+    }
+
+    // Return a context for a call from the address ending in `sender`
+    fn call_from(sender: u64) -> CallContext {
+        CallContext::new(Address::from_low_u64_be(sender), U256::zero(), 2, 2_000)
+    }
+
+    // Commit `saffron` to `store` and read it back, offering `owner` as the first owner
+    fn reopen(saffron: &mut Saffron, store: &dyn LedgerStore, owner: Option<Address>) -> Saffron {
+        saffron.commit(store).unwrap();
+        Saffron::restore("Saffron Vendor".to_string(), "$affron".to_string(), 18, owner, store).unwrap()
+    }
+
+    #[test]
+    fn roles_and_their_audit_trail_survive_a_restart() {
+        with_each_store(|store| {
+            let mut saffron = ledger();
+            let fulfiller = Address::from_low_u64_be(4);
+            saffron.grant_role(&call_from(1), Role::Fulfiller, &fulfiller).unwrap();
+            saffron.renounce_role(&call_from(1), Role::Minter).unwrap();
+
+            // Restoring with another owner does not make it one, since the store has roles on record
+            let stranger = Address::from_low_u64_be(9);
+            let restored = reopen(&mut saffron, store, Some(stranger));
+            assert!(restored.has_role(Role::Owner, &Address::from_low_u64_be(1)));
+            assert!(!restored.has_role(Role::Owner, &stranger));
+            assert!(restored.has_role(Role::Fulfiller, &fulfiller));
+            assert!(!restored.has_role(Role::Minter, &Address::from_low_u64_be(1)));
+            assert_eq!(restored.role_changes(), saffron.role_changes());
+        });
+    }
+
+    #[test]
+    fn balances_allowances_redemptions_and_events_survive_reopening_the_database() {
+        let mut saffron = ledger();
+        let holder = Address::from_low_u64_be(2);
+        let spender = Address::from_low_u64_be(3);
+        saffron.transfer(&call_from(1), &holder, U256::from(3) * U256::exp10(18)).unwrap();
+        saffron.approve(&call_from(2), &spender, U256::exp10(18)).unwrap();
+        let id = saffron.request_redemption(&call_from(2), U256::exp10(18), H256::from_low_u64_be(5)).unwrap();
+
+        let held = TempSledStore::new();
+        saffron.commit(&held.store).unwrap();
+        let held = held.reopen();
+        let restored = Saffron::restore("Saffron Vendor".to_string(), "$affron".to_string(), 18, None, &held.store).unwrap();
+        assert_eq!(restored.balance_of(&Address::from_low_u64_be(1)), U256::from(7) * U256::exp10(18));
+        assert_eq!(restored.balance_of(&holder), U256::from(2) * U256::exp10(18));
+        assert_eq!(restored.allowance(&holder, &spender), U256::exp10(18));
+        assert_eq!(restored.redemption(id), saffron.redemption(id));
+        assert!(restored.redemption(id).is_some());
+        assert_eq!(restored.event_log(), saffron.event_log());
+        assert_eq!(restored.total_supply(), saffron.total_supply());
+    }
+
+    #[test]
+    fn snapshots_keep_past_balances_and_supply_across_a_restart() {
+        let mut saffron = ledger();
+        let (owner, holder) = (Address::from_low_u64_be(1), Address::from_low_u64_be(2));
+        let token = U256::exp10(18);
+        saffron.add_inventory(&call_from(1), U256::from(5)).unwrap();
+
+        // Snapshot 1: the owner holds all 10 tokens
+        let first = saffron.take_snapshot(&call_from(1)).unwrap();
+        saffron.transfer(&call_from(1), &holder, token * 4).unwrap();
+        // Snapshot 2: 6 and 4, then 3 more are minted and 1 burned
+        let second = saffron.take_snapshot(&call_from(1)).unwrap();
+        saffron.mint(&call_from(1), token * 3).unwrap();
+        saffron.burn(&call_from(2), token).unwrap();
+        // Snapshot 3: nothing changes after it
+        let third = saffron.take_snapshot(&call_from(1)).unwrap();
+
+        let check = |saffron: &Saffron| {
+            assert_eq!(saffron.balance_of_at(&owner, first), Ok(token * 10));
+            assert_eq!(saffron.balance_of_at(&holder, first), Ok(U256::zero()));
+            assert_eq!(saffron.total_supply_at(first), Ok(token * 10));
+            assert_eq!(saffron.balance_of_at(&owner, second), Ok(token * 6));
+            assert_eq!(saffron.balance_of_at(&holder, second), Ok(token * 4));
+            assert_eq!(saffron.total_supply_at(second), Ok(token * 10));
+            assert_eq!(saffron.balance_of_at(&owner, third), Ok(token * 9));
+            assert_eq!(saffron.balance_of_at(&holder, third), Ok(token * 3));
+            assert_eq!(saffron.total_supply_at(third), Ok(token * 12));
+            assert_eq!(saffron.total_supply_at(0), Err(SaffronError::UnknownSnapshot(0)));
+        };
+        check(&saffron);
+        assert_eq!(saffron.total_supply_at(4), Err(SaffronError::UnknownSnapshot(4)));
+
+        let held = TempSledStore::new();
+        saffron.commit(&held.store).unwrap();
+        let held = held.reopen();
+        let mut restored = Saffron::restore("Saffron Vendor".to_string(), "$affron".to_string(), 18, None, &held.store).unwrap();
+        check(&restored);
+        assert_eq!(restored.balance_of_at(&owner, 4), Err(SaffronError::UnknownSnapshot(4)));
+
+        // Snapshot ids carry on, and later changes leave the old snapshots alone
+        assert_eq!(restored.take_snapshot(&call_from(1)), Ok(4));
+        restored.transfer(&call_from(2), &owner, token).unwrap();
+        check(&restored);
+        assert_eq!(restored.balance_of_at(&holder, 4), Ok(token * 3));
+        assert_eq!(restored.balance_of(&holder), token * 2);
+    }
+
+    #[test]
+    fn settings_survive_a_restart() {
+        with_each_store(|store| {
+            let mut saffron = ledger();
+            let treasury = Address::from_low_u64_be(7);
+            let vendor = Address::from_low_u64_be(8);
+            let fee = TransferFee { rate_bps: 25, destination: FeeDestination::Treasury(treasury) };
+            saffron.set_transfer_fee(&call_from(1), fee).unwrap();
+            saffron.set_fee_exempt(&call_from(1), &vendor, true).unwrap();
+            saffron.set_permit_domain(&call_from(1), U256::from(5), Address::from_low_u64_be(6)).unwrap();
+            saffron.set_redemption_ttl(&call_from(1), 60).unwrap();
+
+            let restored = reopen(&mut saffron, store, None);
+            assert_eq!(restored.transfer_fee(), fee);
+            assert!(restored.is_fee_exempt(&vendor));
+            assert_eq!(restored.permit_domain().chain_id, U256::from(5));
+            assert_eq!(restored.permit_domain().verifying_contract, Address::from_low_u64_be(6));
+            assert_eq!(restored.redemption_ttl, 60);
+        });
+    }
+
+    #[test]
+    fn pause_and_blocklist_survive_a_restart() {
+        with_each_store(|store| {
+            let mut saffron = ledger();
+            let blocked = Address::from_low_u64_be(3);
+            let unblocked = Address::from_low_u64_be(4);
+            saffron.block_account(&call_from(1), &blocked).unwrap();
+            saffron.block_account(&call_from(1), &unblocked).unwrap();
+            saffron.unblock_account(&call_from(1), &unblocked).unwrap();
+            saffron.pause(&call_from(1)).unwrap();
+
+            let mut restored = reopen(&mut saffron, store, None);
+            assert_eq!(restored.transfer(&call_from(1), &unblocked, U256::one()), Err(SaffronError::Paused));
+            restored.unpause(&call_from(1)).unwrap();
+            assert_eq!(restored.transfer(&call_from(1), &blocked, U256::one()), Err(SaffronError::Blocked(blocked)));
+            assert!(restored.transfer(&call_from(1), &unblocked, U256::one()).is_ok());
+        });
+    }
+
+    #[test]
+    fn oracle_state_survives_a_restart() {
+        with_each_store(|store| {
+            let mut saffron = ledger();
+            let oracle = Address::from_low_u64_be(6);
+            let callback = PriceCallback { address: Address::from_low_u64_be(7), function_selector: [1, 2, 3, 4] };
+            saffron.grant_role(&call_from(1), Role::OracleAdmin, &Address::from_low_u64_be(1)).unwrap();
+            saffron.set_oracle_address(&call_from(1), oracle).unwrap();
+            let guard = PriceGuard { heartbeat: 60, max_deviation_bps: 100 };
+            saffron.set_price_guard(&call_from(1), guard).unwrap();
+            let answered = saffron.request_price(&call_from(1), H256::zero(), U256::one(), callback).unwrap();
+            let waiting = saffron.request_price(&call_from(1), H256::zero(), U256::one(), callback).unwrap();
+            saffron.fulfill_price(&call_from(6), answered, U256::from(1_000), 1).unwrap();
+
+            let mut restored = reopen(&mut saffron, store, None);
+            assert_eq!(restored.oracle_address(), oracle);
+            assert_eq!(restored.price_guard(), guard);
+            assert_eq!(restored.latest_price(), saffron.latest_price());
+            assert_eq!(restored.current_price(&call_from(1)), Ok(U256::from(1_000)));
+            assert_eq!(restored.pending_price_requests().map(|request| request.request_id).collect::<Vec<_>>(), [waiting]);
+
+            // Request ids carry on from the stored count, and the waiting request can still be answered
+            let next = restored.request_price(&call_from(1), H256::zero(), U256::one(), callback).unwrap();
+            assert!(next != answered && next != waiting);
+            restored.fulfill_price(&call_from(6), waiting, U256::from(1_001), 2).unwrap();
+        });
+    }
+
+    #[test]
+    fn a_new_store_restores_the_default_settings() {
+        let restored = Saffron::restore("Saffron Vendor".to_string(), "$affron".to_string(), 18, None, &MemoryStore::new()).unwrap();
+        assert_eq!(restored.redemption_ttl, DEFAULT_REDEMPTION_TTL);
+        assert_eq!(restored.permit_domain().chain_id, U256::one());
+        assert_eq!(restored.transfer_fee(), TransferFee::none());
+    }
+
+    #[test]
+    fn erc20_calls_log_their_events_in_the_callers_block() {
+        let mut saffron = ledger();
+        let ctx = CallContext::new(Address::from_low_u64_be(1), U256::zero(), 7, 7_000);
+        assert!(ERC20::approve(&mut saffron, &ctx, &Address::from_low_u64_be(2), U256::from(5)));
+        assert!(ERC20::transfer(&mut saffron, &ctx, &Address::from_low_u64_be(3), U256::from(5)));
+
+        let logged = &saffron.event_log()[saffron.event_log().len() - 2..];
+        assert!(logged.iter().all(|logged| logged.block_number == 7 && logged.timestamp == 7_000));
+    }
+
+    #[test]
+    fn events_are_filtered_by_address_kind_and_block_range() {
+        let mut saffron = ledger();
+        let (owner, holder, spender) = (Address::from_low_u64_be(1), Address::from_low_u64_be(2), Address::from_low_u64_be(3));
+        saffron.transfer(&CallContext::new(owner, U256::zero(), 5, 5_000), &holder, U256::from(10)).unwrap();
+        saffron.approve(&CallContext::new(holder, U256::zero(), 6, 6_000), &spender, U256::from(4)).unwrap();
+        saffron.transfer(&CallContext::new(holder, U256::zero(), 7, 7_000), &spender, U256::from(3)).unwrap();
+        saffron.burn(&CallContext::new(owner, U256::zero(), 8, 8_000), U256::one()).unwrap();
+
+        // Return the blocks of the events `filter` selects
+        let blocks = |filter: EventFilter| saffron.events(&filter).map(|logged| logged.block_number).collect::<Vec<_>>();
+
+        // The ledger's set-up minted in block 1; an empty filter matches everything
+        assert_eq!(blocks(EventFilter::default()), [1, 5, 6, 7, 8]);
+        // Both the sender and the recipient of a transfer are indexed
+        assert_eq!(blocks(EventFilter { address: Some(holder), ..Default::default() }), [5, 6, 7]);
+        assert_eq!(blocks(EventFilter { address: Some(spender), ..Default::default() }), [6, 7]);
+        assert_eq!(blocks(EventFilter { kind: Some(EventKind::Transfer), ..Default::default() }), [5, 7]);
+        // The block range includes both ends
+        assert_eq!(blocks(EventFilter { from_block: Some(6), to_block: Some(7), ..Default::default() }), [6, 7]);
+        assert_eq!(blocks(EventFilter { to_block: Some(4), ..Default::default() }), [1]);
+        // Every condition must hold
+        let filter = EventFilter { address: Some(owner), kind: Some(EventKind::Transfer), from_block: Some(6), to_block: None };
+        assert!(blocks(filter).is_empty());
+        let filter = EventFilter { address: Some(owner), kind: Some(EventKind::Burn), from_block: Some(6), to_block: Some(8) };
+        assert_eq!(blocks(filter), [8]);
+    }
+
+    #[test]
+    fn more_than_77_decimals_are_refused() {
+        let owner = Address::from_low_u64_be(1);
+        assert!(Saffron::new("Saffron Vendor".to_string(), "$affron".to_string(), 77, owner).is_ok());
+        let result = Saffron::new("Saffron Vendor".to_string(), "$affron".to_string(), 78, owner);
+        assert_eq!(result.err(), Some(SaffronError::InvalidDecimals { decimals: 78, max: MAX_DECIMALS }));
+    }
+
+    #[test]
+    fn inventory_backing_the_supply_cannot_be_removed() {
+        // 10 tokens are in circulation against 10 grams, so nothing can be written off
+        let mut saffron = ledger();
+        let result = saffron.remove_inventory(&call_from(1), U256::one());
+        assert_eq!(result, Err(SaffronError::InsufficientInventory { needed: U256::one(), available: U256::zero() }));
+
+        // Burning half a token leaves 9.5 tokens, which still need 10 grams
+        saffron.burn(&call_from(1), U256::exp10(17) * 5).unwrap();
+        assert!(saffron.remove_inventory(&call_from(1), U256::one()).is_err());
+
+        // Once a whole token is gone its gram can go too
+        saffron.burn(&call_from(1), U256::exp10(17) * 5).unwrap();
+        saffron.remove_inventory(&call_from(1), U256::one()).unwrap();
+        assert_eq!(saffron.inventory_grams(), U256::from(9));
+        assert!(saffron.reserves_report().fully_backed);
+    }
+
+    #[test]
+    fn a_new_ledger_has_nothing_in_circulation() {
+        let saffron = Saffron::new("Saffron Vendor".to_string(), "$affron".to_string(), 18, Address::from_low_u64_be(1)).unwrap();
+        assert_eq!(saffron.total_supply(), U256::zero());
+        assert_eq!(saffron.inventory_grams(), U256::zero());
+        assert!(saffron.reserves_report().fully_backed);
+    }
+
+    #[test]
+    fn the_reserves_report_counts_tokens_in_escrow_as_circulating() {
+        let mut saffron = ledger();
+        saffron.add_inventory(&call_from(1), U256::from(2)).unwrap();
+        saffron.burn(&call_from(1), U256::exp10(17) * 5).unwrap();
+        let id = saffron.request_redemption(&call_from(1), U256::from(3) * U256::exp10(18), H256::zero()).unwrap();
+
+        // The 3 escrowed tokens are still part of the 9.5 in circulation, which need 10 grams
+        let report = saffron.reserves_report();
+        assert_eq!(report.total_supply, U256::exp10(17) * 95);
+        assert_eq!(report.supply_grams, U256::from(10));
+        assert_eq!(report.inventory_grams, U256::from(12));
+        assert_eq!(report.pending_redemption_grams, U256::from(3));
+        assert!(report.fully_backed);
+
+        // Only the 2 grams beyond the supply can be written off while the redemption waits
+        assert!(saffron.remove_inventory(&call_from(1), U256::from(3)).is_err());
+        saffron.remove_inventory(&call_from(1), U256::from(2)).unwrap();
+
+        // Shipping burns the escrowed tokens and takes their grams out of stock
+        saffron.grant_role(&call_from(1), Role::Fulfiller, &Address::from_low_u64_be(1)).unwrap();
+        saffron.fulfill_redemption(&call_from(1), id, "1Z999".to_string()).unwrap();
+        let report = saffron.reserves_report();
+        assert_eq!(report.total_supply, U256::exp10(17) * 65);
+        assert_eq!(report.supply_grams, U256::from(7));
+        assert_eq!(report.inventory_grams, U256::from(7));
+        assert_eq!(report.pending_redemption_grams, U256::zero());
+        assert!(report.fully_backed);
+    }
+
+    #[test]
+    fn transfer_fees_are_rounded_down() {
+        let fee = TransferFee { rate_bps: 25, destination: FeeDestination::Burn };
+        assert_eq!(fee.fee_for(U256::from(10_000)), Ok(U256::from(25)));
+        // 0.25% of 399 is 0.9975, which rounds down to nothing
+        assert_eq!(fee.fee_for(U256::from(399)), Ok(U256::zero()));
+        assert_eq!(fee.fee_for(U256::from(401)), Ok(U256::one()));
+        assert_eq!(TransferFee::none().fee_for(U256::max_value()), Ok(U256::zero()));
+        assert_eq!(fee.fee_for(U256::max_value()), Err(SaffronError::Overflow));
+    }
+
+    #[test]
+    fn a_burn_fee_shrinks_the_supply() {
+        let mut saffron = ledger();
+        let recipient = Address::from_low_u64_be(2);
+        saffron.set_transfer_fee(&call_from(1), TransferFee { rate_bps: 100, destination: FeeDestination::Burn }).unwrap();
+
+        saffron.transfer(&call_from(1), &recipient, U256::exp10(18)).unwrap();
+        assert_eq!(saffron.balance_of(&Address::from_low_u64_be(1)), U256::from(9) * U256::exp10(18));
+        assert_eq!(saffron.balance_of(&recipient), U256::exp10(16) * 99);
+        assert_eq!(saffron.total_supply(), U256::exp10(19) - U256::exp10(16));
+        let logged = saffron.event_log().last().unwrap();
+        assert_eq!(logged.event, SaffronEvent::Burn { from: Address::from_low_u64_be(1), value: U256::exp10(16) });
+    }
+
+    #[test]
+    fn a_treasury_fee_is_credited_to_the_treasury() {
+        let mut saffron = ledger();
+        let (recipient, treasury) = (Address::from_low_u64_be(2), Address::from_low_u64_be(7));
+        let fee = TransferFee { rate_bps: 100, destination: FeeDestination::Treasury(treasury) };
+        saffron.set_transfer_fee(&call_from(1), fee).unwrap();
+
+        saffron.transfer(&call_from(1), &recipient, U256::exp10(18)).unwrap();
+        assert_eq!(saffron.balance_of(&recipient), U256::exp10(16) * 99);
+        assert_eq!(saffron.balance_of(&treasury), U256::exp10(16));
+        assert_eq!(saffron.total_supply(), U256::exp10(19));
+
+        // The treasury spends its fees without paying one
+        saffron.transfer(&call_from(7), &recipient, U256::exp10(16)).unwrap();
+        assert_eq!(saffron.balance_of(&recipient), U256::exp10(18));
+        assert_eq!(saffron.balance_of(&treasury), U256::zero());
+    }
+
+    #[test]
+    fn exempt_senders_and_recipients_pay_no_fee() {
+        let mut saffron = ledger();
+        let (exempt, other) = (Address::from_low_u64_be(2), Address::from_low_u64_be(3));
+        saffron.set_transfer_fee(&call_from(1), TransferFee { rate_bps: 100, destination: FeeDestination::Burn }).unwrap();
+        saffron.set_fee_exempt(&call_from(1), &exempt, true).unwrap();
+
+        // To an exempt recipient, then from an exempt sender
+        saffron.transfer(&call_from(1), &exempt, U256::from(1_000)).unwrap();
+        assert_eq!(saffron.balance_of(&exempt), U256::from(1_000));
+        saffron.transfer(&call_from(2), &other, U256::from(1_000)).unwrap();
+        assert_eq!(saffron.balance_of(&other), U256::from(1_000));
+        assert_eq!(saffron.total_supply(), U256::exp10(19));
+
+        // Without the exemption the fee is taken again
+        saffron.set_fee_exempt(&call_from(1), &exempt, false).unwrap();
+        saffron.transfer(&call_from(3), &exempt, U256::from(1_000)).unwrap();
+        assert_eq!(saffron.balance_of(&exempt), U256::from(990));
+    }
+
+    #[test]
+    fn fee_rates_above_the_maximum_are_refused() {
+        let mut saffron = ledger();
+        let highest = TransferFee { rate_bps: MAX_TRANSFER_FEE_BPS, destination: FeeDestination::Burn };
+        saffron.set_transfer_fee(&call_from(1), highest).unwrap();
+
+        let too_high = TransferFee { rate_bps: MAX_TRANSFER_FEE_BPS + 1, destination: FeeDestination::Burn };
+        let result = saffron.set_transfer_fee(&call_from(1), too_high);
+        assert_eq!(result, Err(SaffronError::FeeRateTooHigh { rate_bps: MAX_TRANSFER_FEE_BPS + 1, max_bps: MAX_TRANSFER_FEE_BPS }));
+        assert_eq!(saffron.transfer_fee(), highest);
+
+        // A treasury must be a real address, and only an owner may set the fee
+        let to_nowhere = TransferFee { rate_bps: 10, destination: FeeDestination::Treasury(Address::zero()) };
+        assert_eq!(saffron.set_transfer_fee(&call_from(1), to_nowhere), Err(SaffronError::ZeroAddress));
+        assert_eq!(saffron.set_transfer_fee(&call_from(2), TransferFee::none()), Err(SaffronError::Unauthorized));
+    }
+
+    #[test]
+    fn nothing_moves_while_the_ledger_is_paused() {
+        let mut saffron = ledger();
+        let (owner, holder) = (Address::from_low_u64_be(1), Address::from_low_u64_be(2));
+        saffron.approve(&call_from(1), &holder, U256::exp10(18)).unwrap();
+        saffron.pause(&call_from(1)).unwrap();
+
+        let token = U256::exp10(18);
+        let buyer = CallContext::new(holder, U256::exp10(15), 2, 2_000);
+        assert_eq!(saffron.transfer(&call_from(1), &holder, token), Err(SaffronError::Paused));
+        assert_eq!(saffron.transfer_from(&call_from(2), &owner, &holder, token), Err(SaffronError::Paused));
+        assert_eq!(saffron.mint(&call_from(1), token), Err(SaffronError::Paused));
+        assert_eq!(saffron.burn(&call_from(1), token), Err(SaffronError::Paused));
+        assert_eq!(saffron.buy(&buyer), Err(SaffronError::Paused));
+        assert_eq!(saffron.request_redemption(&call_from(1), token, H256::zero()), Err(SaffronError::Paused));
+        assert_eq!(saffron.balance_of(&owner), token * 10);
+        assert_eq!(saffron.total_supply(), token * 10);
+
+        // Only an owner can resume, after which transfers go through again
+        assert_eq!(saffron.unpause(&call_from(2)), Err(SaffronError::Unauthorized));
+        saffron.unpause(&call_from(1)).unwrap();
+        saffron.transfer(&call_from(1), &holder, token).unwrap();
+    }
+
+    #[test]
+    fn blocked_senders_recipients_and_spenders_are_refused() {
+        let mut saffron = ledger();
+        let (owner, blocked, other) = (Address::from_low_u64_be(1), Address::from_low_u64_be(2), Address::from_low_u64_be(3));
+        let token = U256::exp10(18);
+        saffron.transfer(&call_from(1), &blocked, token).unwrap();
+        saffron.approve(&call_from(1), &blocked, token).unwrap();
+        saffron.approve(&call_from(2), &other, token).unwrap();
+        saffron.block_account(&call_from(1), &blocked).unwrap();
+
+        // As sender, recipient and spender
+        assert_eq!(saffron.transfer(&call_from(2), &other, token), Err(SaffronError::Blocked(blocked)));
+        assert_eq!(saffron.transfer(&call_from(1), &blocked, token), Err(SaffronError::Blocked(blocked)));
+        assert_eq!(saffron.transfer_from(&call_from(2), &owner, &other, token), Err(SaffronError::Blocked(blocked)));
+        // Nor can anyone move a blocked account's tokens for it
+        assert_eq!(saffron.transfer_from(&call_from(3), &blocked, &other, token), Err(SaffronError::Blocked(blocked)));
+        assert_eq!(saffron.burn(&call_from(2), token), Err(SaffronError::Blocked(blocked)));
+        assert_eq!(saffron.balance_of(&blocked), token);
+        assert_eq!(saffron.allowance(&owner, &blocked), token);
+
+        // Only an owner may block, and unblocking restores the account
+        assert_eq!(saffron.block_account(&call_from(3), &owner), Err(SaffronError::Unauthorized));
+        saffron.unblock_account(&call_from(1), &blocked).unwrap();
+        saffron.transfer(&call_from(2), &other, token).unwrap();
+    }
+
+    // Return a context for a call from the address ending in `sender` at `timestamp`
+    fn call_at(sender: u64, timestamp: u64) -> CallContext {
+        CallContext::new(Address::from_low_u64_be(sender), U256::zero(), timestamp, timestamp)
+    }
+
+    #[test]
+    fn nothing_vests_before_the_cliff_and_the_rest_vests_linearly() {
+        let mut saffron = ledger();
+        let beneficiary = Address::from_low_u64_be(2);
+        // 1000 base units over 1000 seconds from 10_000, with a 250 second cliff
+        let id = saffron.create_vesting(&call_at(1, 9_000), &beneficiary, U256::from(1_000), 10_000, 250, 1_000, false).unwrap();
+        assert_eq!(saffron.balance_of(&VESTING_ADDRESS), U256::from(1_000));
+
+        for (timestamp, vested) in [(9_999, 0), (10_249, 0), (10_250, 250), (10_500, 500), (10_999, 999), (11_000, 1_000), (20_000, 1_000)] {
+            assert_eq!(saffron.vested_amount(id, timestamp), Ok(U256::from(vested)), "at {}", timestamp);
+            assert_eq!(saffron.unvested_amount(id, timestamp), Ok(U256::from(1_000 - vested)), "at {}", timestamp);
+        }
+        assert_eq!(saffron.vested_amount(id + 1, 10_500), Err(SaffronError::UnknownVesting(id + 1)));
+
+        // Releasing before the cliff hands out nothing
+        assert_eq!(saffron.release(&call_at(9, 10_100), id), Ok(U256::zero()));
+        assert_eq!(saffron.balance_of(&beneficiary), U256::zero());
+    }
+
+    #[test]
+    fn releases_hand_out_only_what_has_vested_since_the_last_one() {
+        let mut saffron = ledger();
+        let beneficiary = Address::from_low_u64_be(2);
+        let id = saffron.create_vesting(&call_at(1, 9_000), &beneficiary, U256::from(1_000), 10_000, 0, 1_000, false).unwrap();
+
+        // Anyone may trigger a release, which always pays the beneficiary
+        assert_eq!(saffron.release(&call_at(9, 10_500), id), Ok(U256::from(500)));
+        assert_eq!(saffron.release(&call_at(9, 10_500), id), Ok(U256::zero()));
+        assert_eq!(saffron.release(&call_at(2, 10_750), id), Ok(U256::from(250)));
+        assert_eq!(saffron.balance_of(&beneficiary), U256::from(750));
+        assert_eq!(saffron.vesting_schedule(id).unwrap().released, U256::from(750));
+
+        // A call stamped before the last release finds nothing new, and what was released still
+        // counts as vested
+        assert_eq!(saffron.release(&call_at(9, 10_200), id), Ok(U256::zero()));
+        assert_eq!(saffron.vested_amount(id, 10_200), Ok(U256::from(750)));
+        assert_eq!(saffron.unvested_amount(id, 10_200), Ok(U256::from(250)));
+
+        assert_eq!(saffron.release(&call_at(9, 12_000), id), Ok(U256::from(250)));
+        assert_eq!(saffron.balance_of(&beneficiary), U256::from(1_000));
+        assert_eq!(saffron.balance_of(&VESTING_ADDRESS), U256::zero());
+    }
+
+    #[test]
+    fn revoking_returns_the_unvested_part_and_keeps_the_vested_part_releasable() {
+        let mut saffron = ledger();
+        let (owner, beneficiary) = (Address::from_low_u64_be(1), Address::from_low_u64_be(2));
+        let fixed = saffron.create_vesting(&call_at(1, 9_000), &beneficiary, U256::from(1_000), 10_000, 0, 1_000, false).unwrap();
+        let id = saffron.create_vesting(&call_at(1, 9_000), &beneficiary, U256::from(1_000), 10_000, 0, 1_000, true).unwrap();
+        let before = saffron.balance_of(&owner);
+
+        assert_eq!(saffron.revoke_vesting(&call_at(2, 10_400), id), Err(SaffronError::Unauthorized));
+        assert_eq!(saffron.revoke_vesting(&call_at(1, 10_400), fixed), Err(SaffronError::NotRevocable(fixed)));
+        assert_eq!(saffron.revoke_vesting(&call_at(1, 10_400), id), Ok(U256::from(600)));
+        assert_eq!(saffron.revoke_vesting(&call_at(1, 10_500), id), Err(SaffronError::NotRevocable(id)));
+        assert_eq!(saffron.balance_of(&owner), before + 600);
+
+        // Vesting stopped at the revocation; the 400 vested by then can still be released
+        assert_eq!(saffron.vested_amount(id, 20_000), Ok(U256::from(400)));
+        assert_eq!(saffron.unvested_amount(id, 20_000), Ok(U256::zero()));
+        assert_eq!(saffron.release(&call_at(9, 20_000), id), Ok(U256::from(400)));
+        assert_eq!(saffron.release(&call_at(9, 20_000), fixed), Ok(U256::from(1_000)));
+        assert_eq!(saffron.balance_of(&beneficiary), U256::from(1_400));
+        assert_eq!(saffron.balance_of(&VESTING_ADDRESS), U256::zero());
+    }
+
+    #[test]
+    fn a_revocation_stamped_before_a_release_returns_only_what_is_left() {
+        let mut saffron = ledger();
+        let (owner, beneficiary, other) = (Address::from_low_u64_be(1), Address::from_low_u64_be(2), Address::from_low_u64_be(3));
+        let id = saffron.create_vesting(&call_at(1, 9_000), &beneficiary, U256::from(1_000), 10_000, 0, 1_000, true).unwrap();
+        let others = saffron.create_vesting(&call_at(1, 9_000), &other, U256::from(500), 10_000, 0, 1_000, false).unwrap();
+        assert_eq!(saffron.release(&call_at(9, 10_800), id), Ok(U256::from(800)));
+        let before = saffron.balance_of(&owner);
+
+        // Revoking at a time before the release returns the 200 not yet released, not the 700
+        // unvested at that time, which would take tokens held for the other schedule
+        assert_eq!(saffron.revoke_vesting(&call_at(1, 10_300), id), Ok(U256::from(200)));
+        assert_eq!(saffron.balance_of(&owner), before + 200);
+        assert_eq!(saffron.vesting_schedule(id).unwrap().releasable_at(20_000), U256::zero());
+        assert_eq!(saffron.release(&call_at(9, 20_000), id), Ok(U256::zero()));
+
+        // The other schedule is still fully funded
+        assert_eq!(saffron.balance_of(&VESTING_ADDRESS), U256::from(500));
+        assert_eq!(saffron.release(&call_at(9, 20_000), others), Ok(U256::from(500)));
+        assert_eq!(saffron.balance_of(&beneficiary), U256::from(800));
+    }
+
+    #[test]
+    fn batch_totals_leave_out_transfers_to_the_sender() {
+        let mut saffron = ledger();
+        let owner = Address::from_low_u64_be(1);
+        let recipient = Address::from_low_u64_be(2);
+        let transfers = [(recipient, U256::exp10(18)), (owner, U256::exp10(18)), (recipient, U256::exp10(18))];
+
+        let summary = saffron.batch_transfer(&call_from(1), &transfers).unwrap();
+        assert_eq!(summary.total, U256::from(2) * U256::exp10(18));
+        assert_eq!(summary.sender_balance_after, U256::from(8) * U256::exp10(18));
+        assert_eq!(saffron.balance_of(&owner), summary.sender_balance_after);
+
+        // A transfer to the sender still needs the balance left at its place in the batch
+        let transfers = [(recipient, U256::from(8) * U256::exp10(18)), (owner, U256::one())];
+        assert!(matches!(
+            saffron.check_batch_transfer(&call_from(1), &transfers),
+            Err(SaffronError::InsufficientBalance { .. })
+        ));
+    }
+
+    #[test]
+    fn transfer_from_beyond_the_balance_is_refused() {
+        let mut saffron = ledger();
+        let (owner, spender, recipient) = (Address::from_low_u64_be(1), Address::from_low_u64_be(2), Address::from_low_u64_be(3));
+        let too_much = U256::exp10(19) + 1;
+        saffron.approve(&call_from(1), &spender, too_much).unwrap();
+
+        let result = saffron.transfer_from(&call_from(2), &owner, &recipient, too_much);
+        assert_eq!(result, Err(SaffronError::InsufficientBalance { needed: too_much, available: U256::exp10(19) }));
+        // Nothing moved and the allowance was not used up
+        assert_eq!(saffron.balance_of(&owner), U256::exp10(19));
+        assert_eq!(saffron.balance_of(&recipient), U256::zero());
+        assert_eq!(saffron.allowance(&owner, &spender), too_much);
+    }
+
+    #[test]
+    fn transfer_from_beyond_the_allowance_is_refused() {
+        let mut saffron = ledger();
+        let (owner, spender, recipient) = (Address::from_low_u64_be(1), Address::from_low_u64_be(2), Address::from_low_u64_be(3));
+        saffron.approve(&call_from(1), &spender, U256::from(500)).unwrap();
+
+        let result = saffron.transfer_from(&call_from(2), &owner, &recipient, U256::from(501));
+        assert_eq!(result, Err(SaffronError::InsufficientAllowance { needed: U256::from(501), available: U256::from(500) }));
+        assert_eq!(saffron.balance_of(&owner), U256::exp10(19));
+        assert_eq!(saffron.balance_of(&recipient), U256::zero());
+        assert_eq!(saffron.allowance(&owner, &spender), U256::from(500));
+    }
+
+    #[test]
+    fn transfer_from_can_use_up_the_allowance_exactly() {
+        let mut saffron = ledger();
+        let (owner, spender, recipient) = (Address::from_low_u64_be(1), Address::from_low_u64_be(2), Address::from_low_u64_be(3));
+        saffron.approve(&call_from(1), &spender, U256::from(500)).unwrap();
+
+        saffron.transfer_from(&call_from(2), &owner, &recipient, U256::from(200)).unwrap();
+        saffron.transfer_from(&call_from(2), &owner, &recipient, U256::from(300)).unwrap();
+        assert_eq!(saffron.balance_of(&owner), U256::exp10(19) - 500);
+        assert_eq!(saffron.balance_of(&recipient), U256::from(500));
+        assert_eq!(saffron.allowance(&owner, &spender), U256::zero());
+        // The exhausted allowance is removed rather than kept as a zero entry
+        assert!(saffron.allowances.is_empty());
+
+        // Nothing more can be taken
+        let result = saffron.transfer_from(&call_from(2), &owner, &recipient, U256::one());
+        assert_eq!(result, Err(SaffronError::InsufficientAllowance { needed: U256::one(), available: U256::zero() }));
+    }
+
+    #[test]
+    fn arithmetic_past_u256_max_is_refused() {
+        // A ledger without decimals, so its stock can cap the supply at U256::MAX
+        let owner = Address::from_low_u64_be(1);
+        let minter = Address::from_low_u64_be(2);
+        let mut saffron = Saffron::new("Saffron Vendor".to_string(), "$affron".to_string(), 0, owner).unwrap();
+        saffron.grant_role(&call_from(1), Role::Minter, &owner).unwrap();
+        saffron.grant_role(&call_from(1), Role::Minter, &minter).unwrap();
+        saffron.add_inventory(&call_from(1), U256::max_value()).unwrap();
+        assert_eq!(saffron.add_inventory(&call_from(1), U256::one()), Err(SaffronError::Overflow));
+        assert_eq!(saffron.inventory_grams(), U256::max_value());
+
+        // Minting right up to U256::MAX works, but one more base unit overflows the minter's
+        // balance, and for anyone else the total supply
+        saffron.mint(&call_from(1), U256::max_value() - 1).unwrap();
+        saffron.mint(&call_from(1), U256::one()).unwrap();
+        assert_eq!(saffron.mint(&call_from(1), U256::one()), Err(SaffronError::Overflow));
+        assert_eq!(saffron.mint(&call_from(2), U256::one()), Err(SaffronError::Overflow));
+        assert_eq!(saffron.balance_of(&owner), U256::max_value());
+        assert_eq!(saffron.balance_of(&minter), U256::zero());
+        assert_eq!(saffron.total_supply(), U256::max_value());
+
+        // An allowance cannot be raised past U256::MAX either
+        let spender = Address::from_low_u64_be(3);
+        saffron.approve(&call_from(1), &spender, U256::max_value()).unwrap();
+        assert_eq!(saffron.increase_allowance(&call_from(1), &spender, U256::one()), Err(SaffronError::Overflow));
+        assert_eq!(saffron.allowance(&owner, &spender), U256::max_value());
+    }
+
+    #[test]
+    fn the_call_context_reaches_events_purchases_and_vesting() {
+        let mut saffron = ledger();
+        let owner = Address::from_low_u64_be(1);
+        let beneficiary = Address::from_low_u64_be(2);
+
+        // The sender's own tokens move, and the event is logged at the caller's block and time
+        let ctx = CallContext::new(owner, U256::zero(), 42, 4_200);
+        saffron.transfer(&ctx, &beneficiary, U256::one()).unwrap();
+        let logged = saffron.event_log().last().unwrap();
+        assert_eq!(logged.event, SaffronEvent::Transfer { from: owner, to: beneficiary, value: U256::one() });
+        assert_eq!((logged.block_number, logged.timestamp), (42, 4_200));
+
+        // Vesting is measured at the caller's timestamp: halfway through, half is released
+        let id = saffron.create_vesting(&ctx, &beneficiary, U256::from(1_000), 5_000, 0, 1_000, true).unwrap();
+        let anyone = CallContext::new(Address::from_low_u64_be(9), U256::zero(), 43, 5_500);
+        assert_eq!(saffron.release(&anyone, id), Ok(U256::from(500)));
+        saffron.revoke_vesting(&CallContext::new(owner, U256::zero(), 44, 5_750), id).unwrap();
+        assert_eq!(saffron.vesting_schedule(id).unwrap().revoked_at, Some(5_750));
+        let logged = saffron.event_log().last().unwrap();
+        assert_eq!(logged.event, SaffronEvent::VestingRevoked { id, beneficiary, returned: U256::from(250) });
+        assert_eq!((logged.block_number, logged.timestamp), (44, 5_750));
+
+        // The ETH sent with the call pays for a purchase
+        let buyer = Address::from_low_u64_be(3);
+        saffron.add_inventory(&ctx, U256::one()).unwrap();
+        saffron.grant_role(&ctx, Role::OracleAdmin, &owner).unwrap();
+        saffron.override_price(&ctx, U256::exp10(15)).unwrap();
+        let purchase = saffron.buy(&CallContext::new(buyer, U256::exp10(15), 45, 4_300)).unwrap();
+        assert_eq!(purchase.tokens, U256::exp10(18));
+        assert_eq!(saffron.balance_of(&buyer), U256::exp10(18));
+        assert_eq!(saffron.payments_received(), U256::exp10(15));
+    }
+
+    // Return a shipping address for tests
+    fn destination() -> ShippingAddress {
+        ShippingAddress {
+            name: "JANE DOE".to_string(),
+            line1: "123 N MAIN ST".to_string(),
+            line2: None,
+            city: "SPRINGFIELD".to_string(),
+            state_or_region: "IL".to_string(),
+            postal_code: "62701".to_string(),
+            country_code: "US".to_string(),
+        }
+    }
+
+    #[test]
+    fn redemptions_with_a_shipped_order_are_never_refunded() {
+        use crate::saffron_fulfillment::FileFulfillmentProvider;
+
+        let path = std::env::temp_dir().join(format!("saffron-vendor-orders-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let provider = FileFulfillmentProvider::new(&path);
+
+        let mut saffron = ledger();
+        saffron.grant_role(&call_from(1), Role::Fulfiller, &Address::from_low_u64_be(1)).unwrap();
+        let id = saffron.request_redemption(&call_from(1), U256::exp10(18), H256::zero()).unwrap();
+        saffron.place_fulfillment_order(&call_from(1), id, destination(), &provider).unwrap();
+        provider.mark_shipped(&order_id_for_redemption(id), "1Z999", "UPS").unwrap();
+
+        // Neither the holder nor anyone after the deadline can refund around the provider
+        let late = CallContext::new(Address::from_low_u64_be(5), U256::zero(), 9, u64::MAX);
+        assert_eq!(saffron.cancel_redemption(&call_from(1), id), Err(SaffronError::FulfillmentOrderPlaced(id)));
+        assert_eq!(saffron.expire_redemption(&late, id), Err(SaffronError::FulfillmentOrderPlaced(id)));
+        assert_eq!(
+            saffron.cancel_fulfillment_order(&call_from(1), id, &provider),
+            Err(SaffronError::Fulfillment(FulfillmentError::AlreadyShipped(order_id_for_redemption(id))))
+        );
+
+        let status = saffron.sync_fulfillment(&call_from(1), id, &provider).unwrap();
+        assert_eq!(status, RedemptionStatus::Fulfilled { tracking_number: "1Z999".to_string() });
+        let _ = std::fs::remove_file(&path);
+    }
+}
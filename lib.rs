@@ -0,0 +1,25 @@
+// The Saffron ledger and the services built around it. The redemption server (Main.rs) and the
+// airdrop tool (Airdrop.rs) are built against this library.
+
+#[path = "SaffronVendor.rs"]
+pub mod saffron_vendor;
+#[path = "SaffronStorage.rs"]
+pub mod saffron_storage;
+#[path = "SaffronPermit.rs"]
+pub mod saffron_permit;
+#[path = "SaffronFulfillment.rs"]
+pub mod saffron_fulfillment;
+#[path = "SaffronMockOracle.rs"]
+pub mod saffron_mock_oracle;
+#[path = "SaffronTreasury.rs"]
+pub mod saffron_treasury;
+#[path = "SaffronMailingAddress.rs"]
+pub mod saffron_mailing_address;
+#[path = "SaffronOrderNumbers.rs"]
+pub mod saffron_order_numbers;
+#[path = "SaffronRedemptionProof.rs"]
+pub mod saffron_redemption_proof;
+#[path = "SaffronConfig.rs"]
+pub mod saffron_config;
+#[path = "SaffronOutbox.rs"]
+pub mod saffron_outbox;
@@ -0,0 +1,229 @@
+// The mock oracle's scripted responses, and end-to-end purchase and redemption flows against it,
+// as a developer would run them locally before pointing the ledger at a real Chainlink node
+
+use web3::types::{Address, H256, U256};
+
+use saffron::saffron_fulfillment::{order_id_for_redemption, FileFulfillmentProvider, ShippingAddress};
+use saffron::saffron_mock_oracle::{MockOracle, MockOracleError, MockOutcome, MockResponse};
+use saffron::saffron_vendor::{CallContext, PriceCallback, RedemptionStatus, Role, Saffron, SaffronError, ERC20};
+
+// The price the mock answers with: 0.0025 ETH per gram
+const WEI_PER_GRAM: u64 = 2_500_000_000_000_000;
+
+fn owner() -> Address {
+    Address::from_low_u64_be(1)
+}
+
+fn buyer() -> Address {
+    Address::from_low_u64_be(2)
+}
+
+fn oracle_address() -> Address {
+    Address::from_low_u64_be(6)
+}
+
+// Return a context for a call from `sender` at `timestamp`, one block per second
+fn at(sender: Address, value: U256, timestamp: u64) -> CallContext {
+    CallContext::new(sender, value, timestamp, timestamp)
+}
+
+// Return a ledger with 10 grams in stock whose owner is also its oracle admin and fulfiller,
+// wired to a mock oracle answering with `script`
+fn setup(script: Vec<MockResponse>) -> (Saffron, MockOracle) {
+    let mut saffron = Saffron::new("Saffron Vendor".to_string(), "$affron".to_string(), 18, owner()).unwrap();
+    let ctx = at(owner(), U256::zero(), 1_000);
+    saffron.grant_role(&ctx, Role::OracleAdmin, &owner()).unwrap();
+    saffron.grant_role(&ctx, Role::Fulfiller, &owner()).unwrap();
+    saffron.add_inventory(&ctx, U256::from(10)).unwrap();
+    saffron.set_oracle_address(&ctx, oracle_address()).unwrap();
+    (saffron, MockOracle::new(oracle_address(), script))
+}
+
+// Ask the oracle for a price at `timestamp`
+fn request_price(saffron: &mut Saffron, timestamp: u64) -> H256 {
+    let callback = PriceCallback { address: Address::from_low_u64_be(7), function_selector: [0; 4] };
+    saffron.request_price(&at(owner(), U256::zero(), timestamp), H256::zero(), U256::one(), callback).unwrap()
+}
+
+// Return the ids of the price requests still waiting for an answer
+fn pending(saffron: &Saffron) -> Vec<H256> {
+    saffron.pending_price_requests().map(|request| request.request_id).collect()
+}
+
+#[test]
+fn delayed_answers_wait_until_they_are_due() {
+    let payload = WEI_PER_GRAM.to_string();
+    let (mut saffron, mut oracle) = setup(vec![MockResponse::Respond { payload, delay_seconds: 120 }]);
+    let request_id = request_price(&mut saffron, 1_001);
+
+    // The request is picked up but not answered before its delay has passed
+    assert!(oracle.poll(&mut saffron, &at(Address::zero(), U256::zero(), 1_120)).is_empty());
+    assert_eq!(oracle.scheduled_count(), 1);
+    assert_eq!(saffron.latest_price(), None);
+
+    let outcomes = oracle.poll(&mut saffron, &at(Address::zero(), U256::zero(), 1_121));
+    assert_eq!(outcomes, [MockOutcome::Fulfilled { request_id, wei_per_gram: U256::from(WEI_PER_GRAM), round_id: 1 }]);
+    assert_eq!(oracle.scheduled_count(), 0);
+    assert_eq!(saffron.latest_price().map(|price| price.updated_at), Some(1_121));
+}
+
+#[test]
+fn failed_jobs_leave_the_request_pending() {
+    let (mut saffron, mut oracle) = setup(vec![MockResponse::Fail { reason: "job run errored".to_string() }]);
+    let request_id = request_price(&mut saffron, 1_001);
+
+    let outcomes = oracle.poll(&mut saffron, &at(Address::zero(), U256::zero(), 1_002));
+    assert_eq!(outcomes, [MockOutcome::Failed { request_id, reason: "job run errored".to_string() }]);
+    assert_eq!(pending(&saffron), [request_id]);
+    assert_eq!(saffron.latest_price(), None);
+}
+
+#[test]
+fn malformed_payloads_never_reach_the_ledger() {
+    let malformed = MockResponse::Respond { payload: "not a number".to_string(), delay_seconds: 0 };
+    let (mut saffron, mut oracle) = setup(vec![malformed]);
+    let request_id = request_price(&mut saffron, 1_001);
+
+    let outcomes = oracle.poll(&mut saffron, &at(Address::zero(), U256::zero(), 1_002));
+    assert_eq!(outcomes, [MockOutcome::Malformed { request_id, payload: "not a number".to_string() }]);
+    assert_eq!(pending(&saffron), [request_id]);
+    assert_eq!(saffron.latest_price(), None);
+}
+
+#[test]
+fn silent_oracles_ignore_requests_until_given_a_default() {
+    let (mut saffron, mut oracle) = setup(vec![MockResponse::Silent]);
+    let first = request_price(&mut saffron, 1_001);
+    let outcomes = oracle.poll(&mut saffron, &at(Address::zero(), U256::zero(), 1_002));
+    assert_eq!(outcomes, [MockOutcome::Ignored { request_id: first }]);
+
+    // With the script used up requests are ignored too, until a default response is set
+    let second = request_price(&mut saffron, 1_003);
+    let outcomes = oracle.poll(&mut saffron, &at(Address::zero(), U256::zero(), 1_004));
+    assert_eq!(outcomes, [MockOutcome::Ignored { request_id: second }]);
+    let waiting = pending(&saffron);
+    assert!(waiting.len() == 2 && waiting.contains(&first) && waiting.contains(&second));
+
+    oracle.set_default_response(Some(MockResponse::price(U256::from(WEI_PER_GRAM))));
+    let third = request_price(&mut saffron, 1_005);
+    let outcomes = oracle.poll(&mut saffron, &at(Address::zero(), U256::zero(), 1_006));
+    assert_eq!(outcomes, [MockOutcome::Fulfilled { request_id: third, wei_per_gram: U256::from(WEI_PER_GRAM), round_id: 1 }]);
+    assert_eq!(oracle.outcomes().len(), 3);
+}
+
+#[test]
+fn scripts_are_read_from_json_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("oracle.json");
+    let script = r#"[
+        { "kind": "fail", "reason": "job run errored" },
+        { "kind": "respond", "payload": "2500000000000000", "delay_seconds": 5 },
+        { "kind": "silent" }
+    ]"#;
+    std::fs::write(&path, script).unwrap();
+    let mut oracle = MockOracle::from_file(oracle_address(), &path).unwrap();
+    assert_eq!(oracle.address(), oracle_address());
+
+    let (mut saffron, _) = setup(Vec::new());
+    let failed = request_price(&mut saffron, 1_001);
+    let answered = request_price(&mut saffron, 1_002);
+    let ignored = request_price(&mut saffron, 1_003);
+    let outcomes = oracle.poll(&mut saffron, &at(Address::zero(), U256::zero(), 1_007));
+    assert_eq!(
+        outcomes,
+        [
+            MockOutcome::Failed { request_id: failed, reason: "job run errored".to_string() },
+            MockOutcome::Fulfilled { request_id: answered, wei_per_gram: U256::from(WEI_PER_GRAM), round_id: 1 },
+            MockOutcome::Ignored { request_id: ignored },
+        ]
+    );
+
+    // A missing file and one that is not a list of responses are told apart
+    assert!(matches!(MockOracle::from_file(oracle_address(), dir.path().join("missing.json")), Err(MockOracleError::Io(_))));
+    std::fs::write(&path, r#"[{ "kind": "shout" }]"#).unwrap();
+    assert!(matches!(MockOracle::from_file(oracle_address(), &path), Err(MockOracleError::InvalidScript(_))));
+}
+
+#[test]
+fn tokens_are_bought_at_the_price_the_oracle_reports() {
+    let (mut saffron, mut oracle) = setup(vec![MockResponse::price(U256::from(WEI_PER_GRAM))]);
+
+    // Nothing can be bought before the oracle has answered
+    let payment = U256::from(2 * WEI_PER_GRAM);
+    assert_eq!(saffron.buy(&at(buyer(), payment, 1_001)), Err(SaffronError::NoPrice));
+
+    let request_id = request_price(&mut saffron, 1_001);
+    let outcomes = oracle.poll(&mut saffron, &at(Address::zero(), U256::zero(), 1_002));
+    assert_eq!(outcomes, [MockOutcome::Fulfilled { request_id, wei_per_gram: U256::from(WEI_PER_GRAM), round_id: 1 }]);
+
+    // Two grams' worth of ETH buys two whole tokens, with nothing to hand back
+    let purchase = saffron.buy(&at(buyer(), payment, 1_003)).unwrap();
+    assert_eq!(purchase.tokens, U256::from(2) * U256::exp10(18));
+    assert_eq!(purchase.refund, U256::zero());
+    assert_eq!(saffron.balance_of(&buyer()), purchase.tokens);
+    assert_eq!(saffron.payments_received(), payment);
+}
+
+#[test]
+fn purchases_stop_when_the_oracle_goes_quiet_or_jumps() {
+    let (mut saffron, mut oracle) = setup(vec![
+        MockResponse::price(U256::from(WEI_PER_GRAM)),
+        MockResponse::price(U256::from(2 * WEI_PER_GRAM)),
+    ]);
+    let heartbeat = saffron.price_guard().heartbeat;
+    request_price(&mut saffron, 1_001);
+    oracle.poll(&mut saffron, &at(Address::zero(), U256::zero(), 1_002));
+
+    // Once the heartbeat has passed without a new answer the price is stale
+    let late = 1_003 + heartbeat;
+    assert!(matches!(
+        saffron.buy(&at(buyer(), U256::from(WEI_PER_GRAM), late)),
+        Err(SaffronError::StalePrice { .. })
+    ));
+
+    // A fresh answer that doubles the price is kept but not trusted until an admin confirms it
+    request_price(&mut saffron, late);
+    oracle.poll(&mut saffron, &at(Address::zero(), U256::zero(), late + 1));
+    assert!(matches!(
+        saffron.buy(&at(buyer(), U256::from(WEI_PER_GRAM), late + 2)),
+        Err(SaffronError::PriceDeviation { .. })
+    ));
+    saffron.override_price(&at(owner(), U256::zero(), late + 3), U256::from(2 * WEI_PER_GRAM)).unwrap();
+    let purchase = saffron.buy(&at(buyer(), U256::from(2 * WEI_PER_GRAM), late + 4)).unwrap();
+    assert_eq!(purchase.tokens, U256::exp10(18));
+}
+
+#[test]
+fn bought_tokens_are_redeemed_for_shipped_saffron() {
+    let (mut saffron, mut oracle) = setup(vec![MockResponse::price(U256::from(WEI_PER_GRAM))]);
+    request_price(&mut saffron, 1_001);
+    oracle.poll(&mut saffron, &at(Address::zero(), U256::zero(), 1_002));
+    saffron.buy(&at(buyer(), U256::from(2 * WEI_PER_GRAM), 1_003)).unwrap();
+
+    // Redeem one of the two tokens for a gram of saffron
+    let id = saffron.request_redemption(&at(buyer(), U256::zero(), 1_004), U256::exp10(18), H256::zero()).unwrap();
+    assert_eq!(saffron.balance_of(&buyer()), U256::exp10(18));
+
+    // Ship it through a local order file
+    let path = std::env::temp_dir().join(format!("saffron-mock-oracle-orders-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let provider = FileFulfillmentProvider::new(&path);
+    let destination = ShippingAddress {
+        name: "JANE DOE".to_string(),
+        line1: "123 N MAIN ST".to_string(),
+        line2: None,
+        city: "SPRINGFIELD".to_string(),
+        state_or_region: "IL".to_string(),
+        postal_code: "62701".to_string(),
+        country_code: "US".to_string(),
+    };
+    saffron.place_fulfillment_order(&at(owner(), U256::zero(), 1_005), id, destination, &provider).unwrap();
+    provider.mark_shipped(&order_id_for_redemption(id), "1Z999", "UPS").unwrap();
+    let status = saffron.sync_fulfillment(&at(owner(), U256::zero(), 1_006), id, &provider).unwrap();
+    let _ = std::fs::remove_file(&path);
+
+    // The escrowed token is burned and the gram has left the stock
+    assert_eq!(status, RedemptionStatus::Fulfilled { tracking_number: "1Z999".to_string() });
+    assert_eq!(saffron.total_supply(), U256::exp10(18));
+    assert_eq!(saffron.inventory_grams(), U256::from(9));
+}